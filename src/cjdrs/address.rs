@@ -2,6 +2,7 @@ use std::fmt;
 use std::mem;
 use std::num::Int;
 use std::cmp::Ordering;
+use std::iter::repeat;
 use std::slice::bytes::copy_memory;
 use sodiumoxide::crypto::hash::sha512;
 use PublicKey;
@@ -38,6 +39,68 @@ impl Address {
 		}
 	}
 
+	/// Parses an address in the usual IPv6 notation, including the
+	/// compressed `fc00::1` form.
+	pub fn from_string(string: &str) -> Option<Address> {
+		fn parse_groups(s: &str) -> Option<Vec<u16>> {
+			if s.is_empty() {
+				return Some(vec![]);
+			}
+
+			let mut groups = vec![];
+			for group in s.split(':') {
+				if group.is_empty() || group.len() > 4 {
+					return None;
+				}
+				match u16::from_str_radix(group, 16) {
+					Ok(g) => groups.push(g),
+					Err(..) => return None
+				}
+			}
+			Some(groups)
+		}
+
+		let mut halves = string.split_str("::");
+		let head = match halves.next().and_then(parse_groups) {
+			Some(h) => h,
+			None => return None
+		};
+		let tail = match halves.next() {
+			Some(t) => match parse_groups(t) {
+				Some(t) => Some(t),
+				None => return None
+			},
+			None => None
+		};
+		if halves.next().is_some() {
+			return None;
+		}
+
+		let groups = match tail {
+			Some(tail) => {
+				if head.len() + tail.len() > 7 {
+					return None;
+				}
+				let mut groups = head;
+				let zeros = 8 - groups.len() - tail.len();
+				groups.extend(repeat(0).take(zeros));
+				groups.push_all(tail.as_slice());
+				groups
+			},
+			None => head
+		};
+		if groups.len() != 8 {
+			return None;
+		}
+
+		let mut bytes = [0u8; ADDRESS_SIZE];
+		for (i, group) in groups.iter().enumerate() {
+			bytes[i * 2    ] = (*group >> 8) as u8;
+			bytes[i * 2 + 1] = (*group     ) as u8;
+		}
+		Address::from_bytes(&bytes)
+	}
+
 	pub fn from_public_key(public_key: &PublicKey) -> Option<Address> {
 		let first_sha = sha512::hash(public_key.as_slice());
 		let second_sha = sha512::hash(first_sha.as_slice());
@@ -210,6 +273,30 @@ mod tests {
 		assert_eq!(address.as_u16_le(), address_16);
 	}
 
+	#[test]
+	fn test_from_string() {
+		let address = Address::from_bytes(&[
+			0xfc, 0x50, 0x71, 0xae, 0x09, 0xd6, 0xf7, 0x94,
+			0x75, 0x54, 0x20, 0x83, 0x87, 0x3e, 0x88, 0xa9]).unwrap();
+		assert_eq!(Address::from_string("fc50:71ae:09d6:f794:7554:2083:873e:88a9"), Some(address));
+		assert_eq!(Address::from_string("fc50:71ae:9d6:f794:7554:2083:873e:88a9"), Some(address));
+
+		let address = Address::from_bytes(&[
+			0xfc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]).unwrap();
+		assert_eq!(Address::from_string("fc00::1"), Some(address));
+		assert_eq!(Address::from_string("fc00:0::0:1"), Some(address));
+		assert_eq!(address.to_string(), "fc00:0000:0000:0000:0000:0000:0000:0001");
+		assert_eq!(Address::from_string(address.to_string().as_slice()), Some(address));
+
+		assert_eq!(Address::from_string("fd00::1"), None);
+		assert_eq!(Address::from_string("fc00::1::1"), None);
+		assert_eq!(Address::from_string("fc00:1"), None);
+		assert_eq!(Address::from_string("fc00:0:0:0:0:0:0:0:1"), None);
+		assert_eq!(Address::from_string("fc00::10000"), None);
+		assert_eq!(Address::from_string("fc00::g"), None);
+	}
+
 	#[test]
 	fn test_xor_distance() {
 		// TODO Real data
@@ -1,8 +1,12 @@
-use rustc_serialize::Encodable;
-use rustc_serialize::json::{self, Encoder};
+use std::collections::{HashMap, BTreeMap};
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json::{self, Encoder, Json};
 use std::old_io::File;
 use std::old_io::fs::PathExtensions;
 use crypto::random_password;
+use device::{DEFAULT_DUAL_STACK, DEFAULT_BEACON_MODE, DEFAULT_BEACON_PORT};
+use encoding_scheme::DEFAULT_SCHEME;
+use session::{DEFAULT_RESET_AFTER_INACTIVITY, DEFAULT_HANDSHAKE_TIMEOUT};
 use PrivateIdentity;
 use CjdrsResult;
 use CjdrsError;
@@ -12,8 +16,37 @@ use CjdrsError;
 pub struct Config {
 	pub privateKey: String,
 	pub tunDevice: String,
-	pub udpBind: String,
-	pub authorizedPasswords: Vec<String>
+	/// Also accepts a single address, like older configs have it
+	pub udpBind: Vec<String>,
+	pub udpDualStack: Option<bool>,
+	/// 0 = off, 1 = accept beacons, 2 = send and accept beacons
+	pub beaconMode: Option<u8>,
+	pub beaconPort: Option<u16>,
+	/// Also accepts bare passwords, like older configs have them
+	pub authorizedPasswords: Vec<PasswordEntry>,
+	pub connectTo: Option<HashMap<String, PeerEntry>>,
+	/// Encoding scheme the switch writes labels in, one of fixed4, fixed8,
+	/// variable4x8 and variable3x5x8
+	pub encodingScheme: Option<String>,
+	pub resetAfterInactivitySeconds: Option<u64>,
+	pub handshakeTimeoutSeconds: Option<u64>
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PasswordEntry {
+	pub user: String,
+	pub password: String,
+	pub ipv6: Option<String>
+}
+
+/// Outgoing peer, keyed by its address:port in `connectTo`
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+#[allow(non_snake_case)]
+pub struct PeerEntry {
+	pub publicKey: String,
+	pub password: String,
+	pub login: Option<String>,
+	pub label: Option<String>
 }
 
 impl Config {
@@ -21,10 +54,21 @@ impl Config {
 		Config {
 			privateKey: identity.private_key.as_string(),
 			tunDevice: "tun%d".to_string(),
-			udpBind: "0.0.0.0:3300".to_string(),
+			udpBind: vec!["0.0.0.0:3300".to_string()],
+			udpDualStack: Some(DEFAULT_DUAL_STACK),
+			beaconMode: Some(DEFAULT_BEACON_MODE.as_u8()),
+			beaconPort: Some(DEFAULT_BEACON_PORT),
 			authorizedPasswords: vec![
-				random_password()
-			]
+				PasswordEntry {
+					user: "default-login".to_string(),
+					password: random_password(),
+					ipv6: None
+				}
+			],
+			connectTo: Some(HashMap::new()),
+			encodingScheme: Some(DEFAULT_SCHEME.to_string()),
+			resetAfterInactivitySeconds: Some(DEFAULT_RESET_AFTER_INACTIVITY),
+			handshakeTimeoutSeconds: Some(DEFAULT_HANDSHAKE_TIMEOUT)
 		}
 	}
 
@@ -49,6 +93,83 @@ impl Config {
 	pub fn load(path: &Path) -> CjdrsResult<Config> {
 		let mut file = try!(File::open(path));
 		let content = try!(file.read_to_string());
-		Ok(try!(json::decode(content.as_slice())))
+		Config::from_str(content.as_slice())
+	}
+
+	pub fn from_str(content: &str) -> CjdrsResult<Config> {
+		let mut json = try!(json::from_str(content).map_err(json::DecoderError::ParseError));
+
+		if let Json::Object(ref mut object) = json {
+			let single_bind = match object.get("udpBind") {
+				Some(&Json::String(ref bind)) => Some(bind.clone()),
+				_ => None
+			};
+			if let Some(bind) = single_bind {
+				object.insert("udpBind".to_string(), Json::Array(vec![Json::String(bind)]));
+			}
+
+			if let Some(&mut Json::Array(ref mut passwords)) = object.get_mut("authorizedPasswords") {
+				for (i, entry) in passwords.iter_mut().enumerate() {
+					let password = match *entry {
+						Json::String(ref password) => password.clone(),
+						_ => continue
+					};
+					let mut fields = BTreeMap::new();
+					fields.insert("user".to_string(), Json::String(format!("password{}", i)));
+					fields.insert("password".to_string(), Json::String(password));
+					*entry = Json::Object(fields);
+				}
+			}
+		}
+
+		Ok(try!(Decodable::decode(&mut json::Decoder::new(json))))
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::Config;
+
+	#[test]
+	fn test_single_bind_address() {
+		let config = Config::from_str(r#"{
+			"privateKey": "",
+			"tunDevice": "tun%d",
+			"udpBind": "0.0.0.0:3300",
+			"authorizedPasswords": []
+		}"#).unwrap();
+		assert_eq!(config.udpBind, vec!["0.0.0.0:3300".to_string()]);
+		assert_eq!(config.udpDualStack, None);
+		assert_eq!(config.beaconMode, None);
+		assert_eq!(config.encodingScheme, None);
+
+		let config = Config::from_str(r#"{
+			"privateKey": "",
+			"tunDevice": "tun%d",
+			"udpBind": ["0.0.0.0:3300", "[::]:3300"],
+			"udpDualStack": false,
+			"beaconMode": 0,
+			"beaconPort": 64512,
+			"authorizedPasswords": []
+		}"#).unwrap();
+		assert_eq!(config.udpBind.len(), 2);
+		assert_eq!(config.udpDualStack, Some(false));
+	}
+
+	#[test]
+	fn test_bare_passwords() {
+		let config = Config::from_str(r#"{
+			"privateKey": "",
+			"tunDevice": "tun%d",
+			"udpBind": "0.0.0.0:3300",
+			"authorizedPasswords": ["first", {"user": "bob", "password": "second"}, "third"]
+		}"#).unwrap();
+		let entries: Vec<(&str, &str)> = config.authorizedPasswords.iter()
+			.map(|e| (e.user.as_slice(), e.password.as_slice()))
+			.collect();
+		assert_eq!(entries, vec![("password0", "first"), ("bob", "second"), ("password2", "third")]);
+		assert!(config.authorizedPasswords.iter().all(|e| e.ipv6.is_none()));
 	}
 }
@@ -2,6 +2,8 @@ use std::fmt;
 use sodiumoxide::crypto::asymmetricbox::curve25519xsalsa20poly1305 as crypto_box;
 use sodiumoxide::crypto::scalarmult::curve25519;
 use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::ffi;
+use message::Message;
 use PrivateKey;
 use PublicKey;
 
@@ -24,7 +26,17 @@ pub fn random_password() -> String {
 
 
 
-#[derive(Debug, Copy, Eq, PartialEq)]
+/// Challenge lookup bytes that identify a login name in a handshake
+pub fn login_lookup(login: &str) -> [u8; 7] {
+	let sha256::Digest(hash) = sha256::hash(login.as_bytes());
+	let mut lookup = [0u8; 7];
+	lookup.clone_from_slice(&hash[1..8]);
+	lookup
+}
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PasswordHash([u8; 32]);
 
 impl PasswordHash {
@@ -33,6 +45,14 @@ impl PasswordHash {
 		PasswordHash(hash)
 	}
 
+	/// Challenge lookup bytes that identify this password in a handshake
+	pub fn lookup(&self) -> [u8; 7] {
+		let sha256::Digest(hash) = sha256::hash(self.as_slice());
+		let mut lookup = [0u8; 7];
+		lookup.clone_from_slice(&hash[1..8]);
+		lookup
+	}
+
 	fn as_slice(&self) -> &[u8] {
 		&self.0
 	}
@@ -103,25 +123,117 @@ impl Nonce {
 
 
 
+/// Zero bytes NaCl requires in front of a plaintext
+const ZERO_BYTES: usize = 32;
+
+/// Zero bytes NaCl leaves in front of a ciphertext
+const BOX_ZERO_BYTES: usize = 16;
+
+
 #[derive(Debug, Copy)]
 pub struct CryptoBox;
 
 impl CryptoBox {
-	pub fn encrypt(message: &[u8],
+	/// Encrypts the message in place. The content is replaced with the
+	/// 16 byte authenticator followed by the ciphertext.
+	pub fn encrypt(message: &mut Message,
 	               nonce: &Nonce,
-	               shared_secret: &SharedSecret) -> Vec<u8> {
-		crypto_box::seal_precomputed(
-			message,
-			&crypto_box::Nonce(*nonce.get_bytes()),
-			shared_secret.get_key())
+	               shared_secret: &SharedSecret) {
+		message.push_zeros(ZERO_BYTES);
+
+		let len = message.len();
+		let ret = {
+			let content = message.as_mut_slice();
+			unsafe {
+				ffi::crypto_box_curve25519xsalsa20poly1305_afternm(
+					content.as_mut_ptr(),
+					content.as_ptr(),
+					len as u64,
+					nonce.get_bytes(),
+					&shared_secret.get_key().0)
+			}
+		};
+		assert_eq!(ret, 0);
+
+		message.pop(BOX_ZERO_BYTES);
 	}
-	
-	pub fn decrypt(message: &[u8],
+
+	/// Decrypts the message in place. Returns false if the authenticator
+	/// doesn't match, in which case the content is left undefined.
+	pub fn decrypt(message: &mut Message,
 	               nonce: &Nonce,
-	               shared_secret: &SharedSecret) -> Option<Vec<u8>> {
-		crypto_box::open_precomputed(
-			message,
-			&crypto_box::Nonce(*nonce.get_bytes()),
-			shared_secret.get_key())
+	               shared_secret: &SharedSecret) -> bool {
+		if message.len() < ZERO_BYTES - BOX_ZERO_BYTES {
+			return false;
+		}
+
+		message.push_zeros(BOX_ZERO_BYTES);
+
+		let len = message.len();
+		let ret = {
+			let content = message.as_mut_slice();
+			unsafe {
+				ffi::crypto_box_curve25519xsalsa20poly1305_open_afternm(
+					content.as_mut_ptr(),
+					content.as_ptr(),
+					len as u64,
+					nonce.get_bytes(),
+					&shared_secret.get_key().0)
+			}
+		};
+
+		if ret == 0 {
+			message.pop(ZERO_BYTES);
+			true
+		} else {
+			message.pop(BOX_ZERO_BYTES);
+			false
+		}
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{CryptoBox, Nonce, SharedSecret};
+	use message::Message;
+	use PrivateIdentity;
+
+	#[test]
+	fn test_encrypt_decrypt() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let alice_secret = SharedSecret::without_password(&alice.private_key, &bob.public_key);
+		let bob_secret = SharedSecret::without_password(&bob.private_key, &alice.public_key);
+		let nonce = [7u8; 24];
+
+		let mut message = Message::from_slice(b"in place");
+		let headroom = message.headroom();
+		CryptoBox::encrypt(&mut message, &Nonce::Mine(nonce), &alice_secret);
+		assert_eq!(message.len(), 16 + 8);
+		assert_eq!(message.headroom(), headroom - 16);
+		assert!(message.as_slice() != b"in place");
+
+		assert!(CryptoBox::decrypt(&mut message, &Nonce::Hers(nonce), &bob_secret));
+		assert_eq!(message.as_slice(), b"in place");
+		assert_eq!(message.headroom(), headroom);
+	}
+
+	#[test]
+	fn test_tampered() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let alice_secret = SharedSecret::without_password(&alice.private_key, &bob.public_key);
+		let bob_secret = SharedSecret::without_password(&bob.private_key, &alice.public_key);
+		let nonce = [7u8; 24];
+
+		let mut message = Message::from_slice(b"in place");
+		CryptoBox::encrypt(&mut message, &Nonce::Mine(nonce), &alice_secret);
+		message.as_mut_slice()[20] ^= 1;
+		assert!(!CryptoBox::decrypt(&mut message, &Nonce::Hers(nonce), &bob_secret));
+
+		let mut message = Message::from_slice(&[0u8; 15]);
+		assert!(!CryptoBox::decrypt(&mut message, &Nonce::Hers(nonce), &bob_secret));
 	}
 }
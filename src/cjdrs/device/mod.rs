@@ -1,10 +1,16 @@
 pub use self::tun::Tun;
-pub use self::udp::Udp;
+pub use self::udp::{
+	Udp,
+	BeaconMode,
+	DEFAULT_DUAL_STACK,
+	DEFAULT_BEACON_MODE,
+	DEFAULT_BEACON_PORT};
 
 use std::fmt;
 use mio::net::SockAddr;
 use CjdrsResult;
 use EventReceiver;
+use Message;
 use Task;
 
 mod tun;
@@ -13,5 +19,14 @@ mod udp;
 
 pub trait NetDevice: EventReceiver + fmt::Debug {
 	fn send_message(&mut self, message: &[u8], to: Option<&SockAddr>) -> CjdrsResult<()>;
-	fn receive_message<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task>;
+	fn receive_message<'a>(&'a mut self, message: &'a mut Message) -> Option<Task<'a>>;
+
+	/// Called once a second from the event loop
+	fn timer(&mut self, _now: u64) {}
+
+	/// Sends messages that were queued while the device wasn't writable
+	fn flush(&mut self) {}
+
+	/// Whether packets from other nodes to our address are written to the device
+	fn is_tun(&self) -> bool { false }
 }
@@ -6,6 +6,7 @@ use tuntap::{TunTap, Tun};
 use Address;
 use CjdrsResult;
 use EventReceiver;
+use Message;
 use NetDevice;
 use packet;
 use Task;
@@ -50,9 +51,13 @@ impl NetDevice for Tun {
 		Ok(try!(self.tun.write(message)))
 	}
 
-	fn receive_message<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task> {
-		let data_slice = self.tun.read(buffer).ok().expect("Reading did not succeed");
-		let packet = packet::Tun::from_buffer(data_slice);
+	fn receive_message<'a>(&'a mut self, message: &'a mut Message) -> Option<Task<'a>> {
+		message.clear();
+		let len = self.tun.read(message.receive_space()).ok().expect("Reading did not succeed").len();
+		message.set_len(len);
+
+		let message: &'a Message = message;
+		let packet = packet::Tun::from_buffer(message.as_slice());
 
 		match packet {
 			Ok(tun_packet) => {
@@ -65,6 +70,10 @@ impl NetDevice for Tun {
 			}
 		}
 	}
+
+	fn is_tun(&self) -> bool {
+		true
+	}
 }
 
 impl EventReceiver for Tun {
@@ -73,8 +82,8 @@ impl EventReceiver for Tun {
 		event_loop.register(self, token)
 	}
 
-	fn receive<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task> {
-		self.receive_message(buffer)
+	fn receive<'a>(&'a mut self, message: &'a mut Message, _socket: usize) -> Option<Task<'a>> {
+		self.receive_message(message)
 	}
 }
 
@@ -1,78 +1,420 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::old_io::IoError;
+use std::old_io::net::ip::IpAddr::{Ipv4Addr, Ipv6Addr};
+use libc;
 use mio;
 use mio::net::SockAddr;
 use mio::net::udp::UdpSocket;
-use mio::{event, IoReader};
-use mio::buf::SliceBuf;
+use mio::{event, IoHandle, NonBlock};
+use mio::buf::{SliceBuf, MutSliceBuf, MutBuf};
 use mio::net::UnconnectedSocket;
 use CjdrsResult;
 use CjdrsError;
 use EventReceiver;
+use Message;
 use NetDevice;
+use PublicKey;
 use Task;
-use packet;
+use packet::{self, Beacon, BeaconHeader, BEACON_PASSWORD_LENGTH};
+use PROTOCOL_VERSION;
+
+
+/// Packets waiting for the socket to become writable before new ones are
+/// dropped
+const MAX_QUEUE_LENGTH: usize = 1024;
+
+/// Seconds between two beacons
+const BEACON_INTERVAL: u64 = 30;
+
+/// Sockets of the device, see `EventReceiver`
+const PEERING_SOCKET: usize = 0;
+const BEACON_SOCKET: usize = 1;
+
+/// Whether IPv6 devices also carry IPv4 when the config doesn't say
+pub const DEFAULT_DUAL_STACK: bool = true;
+
+/// Beacon settings used when the config doesn't have them
+pub const DEFAULT_BEACON_MODE: BeaconMode = BeaconMode::SendAndAccept;
+pub const DEFAULT_BEACON_PORT: u16 = 64512;
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+const IPV6_V6ONLY: libc::c_int = 27;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd")))]
+const IPV6_V6ONLY: libc::c_int = 26;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BeaconMode {
+	Off,
+	/// Peer with nodes whose beacons we hear
+	Accept,
+	/// Also broadcast beacons of our own
+	SendAndAccept
+}
+
+impl BeaconMode {
+	pub fn as_u8(&self) -> u8 {
+		match *self {
+			BeaconMode::Off => 0,
+			BeaconMode::Accept => 1,
+			BeaconMode::SendAndAccept => 2
+		}
+	}
+
+	pub fn from_u8(mode: u8) -> Option<BeaconMode> {
+		match mode {
+			0 => Some(BeaconMode::Off),
+			1 => Some(BeaconMode::Accept),
+			2 => Some(BeaconMode::SendAndAccept),
+			_ => None
+		}
+	}
+}
+
+
+
+#[derive(Debug)]
+struct Beacons {
+	/// Listens for beacons. Our own go out from the peering socket.
+	sock: UdpSocket,
+	broadcast_addr: SockAddr,
+	/// Our own beacon, if we send any
+	beacon: Option<Vec<u8>>,
+	last_sent: u64
+}
+
 
 
 #[derive(Debug)]
 pub struct Udp {
-	send_sock: UdpSocket,
-	recv_sock: UdpSocket
+	sock: UdpSocket,
+	queue: VecDeque<(Vec<u8>, SockAddr)>,
+	ipv6: bool,
+	dual_stack: bool,
+	beacons: Option<Beacons>
 }
 
 impl Udp {
-	pub fn create(bind: &str) -> CjdrsResult<Udp> {
-		let send_sock = try!(UdpSocket::v4());
-		let recv_sock = try!(UdpSocket::v4());
-
+	/// Opens a UDP device bound to an IPv4 or IPv6 address, such as
+	/// `0.0.0.0:3300` or `[::]:3300`. A dual-stack IPv6 device also reaches
+	/// IPv4 peers through IPv4-mapped addresses.
+	pub fn create(bind: &str, dual_stack: bool) -> CjdrsResult<Udp> {
 		let bind_addr = match SockAddr::parse(bind) {
 			Some(a) => a,
 			None => fail!(CjdrsError::InvalidBindAddress(bind.to_string()))
 		};
 
-		try!(recv_sock.bind(&bind_addr));
+		let ipv6 = match bind_addr {
+			SockAddr::InetAddr(Ipv4Addr(..), _) => false,
+			SockAddr::InetAddr(Ipv6Addr(..), _) => true,
+			_ => fail!(CjdrsError::InvalidBindAddress(bind.to_string()))
+		};
+
+		let sock = if ipv6 {
+			let sock = try!(UdpSocket::v6());
+			// The system default varies, so always set it before binding
+			try!(set_v6only(&sock, !dual_stack));
+			sock
+		} else {
+			try!(UdpSocket::v4())
+		};
+
+		try!(sock.bind(&bind_addr));
 
 		Ok(Udp {
-			send_sock: send_sock,
-			recv_sock: recv_sock
+			sock: sock,
+			queue: VecDeque::new(),
+			ipv6: ipv6,
+			dual_stack: ipv6 && dual_stack,
+			beacons: None
 		})
 	}
+
+	/// Listens for beacons of other nodes on the LAN on `beacon_port` and, in
+	/// send mode, broadcasts our own beacon there. Peers connecting through
+	/// our beacon authenticate with `password`. Beacons are IPv4 broadcasts
+	/// sent from the peering socket, so it has to be an IPv4 socket.
+	pub fn enable_beacons(&mut self,
+	                      mode: BeaconMode,
+	                      beacon_port: u16,
+	                      public_key: &PublicKey,
+	                      password: &[u8; BEACON_PASSWORD_LENGTH]) -> CjdrsResult<()> {
+		if mode == BeaconMode::Off {
+			return Ok(());
+		}
+		if !self.can_broadcast() {
+			fail!(CjdrsError::BeaconsNeedIpv4);
+		}
+
+		let sock = try!(UdpSocket::v4());
+		try!(sock.bind(&SockAddr::InetAddr(Ipv4Addr(0, 0, 0, 0), beacon_port)));
+
+		let beacon = if mode == BeaconMode::SendAndAccept {
+			try!(self.sock.set_broadcast(true));
+			let mut message = Message::new(64, 64);
+			BeaconHeader::new(PROTOCOL_VERSION, password, public_key).push_to(&mut message);
+			Some(message.as_slice().to_vec())
+		} else {
+			None
+		};
+
+		self.beacons = Some(Beacons {
+			sock: sock,
+			broadcast_addr: SockAddr::InetAddr(Ipv4Addr(255, 255, 255, 255), beacon_port),
+			beacon: beacon,
+			last_sent: 0
+		});
+		Ok(())
+	}
+
+	pub fn supports_ipv4(&self) -> bool {
+		!self.ipv6 || self.dual_stack
+	}
+
+	pub fn supports_ipv6(&self) -> bool {
+		self.ipv6
+	}
+
+	/// Whether the device can send and receive beacons
+	pub fn can_broadcast(&self) -> bool {
+		!self.ipv6
+	}
+
+	/// Whether peers at the address can be reached through this device
+	pub fn can_send_to(&self, address: &SockAddr) -> bool {
+		match *address {
+			SockAddr::InetAddr(Ipv4Addr(..), _) => self.supports_ipv4(),
+			SockAddr::InetAddr(Ipv6Addr(..), _) => self.supports_ipv6(),
+			_ => false
+		}
+	}
+
+	/// Sends a packet unless the socket would block. Returns false if it
+	/// wasn't sent.
+	fn try_send(&mut self, message: &[u8], address: &SockAddr) -> CjdrsResult<bool> {
+		let mut buf = SliceBuf::wrap(message);
+		match try!(self.sock.send_to(&mut buf, address)) {
+			NonBlock::Ready(..) => Ok(true),
+			NonBlock::WouldBlock => Ok(false)
+		}
+	}
+
+	/// Reads a packet from the beacon socket, if beacons are enabled
+	fn receive_beacon<'a>(&'a mut self, message: &'a mut Message) -> Option<Task<'a>> {
+		message.clear();
+
+		let beacons = match self.beacons {
+			Some(ref b) => b,
+			None => return None
+		};
+
+		let from = match receive_from(&beacons.sock, message) {
+			Some(from) => from,
+			None => return None
+		};
+
+		let beacon = match Beacon::from_buffer(message.as_slice()) {
+			Ok(b) => b,
+			Err(e) => {
+				println!("Received an invalid beacon: {}", e);
+				return None;
+			}
+		};
+		let password = match String::from_utf8(beacon.password().to_vec()) {
+			Ok(p) => p,
+			Err(..) => {
+				println!("Received a beacon with an invalid password");
+				return None;
+			}
+		};
+
+		Some(Task::HandleBeacon(from, beacon.public_key(), password))
+	}
 }
 
 impl NetDevice for Udp {
 	fn send_message(&mut self, message: &[u8], to: Option<&SockAddr>) -> CjdrsResult<()> {
 		let address = match to {
-			Some(a) => a,
+			Some(a) if self.dual_stack => ipv4_mapped(a),
+			Some(a) => a.clone(),
 			None => unreachable!()
 		};
 
-		let mut buf = SliceBuf::wrap(message);
-		try!(self.send_sock.send_to(&mut buf, address));
+		// Queued packets go first to keep the order
+		if self.queue.is_empty() && try!(self.try_send(message, &address)) {
+			return Ok(());
+		}
+
+		if self.queue.len() >= MAX_QUEUE_LENGTH {
+			fail!(CjdrsError::SendQueueFull);
+		}
+		self.queue.push_back((message.to_vec(), address));
 		Ok(())
 	}
 
-	fn receive_message<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task> {
-		let len = self.recv_sock.read_slice(buffer).unwrap().unwrap();
-		let data = &buffer[..len];
+	fn timer(&mut self, now: u64) {
+		let beacons = match self.beacons {
+			Some(ref mut b) => b,
+			None => return
+		};
+
+		if let Some(ref beacon) = beacons.beacon {
+			if now >= beacons.last_sent + BEACON_INTERVAL {
+				beacons.last_sent = now;
 
-		match packet::CryptoAuth::from_buffer(data) {
-			Ok(ca_packet) => {
-				Some(Task::HandleIncomingPacket(ca_packet))
-			},
-			Err(e) => {
-				println!("Received an invalid packet from udp device: {}", e);
-				None
+				let mut buf = SliceBuf::wrap(beacon.as_slice());
+				if let Err(e) = self.sock.send_to(&mut buf, &beacons.broadcast_addr) {
+					println!("Sending a beacon failed: {:?}", e);
+				}
 			}
 		}
 	}
+
+	fn flush(&mut self) {
+		while let Some((message, address)) = self.queue.pop_front() {
+			match self.try_send(message.as_slice(), &address) {
+				Ok(true) => (),
+				Ok(false) => {
+					self.queue.push_front((message, address));
+					return;
+				},
+				Err(e) => println!("Sending a queued packet to {:?} failed: {}", address, e)
+			}
+		}
+	}
+
+	fn receive_message<'a>(&'a mut self, message: &'a mut Message) -> Option<Task<'a>> {
+		message.clear();
+
+		// Peers are known by their IPv4 address, however they reached us
+		let from = match receive_from(&self.sock, message) {
+			Some(from) if self.dual_stack => ipv4_unmapped(&from),
+			Some(from) => from,
+			None => return None
+		};
+
+		if let Err(e) = packet::CryptoAuth::from_buffer(message.as_slice()) {
+			println!("Received an invalid packet from udp device: {}", e);
+			return None;
+		}
+
+		Some(Task::HandleIncomingPacket(from, message))
+	}
 }
 
 
 impl EventReceiver for Udp {
 	fn register(&self, event_loop: &mut mio::EventLoop<usize, ()>, token: mio::Token)
 	           -> mio::MioResult<()> {
-		event_loop.register_opt(&self.recv_sock, token, event::READABLE, event::EDGE)
+		// Writability is only edge triggered when a full send buffer drains,
+		// which is when queued packets can go out
+		let peering_token = mio::Token(token.as_usize() + PEERING_SOCKET);
+		try!(event_loop.register_opt(&self.sock, peering_token, event::READABLE | event::WRITABLE, event::EDGE));
+
+		if let Some(ref beacons) = self.beacons {
+			let beacon_token = mio::Token(token.as_usize() + BEACON_SOCKET);
+			try!(event_loop.register_opt(&beacons.sock, beacon_token, event::READABLE, event::EDGE));
+		}
+		Ok(())
+	}
+
+	fn receive<'a>(&'a mut self, message: &'a mut Message, socket: usize) -> Option<Task<'a>> {
+		match socket {
+			BEACON_SOCKET => self.receive_beacon(message),
+			_ => self.receive_message(message)
+		}
+	}
+}
+
+
+/// Reads a packet into the message. Returns the sender, or None if there was
+/// nothing to read.
+fn receive_from(sock: &UdpSocket, message: &mut Message) -> Option<SockAddr> {
+	let (len, from) = {
+		let space = message.receive_space();
+		let space_len = space.len();
+		let mut buf = MutSliceBuf::wrap(space);
+		match sock.recv_from(&mut buf) {
+			Ok(NonBlock::Ready(from)) => (space_len - buf.remaining(), from),
+			Ok(NonBlock::WouldBlock) => return None,
+			Err(e) => {
+				println!("Receiving from udp device failed: {:?}", e);
+				return None;
+			}
+		}
+	};
+	message.set_len(len);
+	Some(from)
+}
+
+/// Sets whether an IPv6 socket is limited to IPv6 or also carries IPv4
+fn set_v6only(sock: &UdpSocket, v6only: bool) -> CjdrsResult<()> {
+	let value: libc::c_int = if v6only { 1 } else { 0 };
+	let result = unsafe {
+		libc::setsockopt(
+			sock.desc().fd,
+			libc::IPPROTO_IPV6,
+			IPV6_V6ONLY,
+			&value as *const libc::c_int as *const libc::c_void,
+			mem::size_of::<libc::c_int>() as libc::socklen_t)
+	};
+	if result != 0 {
+		fail!(IoError::last_error());
 	}
+	Ok(())
+}
+
+/// Converts an IPv4 address to the IPv4-mapped IPv6 address `::ffff:a.b.c.d`
+/// so it can be used with an IPv6 socket. Other addresses are left as they are.
+fn ipv4_mapped(address: &SockAddr) -> SockAddr {
+	match *address {
+		SockAddr::InetAddr(Ipv4Addr(a, b, c, d), port) => SockAddr::InetAddr(
+			Ipv6Addr(0, 0, 0, 0, 0, 0xFFFF,
+			         (a as u16) << 8 | b as u16,
+			         (c as u16) << 8 | d as u16),
+			port),
+		ref other => other.clone()
+	}
+}
+
+/// Converts an IPv4-mapped IPv6 address back to the IPv4 address. Other
+/// addresses are left as they are.
+fn ipv4_unmapped(address: &SockAddr) -> SockAddr {
+	match *address {
+		SockAddr::InetAddr(Ipv6Addr(0, 0, 0, 0, 0, 0xFFFF, ab, cd), port) => SockAddr::InetAddr(
+			Ipv4Addr((ab >> 8) as u8, ab as u8, (cd >> 8) as u8, cd as u8),
+			port),
+		ref other => other.clone()
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{ipv4_mapped, ipv4_unmapped};
+	use mio::net::SockAddr;
+
+	#[test]
+	fn test_ipv4_mapped() {
+		let v4 = SockAddr::parse("192.168.1.2:3300").unwrap();
+		assert_eq!(ipv4_mapped(&v4), SockAddr::parse("[::ffff:c0a8:102]:3300").unwrap());
+
+		let v6 = SockAddr::parse("[fc00::1]:3300").unwrap();
+		assert_eq!(ipv4_mapped(&v6), v6);
+	}
+
+	#[test]
+	fn test_ipv4_unmapped() {
+		let v4 = SockAddr::parse("192.168.1.2:3300").unwrap();
+		let mapped = SockAddr::parse("[::ffff:c0a8:102]:3300").unwrap();
+		assert_eq!(ipv4_unmapped(&mapped), v4);
+		assert_eq!(ipv4_unmapped(&ipv4_mapped(&v4)), v4);
+		assert_eq!(ipv4_unmapped(&v4), v4);
 
-	fn receive<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task> {
-		self.receive_message(buffer)
+		let v6 = SockAddr::parse("[fc00::ffff:1]:3300").unwrap();
+		assert_eq!(ipv4_unmapped(&v6), v6);
 	}
 }
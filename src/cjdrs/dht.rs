@@ -0,0 +1,366 @@
+//! Router messages cjdrs nodes exchange to find each other in the DHT.
+//! They are bencoded dictionaries like the router messages of cjdns:
+//!
+//! * `txid`: Transaction id, echoed in the reply
+//! * `p`: Protocol version of the sender
+//! * `es`: Encoding scheme of the sender's switch, as a form list
+//! * `ei`: Form of the sender's director towards the receiver
+//! * `q`: Query type, only in queries
+//! * `tar`: Address to find in `fn` queries, label to start from in `gp`
+//!   queries
+//! * `n`: Nodes in replies, public key and label of each
+//! * `np`: Versions of the nodes in `n`
+//!
+//! cjdns carries its router messages in end-to-end CryptoAuth sessions.
+//! These travel in the clear under `ROUTER_HANDLE` instead, so they are a
+//! protocol of cjdrs and cjdns nodes don't understand them.
+
+use std::collections::BTreeMap;
+use encoding_scheme::FormList;
+use identity::PUB_KEY_SIZE;
+use util::bencode::{Bencode, BencodeResult, ToBencode, FromBencode};
+use Address;
+use PublicKey;
+use Route;
+
+
+/// Handle that marks a frame following a switch header as a cjdrs router
+/// message
+pub const ROUTER_HANDLE: u32 = 0xFFFF_FFFE;
+
+/// Bytes of a node in `n`: the public key followed by the big endian label
+const NODE_ENTRY_LENGTH: usize = PUB_KEY_SIZE + 8;
+
+
+
+/// A node announced in a reply. The label is the route from the node that
+/// sent the reply.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NodeEntry {
+	pub public_key: PublicKey,
+	pub label: Route,
+	pub version: u32
+}
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DhtQuery {
+	/// Asks for the version and encoding scheme
+	Ping,
+	/// Asks for the nodes closest to an address
+	FindNode(Address),
+	/// Asks for direct peers with labels after the given one, so all peers can
+	/// be fetched in several queries
+	GetPeers(Route)
+}
+
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DhtBody {
+	Query(DhtQuery),
+	Reply(Vec<NodeEntry>)
+}
+
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DhtMessage {
+	pub txid: Vec<u8>,
+	pub version: u32,
+	pub scheme: Option<FormList>,
+	pub return_form: Option<usize>,
+	pub body: DhtBody
+}
+
+impl DhtMessage {
+	pub fn query(txid: Vec<u8>, version: u32, query: DhtQuery) -> DhtMessage {
+		DhtMessage {
+			txid: txid,
+			version: version,
+			scheme: None,
+			return_form: None,
+			body: DhtBody::Query(query)
+		}
+	}
+
+	/// Reply to this message with the same transaction id
+	pub fn reply(&self, version: u32, nodes: Vec<NodeEntry>) -> DhtMessage {
+		DhtMessage {
+			txid: self.txid.clone(),
+			version: version,
+			scheme: None,
+			return_form: None,
+			body: DhtBody::Reply(nodes)
+		}
+	}
+}
+
+impl ToBencode for DhtMessage {
+	fn to_bencode(&self) -> Bencode {
+		let mut dict = BTreeMap::new();
+		dict.insert(b"txid".to_vec(), Bencode::ByteString(self.txid.clone()));
+		dict.insert(b"p".to_vec(), self.version.to_bencode());
+		if let Some(ref scheme) = self.scheme {
+			dict.insert(b"es".to_vec(), Bencode::ByteString(scheme.serialize()));
+		}
+		if let Some(form) = self.return_form {
+			dict.insert(b"ei".to_vec(), (form as u32).to_bencode());
+		}
+
+		match self.body {
+			DhtBody::Query(DhtQuery::Ping) => {
+				dict.insert(b"q".to_vec(), Bencode::ByteString(b"pn".to_vec()));
+			},
+			DhtBody::Query(DhtQuery::FindNode(ref target)) => {
+				dict.insert(b"q".to_vec(), Bencode::ByteString(b"fn".to_vec()));
+				dict.insert(b"tar".to_vec(), Bencode::ByteString(target.as_slice().to_vec()));
+			},
+			DhtBody::Query(DhtQuery::GetPeers(ref label)) => {
+				dict.insert(b"q".to_vec(), Bencode::ByteString(b"gp".to_vec()));
+				dict.insert(b"tar".to_vec(), Bencode::ByteString(pack_label(label)));
+			},
+			DhtBody::Reply(ref nodes) if !nodes.is_empty() => {
+				let mut packed = Vec::with_capacity(nodes.len() * NODE_ENTRY_LENGTH);
+				for node in nodes.iter() {
+					packed.push_all(node.public_key.as_slice());
+					packed.push_all(pack_label(&node.label).as_slice());
+				}
+				dict.insert(b"n".to_vec(), Bencode::ByteString(packed));
+
+				let versions: Vec<u32> = nodes.iter().map(|n| n.version).collect();
+				dict.insert(b"np".to_vec(), Bencode::ByteString(pack_versions(versions.as_slice())));
+			},
+			DhtBody::Reply(..) => ()
+		}
+		Bencode::Dict(dict)
+	}
+}
+
+impl FromBencode for DhtMessage {
+	fn from_bencode(value: &Bencode) -> BencodeResult<DhtMessage> {
+		if value.as_dict().is_none() {
+			return Err("Router message is not a dictionary");
+		}
+
+		let txid = match value.get("txid").and_then(|t| t.as_bytes()) {
+			Some(t) => t.to_vec(),
+			None => return Err("Router message without transaction id")
+		};
+		let version = match value.get("p") {
+			Some(p) => try!(FromBencode::from_bencode(p)),
+			None => return Err("Router message without version")
+		};
+		let scheme = match value.get("es") {
+			Some(es) => match es.as_bytes() {
+				Some(data) => Some(try!(FormList::deserialize(data))),
+				None => return Err("Encoding scheme is not a string")
+			},
+			None => None
+		};
+		let return_form = match value.get("ei") {
+			Some(ei) => {
+				let form: u32 = try!(FromBencode::from_bencode(ei));
+				Some(form as usize)
+			},
+			None => None
+		};
+
+		let body = match value.get("q") {
+			Some(q) => DhtBody::Query(try!(parse_query(q, value))),
+			None => DhtBody::Reply(try!(parse_nodes(value)))
+		};
+
+		Ok(DhtMessage {
+			txid: txid,
+			version: version,
+			scheme: scheme,
+			return_form: return_form,
+			body: body
+		})
+	}
+}
+
+
+fn parse_query(q: &Bencode, message: &Bencode) -> BencodeResult<DhtQuery> {
+	match q.as_str() {
+		Some("pn") => Ok(DhtQuery::Ping),
+		Some("fn") => {
+			let target = match message.get("tar").and_then(|t| t.as_bytes()) {
+				Some(t) if t.len() == 16 => t,
+				_ => return Err("Find node query without a valid target")
+			};
+			match Address::from_slice(target) {
+				Some(address) => Ok(DhtQuery::FindNode(address)),
+				None => Err("Find node query without a valid target")
+			}
+		},
+		Some("gp") => match message.get("tar").and_then(|t| t.as_bytes()) {
+			Some(t) if t.len() == 8 => Ok(DhtQuery::GetPeers(unpack_label(t))),
+			_ => Err("Get peers query without a valid label")
+		},
+		_ => Err("Unknown router query")
+	}
+}
+
+fn parse_nodes(message: &Bencode) -> BencodeResult<Vec<NodeEntry>> {
+	let packed = match message.get("n") {
+		Some(n) => match n.as_bytes() {
+			Some(n) => n,
+			None => return Err("Nodes are not a string")
+		},
+		None => return Ok(vec![])
+	};
+	if packed.len() % NODE_ENTRY_LENGTH != 0 {
+		return Err("Nodes are not a multiple of the node entry length");
+	}
+	let count = packed.len() / NODE_ENTRY_LENGTH;
+
+	let versions = match message.get("np").and_then(|np| np.as_bytes()) {
+		Some(np) => try!(unpack_versions(np, count)),
+		None => return Err("Nodes without versions")
+	};
+
+	Ok(packed.chunks(NODE_ENTRY_LENGTH).zip(versions.into_iter()).map(|(entry, version)| {
+		NodeEntry {
+			public_key: PublicKey::from_slice(&entry[..PUB_KEY_SIZE]),
+			label: unpack_label(&entry[PUB_KEY_SIZE..]),
+			version: version
+		}
+	}).collect())
+}
+
+
+fn pack_label(label: &Route) -> Vec<u8> {
+	range(0usize, 8).rev().map(|i| (label.bits() >> (i * 8)) as u8).collect()
+}
+
+fn unpack_label(packed: &[u8]) -> Route {
+	Route::new(packed.iter().fold(0u64, |label, b| label << 8 | *b as u64))
+}
+
+
+/// Versions are packed big endian with the width in bytes as first byte
+fn pack_versions(versions: &[u32]) -> Vec<u8> {
+	let largest = versions.iter().fold(0, |largest, v| if *v > largest { *v } else { largest });
+	let mut width = 1usize;
+	while width < 4 && largest >> (width * 8) != 0 {
+		width += 1;
+	}
+
+	let mut packed = vec![width as u8];
+	for version in versions.iter() {
+		for i in range(0, width).rev() {
+			packed.push((*version >> (i * 8)) as u8);
+		}
+	}
+	packed
+}
+
+fn unpack_versions(packed: &[u8], count: usize) -> BencodeResult<Vec<u32>> {
+	let width = match packed.first() {
+		Some(&w) if w >= 1 && w <= 4 => w as usize,
+		_ => return Err("Invalid version width")
+	};
+	if packed.len() != 1 + width * count {
+		return Err("Number of versions doesn't match the nodes");
+	}
+
+	Ok(packed[1..].chunks(width).map(|v| {
+		v.iter().fold(0u32, |version, b| version << 8 | *b as u32)
+	}).collect())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{DhtMessage, DhtQuery, DhtBody, NodeEntry};
+	use encoding_scheme::{EncodingScheme, Variable3x5x8};
+	use util::bencode::{encode, decode};
+	use Address;
+	use PublicKey;
+	use Route;
+
+	#[test]
+	fn test_find_node() {
+		let target = Address::from_string("fc00::1").unwrap();
+		let query = DhtMessage::query(vec![0, 0, 0, 7], 16, DhtQuery::FindNode(target));
+		let data = encode(&query);
+		assert_eq!(data, b"d1:pi16e1:q2:fn3:tar16:\xfc\x00\x00\x00\x00\x00\x00\x00\
+		                   \x00\x00\x00\x00\x00\x00\x00\x014:txid4:\x00\x00\x00\x07e".to_vec());
+		assert_eq!(decode::<DhtMessage>(data.as_slice()), Ok(query));
+
+		// Target outside of fc00::/8
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:fn3:tar16:\xfd\x00\x00\x00\x00\x00\x00\x00\
+		                               \x00\x00\x00\x00\x00\x00\x00\x014:txid0:e").is_err());
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:fn3:tar2:\xfc\x004:txid0:e").is_err());
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:xx4:txid0:e").is_err());
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:fne").is_err());
+	}
+
+	#[test]
+	fn test_ping_get_peers() {
+		let ping = DhtMessage::query(vec![2], 16, DhtQuery::Ping);
+		let data = encode(&ping);
+		assert_eq!(data, b"d1:pi16e1:q2:pn4:txid1:\x02e".to_vec());
+		assert_eq!(decode::<DhtMessage>(data.as_slice()), Ok(ping));
+
+		let get_peers = DhtMessage::query(vec![3], 16, DhtQuery::GetPeers(Route::new(0x13)));
+		let data = encode(&get_peers);
+		assert_eq!(data, b"d1:pi16e1:q2:gp3:tar8:\x00\x00\x00\x00\x00\x00\x00\x134:txid1:\x03e".to_vec());
+		assert_eq!(decode::<DhtMessage>(data.as_slice()), Ok(get_peers));
+
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:gp3:tar4:\x00\x00\x00\x134:txid1:\x03e").is_err());
+		assert!(decode::<DhtMessage>(b"d1:pi16e1:q2:gp4:txid1:\x03e").is_err());
+	}
+
+	#[test]
+	fn test_reply() {
+		let query = DhtMessage::query(vec![1], 16, DhtQuery::FindNode(Address::from_string("fc00::1").unwrap()));
+		let mut reply = query.reply(16, vec![
+			NodeEntry { public_key: PublicKey::from_buffer(&[1; 32]), label: Route::new(0x13), version: 16 },
+			NodeEntry { public_key: PublicKey::from_buffer(&[2; 32]), label: Route::new(0x1_0000_0153), version: 300 }
+		]);
+		reply.scheme = Variable3x5x8.form_list();
+		reply.return_form = Some(1);
+
+		let data = encode(&reply);
+		let decoded = decode::<DhtMessage>(data.as_slice()).unwrap();
+		assert_eq!(decoded, reply);
+		assert_eq!(decoded.txid, vec![1]);
+
+		// Versions are two bytes wide because of the second node
+		let value = ::util::bencode::Bencode::decode(data.as_slice()).unwrap();
+		assert_eq!(value.get("np").unwrap().as_bytes(), Some(b"\x02\x00\x10\x01\x2c"));
+		assert_eq!(&value.get("n").unwrap().as_bytes().unwrap()[72..], b"\x00\x00\x00\x01\x00\x00\x01\x53");
+
+		let empty = query.reply(16, vec![]);
+		assert_eq!(encode(&empty), b"d1:pi16e4:txid1:\x01e".to_vec());
+		assert_eq!(decode::<DhtMessage>(b"d1:pi16e4:txid1:\x01e").unwrap().body, DhtBody::Reply(vec![]));
+	}
+
+	#[test]
+	fn test_malformed_reply() {
+		// Node entry one byte short
+		let mut data = b"d1:n39:".to_vec();
+		data.push_all(&[0; 39]);
+		data.push_all(b"2:np2:\x01\x104:txid0:e");
+		assert!(decode::<DhtMessage>(data.as_slice()).is_err());
+
+		// Missing versions
+		let mut data = b"d1:n40:".to_vec();
+		data.push_all(&[0; 40]);
+		data.push_all(b"1:pi16e4:txid0:e");
+		assert!(decode::<DhtMessage>(data.as_slice()).is_err());
+
+		// Two versions for one node
+		let mut data = b"d1:n40:".to_vec();
+		data.push_all(&[0; 40]);
+		data.push_all(b"2:np3:\x01\x10\x101:pi16e4:txid0:e");
+		assert!(decode::<DhtMessage>(data.as_slice()).is_err());
+
+		assert!(decode::<DhtMessage>(b"d4:txid0:e").is_err());
+		assert!(decode::<DhtMessage>(b"d1:pi16ee").is_err());
+		assert!(decode::<DhtMessage>(b"d2:es1:\x001:pi16e4:txid0:e").is_err());
+		assert!(decode::<DhtMessage>(b"li16ee").is_err());
+	}
+}
@@ -1,3 +1,36 @@
+use encoding_scheme::{EncodingScheme, FormList, Form};
+
+
+/// Four bits per hop, for switches with up to 16 interfaces
+#[derive(Debug, Copy, Clone)]
+pub struct Fixed4;
+
+impl EncodingScheme for Fixed4 {
+	fn bits_used_for_label(&self, label: u64) -> Option<u8> {
+		Some(bits_used_for_label(label))
+	}
+
+	fn bits_used_for_number(&self, number: u32) -> u8 {
+		bits_used_for_number(number)
+	}
+
+	fn compress(&self, number: u32) -> u64 {
+		compress(number)
+	}
+
+	fn decompress(&self, label: u64) -> Option<u32> {
+		Some(decompress(label & 0b1111))
+	}
+
+	fn max_number(&self) -> u32 {
+		15
+	}
+
+	fn form_list(&self) -> Option<FormList> {
+		FormList::new(vec![Form::new(4, 0, 0)]).ok()
+	}
+}
+
 
 pub fn bits_used_for_label(_label: u64) -> u8 {
 	4
@@ -1,3 +1,38 @@
+use encoding_scheme::{EncodingScheme, FormList};
+
+
+/// Eight bits per hop, with the self interface squeezed into four
+#[derive(Debug, Copy, Clone)]
+pub struct Fixed8;
+
+impl EncodingScheme for Fixed8 {
+	fn bits_used_for_label(&self, label: u64) -> Option<u8> {
+		Some(bits_used_for_label(label))
+	}
+
+	fn bits_used_for_number(&self, number: u32) -> u8 {
+		bits_used_for_number(number)
+	}
+
+	fn compress(&self, number: u32) -> u64 {
+		compress(number)
+	}
+
+	fn decompress(&self, label: u64) -> Option<u32> {
+		Some(decompress(label & 0b1111_1111))
+	}
+
+	fn max_number(&self) -> u32 {
+		240
+	}
+
+	/// The self interface doesn't get a prefix of its own, so there are no
+	/// forms
+	fn form_list(&self) -> Option<FormList> {
+		None
+	}
+}
+
 
 pub fn bits_used_for_label(label: u64) -> u8 {
 	if label & 0b1111 == 0b0001 {
@@ -0,0 +1,488 @@
+use std::cmp::min;
+use encoding_scheme::EncodingScheme;
+use route::{Route, MAX_ROUTE_LEN};
+
+
+/// Widths of the prefix length and bit count fields in the serialized form
+const PREFIX_LEN_BITS: usize = 5;
+const BIT_COUNT_BITS: usize = 5;
+
+/// Longest form that still leaves room in a label for other hops
+const MAX_FORM_LEN: u8 = 59;
+
+
+
+/// One way of writing a director: `bit_count` bits of director followed by a
+/// `prefix_len` bits long `prefix` that tells the forms apart.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Form {
+	pub bit_count: u8,
+	pub prefix_len: u8,
+	pub prefix: u32
+}
+
+impl Form {
+	pub fn new(bit_count: u8, prefix_len: u8, prefix: u32) -> Form {
+		Form {
+			bit_count: bit_count,
+			prefix_len: prefix_len,
+			prefix: prefix
+		}
+	}
+
+	/// Bits the form takes up in a label
+	pub fn len(&self) -> u8 {
+		self.bit_count + self.prefix_len
+	}
+
+	fn max_director(&self) -> u64 {
+		(1 << self.bit_count as usize) - 1
+	}
+}
+
+
+
+/// Scheme described by a list of forms, the way cjdns announces it to other
+/// nodes. Forms are ordered from the smallest to the largest and numbers use
+/// the smallest form they fit in.
+///
+/// Directors map to interface numbers the way cjdns does it. A single form
+/// uses the numbers as they are. With more forms, the smallest one holds the
+/// self route `0001` and swaps 0 and 1, while the others have no use for 1
+/// and shift the numbers above it down by one. That's the same as the
+/// `variable4x8` and `variable3x5x8` schemes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FormList {
+	forms: Vec<Form>
+}
+
+impl FormList {
+	/// Checks that the forms make up a usable scheme:
+	///
+	/// * Forms are ordered by growing bit count and fit the serialized form.
+	/// * A single form has no prefix. With more forms, no prefix is the start
+	///   of another one, so every label matches one form at most.
+	/// * The self route `0001` matches the smallest form, which is at least
+	///   four bits long.
+	pub fn new(forms: Vec<Form>) -> Result<FormList, &'static str> {
+		if forms.is_empty() {
+			return Err("Encoding scheme has no forms");
+		}
+
+		for form in forms.iter() {
+			if form.bit_count == 0 || form.bit_count as usize >= 1 << BIT_COUNT_BITS {
+				return Err("Form bit count out of range");
+			}
+			if form.prefix_len as usize >= 1 << PREFIX_LEN_BITS {
+				return Err("Form prefix too long");
+			}
+			if form.prefix as u64 > mask(form.prefix_len) {
+				return Err("Form prefix longer than its prefix length");
+			}
+			if form.len() > MAX_FORM_LEN {
+				return Err("Form too long");
+			}
+		}
+
+		for (i, a) in forms.iter().enumerate() {
+			for b in forms[i + 1..].iter() {
+				if b.bit_count <= a.bit_count {
+					return Err("Forms not ordered by bit count");
+				}
+			}
+		}
+
+		if forms.len() == 1 {
+			if forms[0].prefix_len != 0 {
+				return Err("Single form has a prefix");
+			}
+		} else {
+			for (i, a) in forms.iter().enumerate() {
+				if a.prefix_len == 0 {
+					return Err("Form without a prefix");
+				}
+				for b in forms[i + 1..].iter() {
+					let shared = min(a.prefix_len, b.prefix_len);
+					if a.prefix as u64 & mask(shared) == b.prefix as u64 & mask(shared) {
+						return Err("Form prefixes are ambiguous");
+					}
+				}
+			}
+		}
+
+		let list = FormList { forms: forms };
+		let self_route_valid = match list.form_of_label(1) {
+			Some(f) => *f == list.forms[0] && f.len() >= 4,
+			None => false
+		};
+		if !self_route_valid {
+			return Err("Self route must be 0001 in the smallest form");
+		}
+		Ok(list)
+	}
+
+	/// Reads a scheme in the packed format cjdns nodes announce in the DHT
+	pub fn deserialize(data: &[u8]) -> Result<FormList, &'static str> {
+		let mut forms = vec![];
+		let mut block = 0u64;
+		let mut bits = 0usize;
+		let mut bytes = data.iter();
+
+		loop {
+			// A form takes 41 bits at most
+			while bits < 56 {
+				match bytes.next() {
+					Some(&b) => block |= (b as u64) << bits,
+					None => break
+				}
+				bits += 8;
+			}
+
+			let prefix_len = (block & mask(PREFIX_LEN_BITS as u8)) as u8;
+			let bit_count = ((block >> PREFIX_LEN_BITS) & mask(BIT_COUNT_BITS as u8)) as u8;
+			let form_bits = PREFIX_LEN_BITS + BIT_COUNT_BITS + prefix_len as usize;
+			if form_bits > bits {
+				// Only padding left
+				break;
+			}
+
+			let prefix = (block >> (PREFIX_LEN_BITS + BIT_COUNT_BITS)) & mask(prefix_len);
+			forms.push(Form::new(bit_count, prefix_len, prefix as u32));
+
+			block >>= form_bits;
+			bits -= form_bits;
+		}
+
+		if block != 0 || bytes.next().is_some() {
+			return Err("Encoding scheme is truncated or has trailing data");
+		}
+		FormList::new(forms)
+	}
+
+	/// Packs the forms one after the other, least significant bit first,
+	/// each as prefix length, bit count and prefix.
+	pub fn serialize(&self) -> Vec<u8> {
+		let mut data = vec![];
+		let mut block = 0u64;
+		let mut bits = 0usize;
+
+		for form in self.forms.iter() {
+			block |= (form.prefix_len as u64) << bits;
+			bits += PREFIX_LEN_BITS;
+			block |= (form.bit_count as u64) << bits;
+			bits += BIT_COUNT_BITS;
+			block |= (form.prefix as u64) << bits;
+			bits += form.prefix_len as usize;
+
+			while bits >= 8 {
+				data.push(block as u8);
+				block >>= 8;
+				bits -= 8;
+			}
+		}
+
+		if bits > 0 {
+			data.push(block as u8);
+		}
+		data
+	}
+
+	pub fn forms(&self) -> &[Form] {
+		self.forms.as_slice()
+	}
+
+	/// Form the director at the end of the label is written in
+	pub fn form_of_label(&self, label: u64) -> Option<&Form> {
+		self.form_num(label).map(|i| &self.forms[i])
+	}
+
+	/// Position of the form the director at the end of the label is written in
+	pub fn form_num(&self, label: u64) -> Option<usize> {
+		if self.forms.len() == 1 {
+			return Some(0);
+		}
+		self.forms.iter().position(|f| label & mask(f.prefix_len) == f.prefix as u64)
+	}
+
+	/// Writes the director at the end of the label in another form, leaving
+	/// the rest of the label alone. Returns None if the director doesn't fit
+	/// the form, the label would get too long or it's the self route, which
+	/// can only be written as `0001`.
+	pub fn convert_label(&self, label: u64, form_num: usize) -> Option<u64> {
+		let current = match self.form_num(label) {
+			Some(f) => f,
+			None => return None
+		};
+		if form_num >= self.forms.len() {
+			return None;
+		}
+		if form_num == current {
+			return Some(label);
+		}
+		if self.forms.len() == 1 || label & mask(self.forms[current].len()) == 1 {
+			return None;
+		}
+
+		let number = self.number(current, (label >> self.forms[current].prefix_len as usize) &
+		                                  self.forms[current].max_director());
+		let director = match self.director(form_num, number) {
+			Some(d) => d,
+			None => return None
+		};
+
+		let rest = Route::new(label >> self.forms[current].len() as usize);
+		let form = &self.forms[form_num];
+		if rest.bit_len() + form.len() > MAX_ROUTE_LEN {
+			return None;
+		}
+		Some(rest.bits() << form.len() as usize | director << form.prefix_len as usize | form.prefix as u64)
+	}
+
+	/// Writes the director at the end of the label in the smallest form it
+	/// fits in
+	pub fn canonical_label(&self, label: u64) -> Option<u64> {
+		let number = match self.decompress(label) {
+			Some(n) => n,
+			None => return None
+		};
+		match self.form_of_number(number) {
+			Some(f) => self.convert_label(label, f),
+			None => None
+		}
+	}
+
+	/// Director of an interface number in a form, if the number fits it
+	fn director(&self, form_num: usize, number: u32) -> Option<u64> {
+		let director = if self.forms.len() == 1 {
+			Some(number as u64)
+		} else if form_num == 0 {
+			match number {
+				0 => Some(1),
+				1 => Some(0),
+				n => Some(n as u64)
+			}
+		} else {
+			match number {
+				0 => Some(0),
+				1 => None,
+				n => Some(n as u64 - 1)
+			}
+		};
+		director.and_then(|d| if d <= self.forms[form_num].max_director() { Some(d) } else { None })
+	}
+
+	/// Interface number of a director in a form
+	fn number(&self, form_num: usize, director: u64) -> u32 {
+		let number = if self.forms.len() == 1 {
+			director
+		} else if form_num == 0 {
+			match director {
+				0 => 1,
+				1 => 0,
+				d => d
+			}
+		} else {
+			match director {
+				0 => 0,
+				d => d + 1
+			}
+		};
+		number as u32
+	}
+
+	/// Smallest form the interface number fits in
+	fn form_of_number(&self, number: u32) -> Option<usize> {
+		range(0, self.forms.len()).find(|&i| self.director(i, number).is_some())
+	}
+
+	fn form_of_number_or_panic(&self, number: u32) -> usize {
+		match self.form_of_number(number) {
+			Some(f) => f,
+			None => panic!("Interface {} is too large for the scheme", number)
+		}
+	}
+}
+
+impl EncodingScheme for FormList {
+	fn bits_used_for_label(&self, label: u64) -> Option<u8> {
+		self.form_of_label(label).map(|f| f.len())
+	}
+
+	fn bits_used_for_number(&self, number: u32) -> u8 {
+		self.forms[self.form_of_number_or_panic(number)].len()
+	}
+
+	fn compress(&self, number: u32) -> u64 {
+		let form_num = self.form_of_number_or_panic(number);
+		let form = &self.forms[form_num];
+		let director = match self.director(form_num, number) {
+			Some(d) => d,
+			None => unreachable!()
+		};
+		director << form.prefix_len as usize | form.prefix as u64
+	}
+
+	fn decompress(&self, label: u64) -> Option<u32> {
+		self.form_num(label).map(|i| {
+			let form = &self.forms[i];
+			self.number(i, (label >> form.prefix_len as usize) & form.max_director())
+		})
+	}
+
+	fn max_number(&self) -> u32 {
+		let last = self.forms.len() - 1;
+		self.number(last, self.forms[last].max_director())
+	}
+
+	fn form_list(&self) -> Option<FormList> {
+		Some(self.clone())
+	}
+}
+
+
+fn mask(bits: u8) -> u64 {
+	(1 << bits as usize) - 1
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{FormList, Form};
+	use encoding_scheme::{EncodingScheme, variable3x5x8};
+
+	fn v358() -> FormList {
+		FormList::new(vec![Form::new(3, 1, 0b1), Form::new(5, 2, 0b10), Form::new(8, 2, 0b00)]).unwrap()
+	}
+
+	#[test]
+	fn test_compress_decompress() {
+		let scheme = v358();
+		assert_eq!(scheme.max_number(), 256);
+
+		for i in range(0, 257) {
+			assert_eq!(scheme.decompress(scheme.compress(i)), Some(i));
+		}
+	}
+
+	#[test]
+	fn test_forms() {
+		let scheme = v358();
+		assert_eq!(scheme.compress(1), 0b0001);
+		assert_eq!(scheme.compress(0), 0b0011);
+		assert_eq!(scheme.compress(7), 0b1111);
+		assert_eq!(scheme.compress(8), 0b001_1110);
+		assert_eq!(scheme.compress(32), 0b111_1110);
+		assert_eq!(scheme.compress(33), 0b00_1000_0000);
+
+		assert_eq!(scheme.bits_used_for_number(7), 4);
+		assert_eq!(scheme.bits_used_for_number(32), 7);
+		assert_eq!(scheme.bits_used_for_number(33), 10);
+		assert_eq!(scheme.bits_used_for_label(0b001_1110), Some(7));
+	}
+
+	#[test]
+	fn test_unknown_prefix() {
+		let scheme = FormList::new(vec![Form::new(4, 2, 0b01), Form::new(8, 2, 0b10)]).unwrap();
+		assert_eq!(scheme.bits_used_for_label(0b1111_0011), None);
+		assert_eq!(scheme.decompress(0b1111_0011), None);
+		assert_eq!(scheme.decompress(0b1111_0010), Some(61));
+	}
+
+	#[test]
+	fn test_single_form() {
+		let scheme = FormList::new(vec![Form::new(4, 0, 0)]).unwrap();
+		assert_eq!(scheme.compress(1), 1);
+		assert_eq!(scheme.compress(0), 0);
+		assert_eq!(scheme.compress(5), 5);
+		assert_eq!(scheme.decompress(0x35), Some(5));
+		assert_eq!(scheme.bits_used_for_label(0x35), Some(4));
+	}
+
+	#[test]
+	fn test_serialize() {
+		let data = [0x61, 0x14, 0x45, 0x81, 0x00];
+		assert_eq!(v358().serialize(), data.to_vec());
+		assert_eq!(FormList::deserialize(&data).unwrap(), v358());
+
+		let fixed = FormList::new(vec![Form::new(4, 0, 0)]).unwrap();
+		assert_eq!(FormList::deserialize(fixed.serialize().as_slice()).unwrap(), fixed);
+	}
+
+	#[test]
+	fn test_deserialize_invalid() {
+		assert!(FormList::deserialize(&[]).is_err());
+		assert!(FormList::deserialize(&[0x61, 0x14, 0x45, 0x81, 0x00, 0xFF]).is_err());
+		// Second form cut off
+		assert!(FormList::deserialize(&[0x61, 0x14]).is_err());
+	}
+
+	#[test]
+	fn test_validation() {
+		let v = |forms: Vec<Form>| FormList::new(forms).is_ok();
+
+		assert!(!v(vec![]));
+		assert!(!v(vec![Form::new(0, 0, 0)]));
+		assert!(!v(vec![Form::new(32, 0, 0)]));
+		assert!(!v(vec![Form::new(4, 1, 0b1)]));
+		assert!(!v(vec![Form::new(30, 30, 0)]));
+
+		// Self route shorter than four bits
+		assert!(!v(vec![Form::new(2, 1, 0b1), Form::new(8, 1, 0b0)]));
+		assert!(!v(vec![Form::new(3, 0, 0)]));
+
+		// Self route in a larger form
+		assert!(!v(vec![Form::new(3, 1, 0b0), Form::new(8, 1, 0b1)]));
+
+		// Ambiguous and missing prefixes
+		assert!(!v(vec![Form::new(3, 1, 0b1), Form::new(8, 2, 0b11)]));
+		assert!(!v(vec![Form::new(3, 1, 0b1), Form::new(8, 0, 0)]));
+
+		// Unordered
+		assert!(!v(vec![Form::new(8, 1, 0b1), Form::new(3, 1, 0b0)]));
+
+		assert!(v(vec![Form::new(4, 1, 0b1), Form::new(8, 1, 0b0)]));
+	}
+
+	#[test]
+	fn test_same_as_variable3x5x8() {
+		let scheme = v358();
+		for i in range(0, 257) {
+			assert_eq!(scheme.compress(i), variable3x5x8::compress(i));
+		}
+	}
+
+	#[test]
+	fn test_convert_label() {
+		let scheme = v358();
+
+		// Interface 2 with a hop behind it, widened to five and eight bits
+		let label = 0b1_0111_0101;
+		assert_eq!(scheme.convert_label(label, 1), Some(0b1_0111_000_0110));
+		assert_eq!(scheme.convert_label(label, 2), Some(0b1_0111_0000_0001_00));
+		assert_eq!(scheme.canonical_label(0b1_0111_0000_0001_00), Some(label));
+		assert_eq!(scheme.convert_label(label, 0), Some(label));
+
+		// Interface 0 is 1 in the smallest form and 0 in the others
+		assert_eq!(scheme.convert_label(0b1_0011, 1), Some(0b1_00000_10));
+		assert_eq!(scheme.canonical_label(0b1_00000_10), Some(0b1_0011));
+
+		// Too large for the smaller forms
+		assert_eq!(scheme.convert_label(0b1_00100000_00, 1), None);
+		assert_eq!(scheme.convert_label(0b1_00100000_00, 0), None);
+		assert_eq!(scheme.canonical_label(0b1_00100000_00), Some(0b1_00100000_00));
+
+		// The self route stays as it is
+		assert_eq!(scheme.convert_label(0b0001, 1), None);
+		assert_eq!(scheme.canonical_label(0b0001), Some(0b0001));
+
+		// No room left for the wider director
+		let long = 1 << 59 | 0b0101;
+		assert_eq!(scheme.convert_label(long, 0), Some(long));
+		assert_eq!(scheme.convert_label(long, 1), None);
+
+		assert_eq!(scheme.convert_label(label, 3), None);
+		let fixed = FormList::new(vec![Form::new(4, 0, 0)]).unwrap();
+		assert_eq!(fixed.convert_label(0x35, 0), Some(0x35));
+	}
+}
@@ -1,17 +1,107 @@
 //! Self interface (1) must be encoded as 1 with 3 or more leading zeros.
 //! Route label's 3 highest bits must not be all zero.
 
+use std::fmt::Debug;
+
+pub use self::fixed4::Fixed4;
+pub use self::fixed8::Fixed8;
+pub use self::form_list::{FormList, Form};
+pub use self::variable3x5x8::Variable3x5x8;
+pub use self::variable4x8::Variable4x8;
+
 pub mod fixed4;
 pub mod fixed8;
+pub mod form_list;
 pub mod variable3x5x8;
 pub mod variable4x8;
 
-// trait EncodingScheme {
-// 	fn bits_used_for_label(label: u64) -> u8;
-// 	fn bits_used_for_number(number: u32) -> u8;
-// 	fn compress(number: u32) -> u64;
-// 	fn decompress(label: u64) -> u32;
-// }
 
-// TODO Use a trait
-// TODO Tests
+/// Translates between interface numbers and the directors that select them
+/// in the low bits of a route label.
+pub trait EncodingScheme: Debug {
+	/// Width of the director at the end of the label, or None if the label
+	/// isn't valid in this scheme
+	fn bits_used_for_label(&self, label: u64) -> Option<u8>;
+
+	/// Width of the director for an interface number
+	fn bits_used_for_number(&self, number: u32) -> u8;
+
+	/// Director for an interface number. The number must not be larger than
+	/// `max_number()`.
+	fn compress(&self, number: u32) -> u64;
+
+	/// Interface number selected by the director at the end of the label
+	fn decompress(&self, label: u64) -> Option<u32>;
+
+	/// Highest interface number the scheme can express
+	fn max_number(&self) -> u32;
+
+	/// The forms of the labels, which is how the scheme is announced to other
+	/// nodes. None if the scheme can't be described by forms.
+	fn form_list(&self) -> Option<FormList>;
+}
+
+
+/// Name of the scheme cjdns uses, which the switch writes labels in unless
+/// the config chooses another one
+pub const DEFAULT_SCHEME: &'static str = "variable3x5x8";
+
+
+/// Looks up one of the built-in schemes by the name of its module
+pub fn from_name(name: &str) -> Option<Box<EncodingScheme>> {
+	match name {
+		"fixed4" => Some(Box::new(Fixed4) as Box<EncodingScheme>),
+		"fixed8" => Some(Box::new(Fixed8) as Box<EncodingScheme>),
+		"variable4x8" => Some(Box::new(Variable4x8) as Box<EncodingScheme>),
+		"variable3x5x8" => Some(Box::new(Variable3x5x8) as Box<EncodingScheme>),
+		_ => None
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{EncodingScheme, FormList, DEFAULT_SCHEME, from_name};
+
+	fn check_scheme(scheme: &EncodingScheme) {
+		// Self interface
+		assert_eq!(scheme.compress(1), 1);
+		assert_eq!(scheme.decompress(1), Some(1));
+
+		for number in range(0, scheme.max_number() + 1) {
+			let label = scheme.compress(number);
+			assert_eq!(scheme.decompress(label), Some(number));
+			assert_eq!(scheme.bits_used_for_label(label), Some(scheme.bits_used_for_number(number)));
+
+			// Bits beyond the director belong to the next hops
+			let bits = scheme.bits_used_for_number(number) as usize;
+			assert_eq!(scheme.decompress(label | 0b1011 << bits), Some(number));
+		}
+
+		// The form list announces exactly the labels the scheme writes
+		if let Some(form_list) = scheme.form_list() {
+			let data = form_list.serialize();
+			assert_eq!(FormList::deserialize(data.as_slice()).unwrap(), form_list);
+			assert_eq!(form_list.max_number(), scheme.max_number());
+
+			for number in range(0, scheme.max_number() + 1) {
+				let label = scheme.compress(number);
+				assert_eq!(form_list.compress(number), label);
+				assert_eq!(form_list.decompress(label), Some(number));
+				assert_eq!(form_list.bits_used_for_number(number), scheme.bits_used_for_number(number));
+				assert_eq!(form_list.bits_used_for_label(label), scheme.bits_used_for_label(label));
+			}
+		}
+	}
+
+	#[test]
+	fn test_built_in_schemes() {
+		for name in ["fixed4", "fixed8", "variable4x8", "variable3x5x8"].iter() {
+			check_scheme(&*from_name(*name).unwrap());
+		}
+		assert!(from_name("fixed8").unwrap().form_list().is_none());
+		assert!(from_name("fixed16").is_none());
+		assert!(from_name(DEFAULT_SCHEME).is_some());
+	}
+}
@@ -1,3 +1,36 @@
+use encoding_scheme::{EncodingScheme, FormList, Form};
+
+
+/// Three, five or eight bits per hop plus a prefix of one or two bits
+#[derive(Debug, Copy, Clone)]
+pub struct Variable3x5x8;
+
+impl EncodingScheme for Variable3x5x8 {
+	fn bits_used_for_label(&self, label: u64) -> Option<u8> {
+		Some(bits_used_for_label(label))
+	}
+
+	fn bits_used_for_number(&self, number: u32) -> u8 {
+		bits_used_for_number(number)
+	}
+
+	fn compress(&self, number: u32) -> u64 {
+		compress(number)
+	}
+
+	fn decompress(&self, label: u64) -> Option<u32> {
+		Some(decompress(label))
+	}
+
+	fn max_number(&self) -> u32 {
+		256
+	}
+
+	fn form_list(&self) -> Option<FormList> {
+		FormList::new(vec![Form::new(3, 1, 0b1), Form::new(5, 2, 0b10), Form::new(8, 2, 0b00)]).ok()
+	}
+}
+
 
 pub fn bits_used_for_label(label: u64) -> u8 {
 	if label & 0b1 != 0 {
@@ -1,3 +1,36 @@
+use encoding_scheme::{EncodingScheme, FormList, Form};
+
+
+/// Four or eight bits per hop plus a one bit prefix
+#[derive(Debug, Copy, Clone)]
+pub struct Variable4x8;
+
+impl EncodingScheme for Variable4x8 {
+	fn bits_used_for_label(&self, label: u64) -> Option<u8> {
+		Some(bits_used_for_label(label))
+	}
+
+	fn bits_used_for_number(&self, number: u32) -> u8 {
+		bits_used_for_number(number)
+	}
+
+	fn compress(&self, number: u32) -> u64 {
+		compress(number)
+	}
+
+	fn decompress(&self, label: u64) -> Option<u32> {
+		Some(decompress(label))
+	}
+
+	fn max_number(&self) -> u32 {
+		256
+	}
+
+	fn form_list(&self) -> Option<FormList> {
+		FormList::new(vec![Form::new(4, 1, 0b1), Form::new(8, 1, 0b0)]).ok()
+	}
+}
+
 
 pub fn bits_used_for_label(label: u64) -> u8 {
 	if label & 0b1 != 0 {
@@ -8,7 +41,7 @@ pub fn bits_used_for_label(label: u64) -> u8 {
 }
 
 pub fn bits_used_for_number(number: u32) -> u8 {
-	if number < 15 {
+	if number < 16 {
 		4 + 1
 	} else {
 		8 + 1
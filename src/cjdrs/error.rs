@@ -12,6 +12,13 @@ use CjdrsError::{
 	NoAddressForPrivateKey,
 	NoAddressForPublicKey,
 	InvalidBindAddress,
+	InvalidPeerAddress,
+	InvalidAddress,
+	InvalidBeaconMode,
+	UnknownEncodingScheme,
+	DuplicatePassword,
+	BeaconsNeedIpv4,
+	SendQueueFull,
 	JsonDecodingError,
 	JsonEncodingError,
 	MioError,
@@ -29,6 +36,13 @@ pub enum CjdrsError {
 	NoAddressForPrivateKey(PrivateKey),
 	NoAddressForPublicKey(PublicKey),
 	InvalidBindAddress(String),
+	InvalidPeerAddress(String),
+	InvalidAddress(String),
+	InvalidBeaconMode(u8),
+	UnknownEncodingScheme(String),
+	DuplicatePassword(String),
+	BeaconsNeedIpv4,
+	SendQueueFull,
 	JsonDecodingError(json::DecoderError),
 	JsonEncodingError(json::EncoderError),
 	MioError(mio::MioError),
@@ -46,6 +60,13 @@ impl error::Error for CjdrsError {
 			NoAddressForPrivateKey(..) => "Private key has no valid IP address",
 			NoAddressForPublicKey(..) => "Public key has no valid IP address",
 			InvalidBindAddress(..) => "Invalid bind address",
+			InvalidPeerAddress(..) => "Invalid peer address",
+			InvalidAddress(..) => "Invalid IPv6 address",
+			InvalidBeaconMode(..) => "Invalid beacon mode",
+			UnknownEncodingScheme(..) => "Unknown encoding scheme",
+			DuplicatePassword(..) => "Duplicate authorized password",
+			BeaconsNeedIpv4 => "Beacons need an IPv4 device",
+			SendQueueFull => "Send queue full",
 			JsonDecodingError(..) => "JSON decoding error",
 			JsonEncodingError(..) => "JSON encoding error",
 			MioError(..) => "Event handler error",
@@ -89,6 +110,27 @@ impl fmt::Display for CjdrsError {
 
 			InvalidBindAddress(ref s) =>
 				write!(f, "Bind address '{}' is invalid", s),
+
+			InvalidPeerAddress(ref s) =>
+				write!(f, "Peer address '{}' is invalid", s),
+
+			InvalidAddress(ref s) =>
+				write!(f, "Address '{}' is not a valid cjdns address", s),
+
+			InvalidBeaconMode(m) =>
+				write!(f, "Beacon mode must be 0 (off), 1 (accept) or 2 (send and accept), not {}", m),
+
+			UnknownEncodingScheme(ref s) =>
+				write!(f, "Encoding scheme must be fixed4, fixed8, variable4x8 or variable3x5x8, not '{}'", s),
+
+			DuplicatePassword(ref user) =>
+				write!(f, "User '{}' or its password is already in use", user),
+
+			BeaconsNeedIpv4 =>
+				write!(f, "Beacons are IPv4 broadcasts, only devices bound to an IPv4 address can handle them"),
+
+			SendQueueFull =>
+				write!(f, "Packet dropped because the device can't keep up"),
 			
 			JsonDecodingError(ref e) =>
 				write!(f, "{:?}", e),
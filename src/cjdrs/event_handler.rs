@@ -1,24 +1,75 @@
+use std::cmp::min;
+use std::slice::bytes::copy_memory;
 use std::time::duration::Duration;
 use mio;
-use crypto::{PasswordHash, SharedSecret};
+use mio::net::SockAddr;
+use crypto::PasswordHash;
 use debug::as_hex;
 use device::NetDevice;
-use packet;
+use dht::{DhtMessage, DhtQuery, DhtBody, NodeEntry, ROUTER_HANDLE};
+use encoding_scheme::EncodingScheme;
+use interface_controller::{InterfaceController, PeerState, PeerStats};
+use message::{MESSAGE_SIZE, DEFAULT_HEADROOM};
+use packet::{
+	self,
+	Control,
+	ControlType,
+	ControlPing,
+	ControlKeyPing,
+	ControlError,
+	Icmpv6,
+	SwitchHeader,
+	UnreachableCode,
+	CONTROL_HANDLE,
+	ICMPV6_NEXT_HEADER,
+	MAX_INVOKING_PACKET_LENGTH,
+	SWITCH_HEADER_LENGTH};
+use Address;
+use CjdrsResult;
+use Message;
+use PasswordStore;
+use PendingPacket;
+use PendingQueue;
 use PrivateIdentity;
+use PublicKey;
+use Route;
 use Router;
+use Session;
+use SessionManager;
+use SessionTimeouts;
+use switch_core::{SwitchResult, SELF_INTERFACE};
+use SwitchError;
+use util::{self, bencode, reverse_bits};
+use PROTOCOL_VERSION;
+use SESSION_HANDLE;
+
+
+/// Bytes of an undeliverable frame that are echoed back in an error message
+const MAX_ERROR_CAUSE_LENGTH: usize = 128;
+
+/// Peers sent in answer to a get peers query
+const MAX_PEERS_PER_REPLY: usize = 8;
+
+/// Consecutive event loop tokens every device gets, one for each socket
+pub const TOKENS_PER_DEVICE: usize = 2;
 
 
 #[derive(Debug)]
 pub enum Task<'a> {
-	HandleIncomingPacket(packet::CryptoAuth<'a>),
+	HandleIncomingPacket(SockAddr, &'a mut Message),
+	/// A node on the LAN announced that it accepts peers at the address with
+	/// the password
+	HandleBeacon(SockAddr, PublicKey, String),
 	HandleOutgoingPacket(packet::IPv6<'a>)
 }
 
 
 pub trait EventReceiver {
+	/// Registers the sockets of the device, the n-th one with `token + n`
 	fn register(&self, event_loop: &mut mio::EventLoop<usize, ()>, token: mio::Token)
 	            -> mio::MioResult<()>;
-	fn receive<'a>(&'a mut self, buffer: &'a mut [u8]) -> Option<Task>;
+	/// Reads from the n-th socket of the device
+	fn receive<'a>(&'a mut self, message: &'a mut Message, socket: usize) -> Option<Task<'a>>;
 }
 
 
@@ -26,72 +77,1136 @@ pub trait EventReceiver {
 pub struct EventHandler<'a> {
 	my_identity: PrivateIdentity,
 	devices: Vec<Box<NetDevice + 'a>>,
-	router: Router
+	router: Router,
+	password_store: PasswordStore,
+	session_timeouts: SessionTimeouts,
+	interface_controller: InterfaceController,
+	sessions: SessionManager,
+	pending: PendingQueue,
+	message: Message
 }
 
 impl<'a> EventHandler<'a> {
 	pub fn new(my_identity: PrivateIdentity,
 	           devices: Vec<Box<NetDevice + 'a>>,
-	           router: Router) -> EventHandler<'a> {
+	           router: Router,
+	           interface_controller: InterfaceController,
+	           password_store: PasswordStore,
+	           session_timeouts: SessionTimeouts) -> EventHandler<'a> {
 
 		EventHandler {
 			my_identity: my_identity,
 			devices: devices,
-			router: router
+			router: router,
+			password_store: password_store,
+			session_timeouts: session_timeouts,
+			interface_controller: interface_controller,
+			sessions: SessionManager::new(&my_identity),
+			pending: PendingQueue::new(),
+			message: Message::new(MESSAGE_SIZE, DEFAULT_HEADROOM)
 		}
 	}
 
 	pub fn register_handlers(&self, event_loop: &mut mio::EventLoop<usize, ()>)
 	                         -> mio::MioResult<()> {
 		for (i, device) in self.devices.iter().enumerate() {
-			try!(device.register(event_loop, mio::Token(i)));
+			try!(device.register(event_loop, mio::Token(i * TOKENS_PER_DEVICE)));
 		}
 		event_loop.timeout(1000, Duration::milliseconds(0)).unwrap();
 		Ok(())
 	}
+
+	pub fn peer_stats(&self) -> Vec<PeerStats> {
+		self.interface_controller.peer_stats()
+	}
+
+	/// Starts a CryptoAuth handshake with a peer by sending it a Hello. The
+	/// Hello is repeated with growing delays until the session is established.
+	pub fn connect(&mut self,
+	               device_idx: usize,
+	               address: SockAddr,
+	               her_public_key: &PublicKey,
+	               password_hash: Option<PasswordHash>,
+	               login: Option<&str>,
+	               label: Option<&str>) -> CjdrsResult<()> {
+		let mut session = Session::new(&self.my_identity, her_public_key, password_hash);
+		if let Some(login) = login {
+			session.set_login(login);
+		}
+
+		let now = util::timestamp();
+		if self.interface_controller.add_peer(device_idx, address.clone(), session, true, now).is_none() {
+			println!("No free switch interface for {:?}", address);
+			return Ok(());
+		}
+
+		let peer = match self.interface_controller.peer_mut(&address) {
+			Some(p) => p,
+			None => unreachable!()
+		};
+		if let Some(label) = label {
+			peer.set_label(label);
+		}
+
+		self.message.clear();
+		if let Err(..) = peer.session_mut().encrypt(&mut self.message) {
+			unreachable!();
+		}
+		peer.attempted(now, self.session_timeouts.handshake_timeout);
+
+		try!(self.devices[device_idx].send_message(self.message.as_slice(), Some(&address)));
+		peer.sent(self.message.len());
+		Ok(())
+	}
+
+	/// Peers with a node that announced itself in a beacon, unless it's us or
+	/// a node we already have a session with.
+	fn handle_beacon(&mut self,
+	                 device_idx: usize,
+	                 address: SockAddr,
+	                 public_key: PublicKey,
+	                 password: &str) {
+		if public_key == self.my_identity.public_key ||
+		   self.interface_controller.has_peer_with_key(&public_key) {
+			return;
+		}
+
+		println!("Received a beacon from {:?}, connecting", address);
+		let connected = self.connect(
+			device_idx,
+			address.clone(),
+			&public_key,
+			Some(PasswordHash::from_password(password)),
+			None,
+			Some("beacon"));
+
+		if let Err(e) = connected {
+			println!("Connecting to {:?} failed: {}", address, e);
+		}
+	}
+
+	/// Sends an empty packet to a peer that still waits for one to finish the
+	/// CryptoAuth handshake
+	fn continue_handshake(&mut self, address: &SockAddr) {
+		let peer = match self.interface_controller.peer_mut(address) {
+			Some(p) => p,
+			None => return
+		};
+		if !peer.session().is_reply_due() {
+			return;
+		}
+
+		self.message.clear();
+		if let Err(e) = peer.session_mut().encrypt(&mut self.message) {
+			println!("Couldn't encrypt keepalive for {:?}: {}", address, e);
+			return;
+		}
+
+		let device = &mut self.devices[peer.device_idx()];
+		match device.send_message(self.message.as_slice(), Some(address)) {
+			Ok(()) => peer.sent(self.message.len()),
+			Err(e) => println!("Sending keepalive to {:?} failed: {}", address, e)
+		}
+	}
+
+	/// Marks quiet peers unresponsive and resets sessions that have timed
+	/// out. Sessions we initiated start over with a new Hello once their retry
+	/// is due, the others are dropped until the peer reconnects.
+	fn check_peers(&mut self) {
+		let now = util::timestamp();
+		let mut dropped = vec![];
+
+		for (address, peer) in self.interface_controller.peers_mut() {
+			peer.check_responsive(now);
+			if !peer.session().is_timed_out(now, &self.session_timeouts) {
+				continue;
+			}
+
+			if !peer.is_outgoing() {
+				println!("Session with {:?} timed out", address);
+				dropped.push(address.clone());
+				continue;
+			}
+
+			if !peer.retry_due(now) {
+				continue;
+			}
+
+			println!("Session with {:?} timed out, sending a new Hello", address);
+			peer.reset();
+			self.message.clear();
+			if let Err(..) = peer.session_mut().encrypt(&mut self.message) {
+				unreachable!();
+			}
+			peer.attempted(now, self.session_timeouts.handshake_timeout);
+
+			let device = &mut self.devices[peer.device_idx()];
+			match device.send_message(self.message.as_slice(), Some(address)) {
+				Ok(()) => peer.sent(self.message.len()),
+				Err(e) => println!("Sending Hello to {:?} failed: {}", address, e)
+			}
+		}
+
+		for address in dropped.iter() {
+			self.interface_controller.remove_peer(address);
+		}
+	}
+
+	/// Puts a peer we just established a session with into the node store,
+	/// reachable through its switch interface, and starts a session with it if
+	/// packets are waiting for it
+	fn add_peer_node(&mut self, public_key: &PublicKey, interface: u32) {
+		let address = match Address::from_public_key(public_key) {
+			Some(a) => a,
+			None => {
+				println!("Peer {} has no valid address", public_key);
+				return;
+			}
+		};
+
+		let route = self.peer_route(interface);
+		let scheme = self.interface_controller.switch_core().interface(interface)
+			.and_then(|i| i.scheme.form_list());
+		println!("Peer {} is at {}", address, route);
+		self.router.add_peer(public_key, &address, route, scheme, util::timestamp());
+		self.resume_pending(&address);
+	}
+
+	/// Route to the peer behind a switch interface
+	fn peer_route(&self, interface: u32) -> Route {
+		let scheme = self.interface_controller.switch_core().scheme();
+		let bits = scheme.bits_used_for_number(interface) as usize;
+		Route::new(1u64 << bits | scheme.compress(interface))
+	}
+
+	/// Interface of the peer a route leads to directly, if any
+	fn peer_interface(&self, route: &Route) -> Option<u32> {
+		let interface = match self.interface_controller.switch_core().scheme().decompress(route.bits()) {
+			Some(i) => i,
+			None => return None
+		};
+		match self.interface_controller.peer_by_interface(interface) {
+			Some(..) if self.peer_route(interface) == *route => Some(interface),
+			_ => None
+		}
+	}
+
+	/// Established peers with labels after `after`, lowest label first
+	fn peers_after(&self, after: &Route) -> Vec<NodeEntry> {
+		let mut peers: Vec<NodeEntry> = self.interface_controller.peers()
+			.filter(|&(_, p)| p.state() == PeerState::Established)
+			.map(|(_, p)| {
+				let public_key = *p.session().her_public_key();
+				let version = Address::from_public_key(&public_key)
+					.and_then(|a| self.router.node_store().get(&a))
+					.map(|n| n.version)
+					.unwrap_or(0);
+				NodeEntry {
+					public_key: public_key,
+					label: self.peer_route(p.interface()),
+					version: version
+				}
+			})
+			.filter(|n| n.label.bits() > after.bits())
+			.collect();
+
+		peers.sort_by(|a, b| a.label.bits().cmp(&b.label.bits()));
+		peers.truncate(MAX_PEERS_PER_REPLY);
+		peers
+	}
+
+	/// Sends the next queries of the running searches. When a search finds
+	/// its target, a session is started for the packets waiting for it. When
+	/// it fails, they are answered with an error.
+	fn run_searches(&mut self) {
+		let now = util::timestamp();
+		for (route, query) in self.router.search_queries(now).into_iter() {
+			self.send_router_message(route, query);
+		}
+
+		for (target, route) in self.router.finish_searches(now).into_iter() {
+			match route {
+				Some(route) => {
+					println!("Found {} at {}", target, route);
+					self.resume_pending(&target);
+				},
+				None => {
+					println!("Search for {} failed", target);
+					let packets = self.pending.take(&target);
+					for packet in packets.iter() {
+						self.send_unreachable(packet, UnreachableCode::NoRoute);
+					}
+				}
+			}
+		}
+	}
+
+	/// Sends a packet from the tun device through the end-to-end session with
+	/// its destination. Until the session is established the packet is held,
+	/// and a session is started, or a search if there's no route to the
+	/// destination.
+	fn handle_outgoing_packet(&mut self, device_idx: usize, destination: Address, data: Vec<u8>) {
+		// Packets that are already waiting go first
+		if self.sessions.is_established(&destination) && !self.pending.contains(&destination) {
+			self.send_data(&destination, data.as_slice());
+			return;
+		}
+
+		let now = util::timestamp();
+		let packet = PendingPacket { device_idx: device_idx, data: data, queued: now };
+		if let Err(packet) = self.pending.push(&destination, packet) {
+			println!("    Too many packets waiting, dropping");
+			self.send_unreachable(&packet, UnreachableCode::AddressUnreachable);
+			return;
+		}
+
+		if self.sessions.is_established(&destination) {
+			self.send_pending(&destination);
+		} else if self.sessions.contains(&destination) {
+			println!("    Waiting for a session");
+		} else if self.router.get_route(&destination).is_some() {
+			println!("    Starting a session");
+			self.start_session(&destination);
+		} else if self.router.is_searching(&destination) {
+			println!("    No route, search running");
+		} else if self.router.search(&destination, now) {
+			println!("    No route, searching");
+			self.run_searches();
+		} else {
+			println!("    No route and no nodes to ask");
+			let packets = self.pending.take(&destination);
+			for packet in packets.iter() {
+				self.send_unreachable(packet, UnreachableCode::NoRoute);
+			}
+		}
+	}
+
+	/// Starts a session with a node packets are waiting for once there is a
+	/// route to it, or sends them if the session is established already
+	fn resume_pending(&mut self, address: &Address) {
+		if !self.pending.contains(address) {
+			return;
+		}
+
+		if self.sessions.is_established(address) {
+			self.send_pending(address);
+		} else if !self.sessions.contains(address) && self.router.get_route(address).is_some() {
+			self.start_session(address);
+		}
+	}
+
+	/// Starts an end-to-end session with a node in the node store by sending
+	/// it a Hello along its route
+	fn start_session(&mut self, address: &Address) {
+		let (public_key, route) = match self.router.node_store().get(address) {
+			Some(node) => (node.public_key, node.route),
+			None => return
+		};
+
+		if !self.sessions.start(address, &public_key, route) {
+			println!("Couldn't start a session with {}", address);
+			return;
+		}
+		println!("Starting a session with {} at {}", address, route);
+
+		self.message.clear();
+		self.send_session_packet(address);
+	}
+
+	/// Sends the packets that waited for the session with a node
+	fn send_pending(&mut self, address: &Address) {
+		let packets = self.pending.take(address);
+		for packet in packets.iter() {
+			self.send_data(address, packet.data.as_slice());
+		}
+	}
+
+	/// Sends a packet from the tun device through the session with a node
+	fn send_data(&mut self, address: &Address, data: &[u8]) {
+		self.message.clear();
+		if data.len() > self.message.receive_space().len() {
+			println!("Packet for {} too long", address);
+			return;
+		}
+		copy_memory(self.message.receive_space(), data);
+		self.message.set_len(data.len());
+
+		self.send_session_packet(address);
+	}
+
+	/// Wraps the message buffer in a packet of the session with a node and
+	/// sends it along the route of the session
+	fn send_session_packet(&mut self, address: &Address) {
+		let route = match self.sessions.encrypt(address, &mut self.message) {
+			Ok(r) => r,
+			Err(e) => {
+				println!("Couldn't encrypt packet for {}: {}", address, e);
+				return;
+			}
+		};
+
+		SwitchHeader::new(route.bits()).push_to(&mut self.message);
+		self.switch_frame(SELF_INTERFACE);
+	}
+
+	/// Answers a packet from the tun device that can't be delivered with an
+	/// ICMPv6 destination unreachable that seems to come from the destination
+	fn send_unreachable(&mut self, pending: &PendingPacket, code: UnreachableCode) {
+		let ipv6 = match packet::IPv6::from_buffer(pending.data.as_slice()) {
+			Ok(p) => p,
+			Err(..) => return
+		};
+
+		// Errors about errors would never end
+		if ipv6.get_next_header() == ICMPV6_NEXT_HEADER {
+			match Icmpv6::from_buffer(ipv6.get_data()) {
+				Ok(ref icmp) if !icmp.is_error() => (),
+				_ => return
+			}
+		}
+
+		let (source, destination) = match (ipv6.get_source(), ipv6.get_destination()) {
+			(Some(s), Some(d)) => (s, d),
+			_ => return
+		};
+
+		let len = min(pending.data.len(), MAX_INVOKING_PACKET_LENGTH);
+		self.message.clear();
+		copy_memory(self.message.receive_space(), &pending.data[..len]);
+		self.message.set_len(len);
+
+		Icmpv6::push_unreachable(&mut self.message, code, &destination, &source);
+		packet::IPv6::push_header(&mut self.message, ICMPV6_NEXT_HEADER, &destination, &source);
+		packet::Tun::push_header(&mut self.message);
+
+		if let Err(e) = self.devices[pending.device_idx].send_message(self.message.as_slice(), None) {
+			println!("Couldn't send ICMPv6 error to {}: {}", source, e);
+		}
+	}
+
+	/// Sends a router message along a route, announcing our encoding scheme
+	fn send_router_message(&mut self, route: Route, mut message: DhtMessage) {
+		message.scheme = self.interface_controller.switch_core().scheme().form_list();
+		message.return_form = match message.scheme {
+			Some(ref scheme) => scheme.form_num(route.bits()),
+			None => None
+		};
+		let data = bencode::encode(&message);
+
+		self.message.clear();
+		if data.len() > self.message.receive_space().len() {
+			println!("Router message for {} too long", route);
+			return;
+		}
+		copy_memory(self.message.receive_space(), data.as_slice());
+		self.message.set_len(data.len());
+
+		self.message.push_u32(ROUTER_HANDLE);
+		SwitchHeader::new(route.bits()).push_to(&mut self.message);
+		self.switch_frame(SELF_INTERFACE);
+	}
+
+	/// Passes a decrypted frame that arrived on interface `source` through the
+	/// switch and sends it on to the next hop.
+	fn switch_frame(&mut self, source: u32) {
+		if self.message.len() < SWITCH_HEADER_LENGTH {
+			println!("Dropping frame without a switch header");
+			return;
+		}
+		if self.message.len() < SWITCH_HEADER_LENGTH + 4 {
+			self.send_error(source, SwitchError::Undersize);
+			return;
+		}
+
+		let (cause, result) = {
+			let header = match SwitchHeader::from_buffer_mut(self.message.as_mut_slice()) {
+				Ok(h) => h,
+				Err(..) => unreachable!()
+			};
+			(*header, self.interface_controller.switch_core().switch(source, header))
+		};
+
+		let error = match result {
+			Ok(SELF_INTERFACE) => {
+				self.receive_frame();
+				return;
+			},
+			Ok(destination) => match self.forward(destination) {
+				Ok(()) => return,
+				Err(e) => {
+					// The frame is encrypted by now, only its header is left
+					// to be reported
+					self.message.clear();
+					cause.push_to(&mut self.message);
+					e
+				}
+			},
+			Err(e) => e
+		};
+		self.send_error(source, error);
+	}
+
+	/// Encrypts the frame in the message buffer for the peer behind an
+	/// interface and sends it out without switching.
+	fn forward(&mut self, interface: u32) -> SwitchResult<()> {
+		let peer = match self.interface_controller.peer_by_interface_mut(interface) {
+			Some(p) => p,
+			None => return Err(SwitchError::Undeliverable)
+		};
+
+		if let Err(e) = peer.session_mut().encrypt(&mut self.message) {
+			println!("Couldn't encrypt frame for {:?}: {}", peer.address(), e);
+			return Err(SwitchError::Undeliverable);
+		}
+
+		let device = &mut self.devices[peer.device_idx()];
+		if let Err(e) = device.send_message(self.message.as_slice(), Some(peer.address())) {
+			println!("Sending frame to {:?} failed: {}", peer.address(), e);
+			return Err(SwitchError::LinkLimitExceeded);
+		}
+		peer.sent(self.message.len());
+		Ok(())
+	}
+
+	/// Answers the frame in the message buffer with an ERROR control message
+	/// sent back along its reversed label, unless the sender suppressed errors.
+	fn send_error(&mut self, source: u32, error: SwitchError) {
+		let cause = match SwitchHeader::from_buffer_mut(self.message.as_mut_slice()) {
+			Ok(h) => *h,
+			Err(..) => return
+		};
+
+		if source == SELF_INTERFACE {
+			println!("Couldn't send frame to {}: {}", Route::new(cause.label()), error);
+			return;
+		}
+		if cause.suppress_errors() {
+			println!("Dropping frame from interface {}: {}", source, error);
+			return;
+		}
+		println!("Returning error for frame from interface {}: {}", source, error);
+
+		self.message.pop(SWITCH_HEADER_LENGTH);
+		let cause_len = min(self.message.len(), MAX_ERROR_CAUSE_LENGTH);
+		self.message.set_len(cause_len);
+
+		ControlError::push_header(&mut self.message, error, &cause);
+		self.message.push_u32(CONTROL_HANDLE);
+
+		// Errors about errors would never end
+		let mut header = SwitchHeader::new(reverse_bits(cause.label()));
+		header.set_suppress_errors(true);
+		header.push_to(&mut self.message);
+
+		if let Err(e) = self.forward(source) {
+			println!("Couldn't return error to interface {}: {}", source, e);
+		}
+	}
+
+	/// Handles a frame that was switched to this node
+	fn receive_frame(&mut self) {
+		if self.message.len() < SWITCH_HEADER_LENGTH + 4 {
+			println!("Dropping frame without a session handle");
+			return;
+		}
+
+		let header = match SwitchHeader::from_buffer_mut(self.message.as_mut_slice()) {
+			Ok(h) => *h,
+			Err(..) => unreachable!()
+		};
+		self.message.pop(SWITCH_HEADER_LENGTH);
+
+		match self.message.pop_u32() {
+			CONTROL_HANDLE => self.handle_control(&header),
+			ROUTER_HANDLE => self.handle_router_message(&header),
+			SESSION_HANDLE => self.handle_session_packet(&header),
+			_ => println!("Received frame: {}", as_hex(self.message.as_slice()))
+		}
+	}
+
+	/// Answers router queries of other cjdrs nodes and passes replies on to
+	/// the searches. Pings get an empty reply, which carries our version and
+	/// encoding scheme. cjdns nodes don't send these, see `dht`.
+	fn handle_router_message(&mut self, header: &SwitchHeader) {
+		let return_label = Route::new(reverse_bits(header.label()));
+
+		let message = match bencode::decode::<DhtMessage>(self.message.as_slice()) {
+			Ok(m) => m,
+			Err(e) => {
+				println!("Dropping invalid router message from {}: {}", return_label, e);
+				return;
+			}
+		};
+
+		// Peers announce their encoding scheme in every message
+		if let Some(ref scheme) = message.scheme {
+			if let Some(interface) = self.peer_interface(&return_label) {
+				self.interface_controller.set_peer_scheme(interface, Box::new(scheme.clone()));
+			}
+		}
+
+		match message.body {
+			DhtBody::Query(ref query) => {
+				let nodes = match *query {
+					DhtQuery::Ping => vec![],
+					DhtQuery::FindNode(ref target) => self.router.find_node(target),
+					DhtQuery::GetPeers(ref after) => self.peers_after(after)
+				};
+				let reply = message.reply(PROTOCOL_VERSION, nodes);
+				self.send_router_message(return_label, reply);
+			},
+			DhtBody::Reply(..) => {
+				self.router.handle_reply(&message, &return_label, util::timestamp());
+				self.run_searches();
+			}
+		}
+	}
+
+	/// Unwraps a packet of an end-to-end session and writes the data it carries
+	/// to the tun device. Once the session is established, the packets that
+	/// waited for it are sent.
+	fn handle_session_packet(&mut self, header: &SwitchHeader) {
+		let return_label = Route::new(reverse_bits(header.label()));
+
+		let (from, established) = match self.sessions.receive(return_label, &mut self.message) {
+			Ok(r) => r,
+			Err(e) => {
+				println!("Dropping session packet from {}: {}", return_label, e);
+				return;
+			}
+		};
+
+		if self.message.len() > 0 {
+			self.write_to_tun(&from);
+		}
+		if established {
+			println!("Session with {} established", from);
+			self.send_pending(&from);
+		}
+		if self.sessions.is_reply_due(&from) {
+			self.message.clear();
+			self.send_session_packet(&from);
+		}
+	}
+
+	/// Writes the IPv6 packet in the message buffer to the tun device if the
+	/// node the session is with sent it to us
+	fn write_to_tun(&mut self, from: &Address) {
+		let addressed = match packet::IPv6::from_buffer(self.message.as_slice()) {
+			Ok(ipv6) => ipv6.get_source() == Some(*from) &&
+			            ipv6.get_destination() == Some(self.my_identity.address),
+			Err(..) => false
+		};
+		if !addressed {
+			println!("Dropping packet from {} that isn't an IPv6 packet from it to us", from);
+			return;
+		}
+
+		let tun_idx = match self.devices.iter().position(|d| d.is_tun()) {
+			Some(i) => i,
+			None => {
+				println!("Dropping packet from {}, there is no tun device", from);
+				return;
+			}
+		};
+
+		packet::Tun::push_header(&mut self.message);
+		if let Err(e) = self.devices[tun_idx].send_message(self.message.as_slice(), None) {
+			println!("Couldn't write packet from {} to the tun device: {}", from, e);
+		}
+	}
+
+	/// Answers pings and key pings and reports the other control messages
+	fn handle_control(&mut self, header: &SwitchHeader) {
+		let return_label = Route::new(reverse_bits(header.label()));
+
+		let reply = match Control::from_buffer(self.message.as_slice()) {
+			Ok(Control::Ping(ping)) => Some((ControlType::Pong, ping.data.to_vec())),
+			Ok(Control::KeyPing(ping)) => Some((ControlType::KeyPong, ping.data.to_vec())),
+			Ok(Control::Pong(pong)) => {
+				println!("Pong from {}, version {}", return_label, pong.version());
+				None
+			},
+			Ok(Control::KeyPong(pong)) => {
+				println!("Key pong from {}, version {}, key {}",
+				         return_label, pong.version(), pong.public_key());
+				None
+			},
+			Ok(Control::Error(error)) => {
+				match error.error_type() {
+					Some(e) => println!("Error from {} caused by frame to {}: {}",
+					                    return_label, Route::new(error.cause().label()), e),
+					None => println!("Unknown error from {}", return_label)
+				}
+				None
+			},
+			Err(e) => {
+				println!("Dropping invalid control message: {}", e);
+				None
+			}
+		};
+
+		if let Some((reply_type, data)) = reply {
+			self.message.clear();
+			copy_memory(self.message.receive_space(), data.as_slice());
+			self.message.set_len(data.len());
+
+			match reply_type {
+				ControlType::Pong =>
+					ControlPing::push_header(&mut self.message, true, PROTOCOL_VERSION),
+				ControlType::KeyPong =>
+					ControlKeyPing::push_header(
+						&mut self.message, true, PROTOCOL_VERSION, &self.my_identity.public_key),
+				_ => unreachable!()
+			}
+			self.message.push_u32(CONTROL_HANDLE);
+			SwitchHeader::new(return_label.bits()).push_to(&mut self.message);
+			self.switch_frame(SELF_INTERFACE);
+		}
+	}
+
+	/// Reads from the n-th socket of a device and handles what arrived
+	fn handle_event(&mut self, device_idx: usize, socket: usize) {
+		let mut beacon = None;
+		let mut new_peer = None;
+		let mut decrypted_from = None;
+		let mut outgoing = None;
+		let received_on = {
+			let maybe_task = self.devices[device_idx].receive(&mut self.message, socket);
+
+			match maybe_task {
+				Some(Task::HandleIncomingPacket(from, message)) => {
+					println!("Handling incoming packet from {:?}", from);
+
+					let now = util::timestamp();
+					let len = message.len();
+
+					let decrypted = if self.interface_controller.peer(&from).is_none() {
+						let mut session = match packet::CryptoAuth::from_buffer(message.as_slice()) {
+							Ok(packet::CryptoAuth::Handshake(ref handshake)) => {
+								let password = match self.password_store.lookup(handshake.challenge()) {
+									Some(p) => p,
+									None => {
+										println!("Rejecting handshake with an unknown password");
+										return;
+									}
+								};
+								if !password.allows(&handshake.public_key()) {
+									println!("Rejecting handshake, user '{}' is not allowed from {}",
+									         password.user(), handshake.public_key());
+									return;
+								}
+
+								let mut session = Session::new(
+									&self.my_identity,
+									&handshake.public_key(),
+									Some(*password.password_hash()));
+								session.set_user(password.user());
+								session
+							},
+							_ => {
+								println!("Data packet from an unknown endpoint");
+								return;
+							}
+						};
+
+						// Only a Hello that decrypts gets a switch interface
+						match session.receive(message) {
+							Ok(()) => {
+								println!("Peer {:?} authenticated as '{}'", from, session.user().unwrap_or(""));
+								if self.interface_controller.add_peer(device_idx, from.clone(), session, false, now).is_none() {
+									println!("No free switch interface for {:?}", from);
+									return;
+								}
+								Ok(false)
+							},
+							Err(e) => Err(e)
+						}
+					} else {
+						let peer = match self.interface_controller.peer_mut(&from) {
+							Some(p) => p,
+							None => unreachable!()
+						};
+						let was_established = peer.state() == PeerState::Established;
+						peer.session_mut().receive(message).map(|()| was_established)
+					};
+
+					match decrypted {
+						Ok(was_established) => {
+							let peer = match self.interface_controller.peer_mut(&from) {
+								Some(p) => p,
+								None => unreachable!()
+							};
+							peer.received(len, now);
+							if !was_established && peer.state() == PeerState::Established {
+								new_peer = Some((*peer.session().her_public_key(), peer.interface()));
+							}
+							decrypted_from = Some(from.clone());
+							if message.len() == 0 { None } else { Some(peer.interface()) }
+						},
+						Err(e) => {
+							println!("Couldn't decrypt the message: {}", e);
+							None
+						}
+					}
+				},
+				Some(Task::HandleBeacon(address, public_key, password)) => {
+					beacon = Some((address, public_key, password));
+					None
+				},
+				Some(Task::HandleOutgoingPacket(ipv6_packet)) => {
+					let destination = ipv6_packet.get_destination().unwrap();
+					println!("Handling outgoing packet to {}", destination);
+					outgoing = Some((destination, ipv6_packet.slice.to_vec()));
+					None
+				},
+				None => None
+			}
+		};
+
+		if let Some((public_key, interface)) = new_peer {
+			self.add_peer_node(&public_key, interface);
+		}
+		if let Some(source) = received_on {
+			self.switch_frame(source);
+		}
+		if let Some(address) = decrypted_from {
+			self.continue_handshake(&address);
+		}
+		if let Some((destination, data)) = outgoing {
+			self.handle_outgoing_packet(device_idx, destination, data);
+		}
+		if let Some((address, public_key, password)) = beacon {
+			self.handle_beacon(device_idx, address, public_key, password.as_slice());
+		}
+	}
 }
 
 impl<'a> mio::Handler<usize, ()> for EventHandler<'a> {
 	fn timeout(&mut self, event_loop: &mut mio::EventLoop<usize, ()>, timeout: usize) {
 		assert_eq!(timeout, 1000);
-	
+
+		self.check_peers();
+		self.run_searches();
+
+		let now = util::timestamp();
+		for address in self.sessions.expire(now, &self.session_timeouts).iter() {
+			println!("Session with {} timed out", address);
+		}
+
+		let expired = self.pending.expire(now);
+		for packet in expired.iter() {
+			self.send_unreachable(packet, UnreachableCode::AddressUnreachable);
+		}
+		// Sessions that timed out during the handshake start over
+		for destination in self.pending.destinations().iter() {
+			self.resume_pending(destination);
+		}
+
+		for device in self.devices.iter_mut() {
+			device.timer(now);
+		}
 		event_loop.timeout(1000, Duration::milliseconds(1000)).unwrap();
 	}
 	
+	fn writable(&mut self, _event_loop: &mut mio::EventLoop<usize, ()>, token: mio::Token) {
+		self.devices[token.as_usize() / TOKENS_PER_DEVICE].flush();
+	}
+
 	fn readable(&mut self, _event_loop: &mut mio::EventLoop<usize, ()>,
 	            token: mio::Token, _hint: mio::event::ReadHint) {
+		self.handle_event(token.as_usize() / TOKENS_PER_DEVICE, token.as_usize() % TOKENS_PER_DEVICE);
+	}
+}
 
-		let mut buffer = [0u8; 1500];
-
-		let device_idx = token.as_usize();
-		let maybe_task = self.devices[device_idx].receive(&mut buffer);
-
-		if let Some(task) = maybe_task {
-			match task {
-				Task::HandleIncomingPacket(mut ca_packet) => {
-					println!("Handling incoming packet");
-
-					let password_hash = PasswordHash::from_password("aaa");
-					
-					let shared_secret = SharedSecret::with_password(
-						&self.my_identity.private_key,
-						&ca_packet.public_key(),
-						&password_hash);
-					
-					match ca_packet.decrypt(&shared_secret) {
-						Some(message) => println!("Decrypted message: {}", as_hex(message.as_slice())),
-						None => println!("Couldn't decrypt the message!")
-					}
 
-				},
-				Task::HandleOutgoingPacket(ipv6_packet) => {
-					let destination = ipv6_packet.get_destination().unwrap();
-					println!("Handling outgoing packet to {}", destination);
-					
-					let route = self.router.get_route(&destination);
-					println!("    Route: {}", route);
-				}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::fmt;
+	use std::rc::Rc;
+	use std::slice::bytes::copy_memory;
+	use mio;
+	use mio::net::SockAddr;
+	use super::{EventHandler, EventReceiver, Task};
+	use crypto::PasswordHash;
+	use dht::{DhtMessage, DhtQuery};
+	use encoding_scheme::{EncodingScheme, FormList, Fixed4, Variable3x5x8};
+	use CjdrsResult;
+	use InterfaceController;
+	use Message;
+	use NetDevice;
+	use packet;
+	use PasswordStore;
+	use PeerState;
+	use PrivateIdentity;
+	use Router;
+	use SessionTimeouts;
+	use PROTOCOL_VERSION;
+
+	type Packets = Rc<RefCell<Vec<Vec<u8>>>>;
+
+	/// Device that hands the packets sent to it to the other end of the pipe
+	struct Pipe {
+		other_end: SockAddr,
+		incoming: Packets,
+		outgoing: Packets
+	}
+
+	impl fmt::Debug for Pipe {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "Pipe to {:?}", self.other_end)
+		}
+	}
+
+	impl NetDevice for Pipe {
+		fn send_message(&mut self, message: &[u8], _to: Option<&SockAddr>) -> CjdrsResult<()> {
+			self.outgoing.borrow_mut().push(message.to_vec());
+			Ok(())
+		}
+
+		fn receive_message<'a>(&'a mut self, message: &'a mut Message) -> Option<Task<'a>> {
+			let mut incoming = self.incoming.borrow_mut();
+			if incoming.is_empty() {
+				return None;
+			}
+
+			let packet = incoming.remove(0);
+			message.clear();
+			copy_memory(message.receive_space(), packet.as_slice());
+			message.set_len(packet.len());
+			Some(Task::HandleIncomingPacket(self.other_end.clone(), message))
+		}
+	}
+
+	impl EventReceiver for Pipe {
+		fn register(&self, _event_loop: &mut mio::EventLoop<usize, ()>, _token: mio::Token)
+		            -> mio::MioResult<()> {
+			Ok(())
+		}
+
+		fn receive<'a>(&'a mut self, message: &'a mut Message, _socket: usize) -> Option<Task<'a>> {
+			self.receive_message(message)
+		}
+	}
+
+	/// Tun device that keeps the packets written to it
+	struct TunPipe {
+		written: Packets
+	}
+
+	impl fmt::Debug for TunPipe {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "Tun pipe")
+		}
+	}
+
+	impl NetDevice for TunPipe {
+		fn send_message(&mut self, message: &[u8], _to: Option<&SockAddr>) -> CjdrsResult<()> {
+			self.written.borrow_mut().push(message.to_vec());
+			Ok(())
+		}
+
+		fn receive_message<'a>(&'a mut self, _message: &'a mut Message) -> Option<Task<'a>> {
+			None
+		}
+
+		fn is_tun(&self) -> bool {
+			true
+		}
+	}
+
+	impl EventReceiver for TunPipe {
+		fn register(&self, _event_loop: &mut mio::EventLoop<usize, ()>, _token: mio::Token)
+		            -> mio::MioResult<()> {
+			Ok(())
+		}
+
+		fn receive<'a>(&'a mut self, message: &'a mut Message, _socket: usize) -> Option<Task<'a>> {
+			self.receive_message(message)
+		}
+	}
+
+	fn handler<'a>(identity: &PrivateIdentity,
+	               pipe: Pipe,
+	               scheme: Box<EncodingScheme>,
+	               password_store: PasswordStore) -> EventHandler<'a> {
+		EventHandler::new(
+			*identity,
+			vec![Box::new(pipe) as Box<NetDevice>],
+			Router::new(&identity.address),
+			InterfaceController::with_scheme(scheme),
+			password_store,
+			SessionTimeouts { reset_after_inactivity: 60, handshake_timeout: 10 })
+	}
+
+	/// Alice and Bob linked by a pipe, each with the packets sent to them.
+	/// Bob accepts `password` from users named `user` and his switch uses
+	/// `bob_scheme`.
+	fn alice_and_bob(user: &str, password: &str, bob_scheme: Box<EncodingScheme>)
+	                 -> (EventHandler<'static>, Packets, EventHandler<'static>, Packets) {
+		let to_alice: Packets = Rc::new(RefCell::new(vec![]));
+		let to_bob: Packets = Rc::new(RefCell::new(vec![]));
+
+		let alice = handler(
+			&PrivateIdentity::generate(),
+			Pipe { other_end: bob_address(), incoming: to_alice.clone(), outgoing: to_bob.clone() },
+			Box::new(Variable3x5x8),
+			PasswordStore::new());
+		let mut password_store = PasswordStore::new();
+		password_store.add(user, password, None).unwrap();
+		let bob = handler(
+			&PrivateIdentity::generate(),
+			Pipe { other_end: alice_address(), incoming: to_bob.clone(), outgoing: to_alice.clone() },
+			bob_scheme,
+			password_store);
+
+		(alice, to_alice, bob, to_bob)
+	}
+
+	fn alice_address() -> SockAddr {
+		SockAddr::parse("127.0.0.1:1000").unwrap()
+	}
+
+	fn bob_address() -> SockAddr {
+		SockAddr::parse("127.0.0.1:2000").unwrap()
+	}
+
+	/// Delivers packets both ways until nothing is sent anymore
+	fn exchange(alice: &mut EventHandler, to_alice: &Packets, bob: &mut EventHandler, to_bob: &Packets) {
+		while !to_alice.borrow().is_empty() || !to_bob.borrow().is_empty() {
+			while !to_bob.borrow().is_empty() {
+				bob.handle_event(0, 0);
+			}
+			while !to_alice.borrow().is_empty() {
+				alice.handle_event(0, 0);
 			}
 		}
 	}
+
+	/// Checks that both have an established session with each other
+	fn assert_established(alice: &EventHandler, bob: &EventHandler) {
+		assert_peered(alice, bob);
+		assert_peered(bob, alice);
+	}
+
+	fn assert_peered(node: &EventHandler, other: &EventHandler) {
+		let peers = node.peer_stats();
+		assert_eq!(peers.len(), 1);
+		assert_eq!(peers[0].state, PeerState::Established);
+		assert_eq!(peers[0].public_key, other.my_identity.public_key);
+		assert!(node.router.node_store().get(&other.my_identity.address).is_some());
+	}
+
+	/// Gives a node a tun device as its second device and returns the packets
+	/// written to it
+	fn add_tun(node: &mut EventHandler<'static>) -> Packets {
+		let written: Packets = Rc::new(RefCell::new(vec![]));
+		node.devices.push(Box::new(TunPipe { written: written.clone() }) as Box<NetDevice>);
+		written
+	}
+
+	/// IPv6 packet between two nodes
+	fn ipv6_packet(from: &EventHandler, to: &EventHandler, payload: &[u8]) -> Vec<u8> {
+		let mut message = Message::from_slice(payload);
+		packet::IPv6::push_header(&mut message, 17, &from.my_identity.address, &to.my_identity.address);
+		message.as_slice().to_vec()
+	}
+
+	/// Scheme of the peer behind an interface as the node knows it
+	fn peer_scheme(node: &EventHandler, interface: u32) -> Option<FormList> {
+		node.interface_controller.switch_core().interface(interface).unwrap().scheme.form_list()
+	}
+
+	#[test]
+	fn test_handshake() {
+		let (mut alice, to_alice, mut bob, to_bob) = alice_and_bob("alice", "secret", Box::new(Variable3x5x8));
+
+		let bob_key = bob.my_identity.public_key;
+		alice.connect(0, bob_address(), &bob_key, Some(PasswordHash::from_password("secret")), None, None)
+			.unwrap();
+		assert_eq!(to_bob.borrow().len(), 1);
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+
+		assert_established(&alice, &bob);
+		assert_eq!(bob.peer_stats()[0].user, Some("alice".to_string()));
+	}
+
+	#[test]
+	fn test_beacon() {
+		let (mut alice, to_alice, mut bob, to_bob) = alice_and_bob("beacon", "beacon password", Box::new(Variable3x5x8));
+
+		// Our own beacons and beacons of peers are ignored
+		let alice_key = alice.my_identity.public_key;
+		alice.handle_beacon(0, bob_address(), alice_key, "beacon password");
+		assert!(to_bob.borrow().is_empty());
+
+		let bob_key = bob.my_identity.public_key;
+		alice.handle_beacon(0, bob_address(), bob_key, "beacon password");
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+		assert_established(&alice, &bob);
+		assert_eq!(alice.peer_stats()[0].label, Some("beacon".to_string()));
+
+		alice.handle_beacon(0, bob_address(), bob_key, "beacon password");
+		assert!(to_bob.borrow().is_empty());
+	}
+
+	#[test]
+	fn test_ping() {
+		let (mut alice, to_alice, mut bob, to_bob) = alice_and_bob("alice", "secret", Box::new(Fixed4));
+		let bob_key = bob.my_identity.public_key;
+		alice.connect(0, bob_address(), &bob_key, Some(PasswordHash::from_password("secret")), None, None)
+			.unwrap();
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+
+		// Until Bob tells us, his switch is assumed to use the cjdns scheme
+		let interface = alice.peer_stats()[0].interface;
+		assert_eq!(peer_scheme(&alice, interface), Variable3x5x8.form_list());
+
+		let route = alice.peer_route(interface);
+		alice.send_router_message(route, DhtMessage::query(vec![0, 0, 0, 1], PROTOCOL_VERSION, DhtQuery::Ping));
+		assert_eq!(to_bob.borrow().len(), 1);
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+		assert_eq!(peer_scheme(&alice, interface), Fixed4.form_list());
+	}
+
+	#[test]
+	fn test_outgoing_packet() {
+		let (mut alice, to_alice, mut bob, to_bob) = alice_and_bob("alice", "secret", Box::new(Variable3x5x8));
+		let alice_tun = add_tun(&mut alice);
+		let bob_tun = add_tun(&mut bob);
+		let bob_key = bob.my_identity.public_key;
+		alice.connect(0, bob_address(), &bob_key, Some(PasswordHash::from_password("secret")), None, None)
+			.unwrap();
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+
+		// The packet waits until the session with Bob is established
+		let bob_ip = bob.my_identity.address;
+		let first = ipv6_packet(&alice, &bob, b"first");
+		alice.handle_outgoing_packet(1, bob_ip, first.clone());
+		assert_eq!(alice.pending.len(), 1);
+		assert!(alice.sessions.contains(&bob_ip));
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+
+		assert_eq!(alice.pending.len(), 0);
+		assert!(alice.sessions.is_established(&bob_ip));
+		assert_eq!(bob_tun.borrow().len(), 1);
+		assert_eq!(&bob_tun.borrow()[0][4..], first.as_slice());
+
+		// Then packets go out right away
+		let second = ipv6_packet(&alice, &bob, b"second");
+		alice.handle_outgoing_packet(1, bob_ip, second.clone());
+		assert_eq!(alice.pending.len(), 0);
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+		assert_eq!(bob_tun.borrow().len(), 2);
+		assert_eq!(&bob_tun.borrow()[1][4..], second.as_slice());
+
+		// Packets that don't come from the sender of the session are dropped
+		let forged = ipv6_packet(&bob, &bob, b"forged");
+		alice.handle_outgoing_packet(1, bob_ip, forged);
+		exchange(&mut alice, &to_alice, &mut bob, &to_bob);
+		assert_eq!(bob_tun.borrow().len(), 2);
+		assert!(alice_tun.borrow().is_empty());
+	}
 }
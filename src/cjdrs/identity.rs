@@ -16,6 +16,12 @@ pub const PUB_KEY_SIZE: usize = 32;
 pub struct PrivateKey([u8; PRIV_KEY_SIZE]);
 
 impl PrivateKey {
+	pub fn generate() -> PrivateKey {
+		let mut buffer = [0u8; PRIV_KEY_SIZE];
+		crypto::randombytes_into(&mut buffer);
+		PrivateKey(buffer)
+	}
+
 	pub fn from_string(string: &str) -> CjdrsResult<PrivateKey> {
 		match string.from_hex() {
 			Ok(bytes) => {
@@ -59,6 +65,11 @@ impl PublicKey {
 		PublicKey(*buffer)
 	}
 
+	pub fn from_private_key(private_key: &PrivateKey) -> PublicKey {
+		let input = curve25519::Scalar(*private_key.as_slice());
+		PublicKey(curve25519::scalarmult_base(&input).0)
+	}
+
 	pub fn from_slice(slice: &[u8]) -> PublicKey {
 		assert_eq!(slice.len(), PUB_KEY_SIZE);
 
@@ -116,11 +127,7 @@ pub struct PrivateIdentity {
 impl PrivateIdentity {
 	pub fn generate() -> PrivateIdentity {
 		loop {
-			let private_key = {
-				let mut private_key_buf = [0; PRIV_KEY_SIZE];
-				crypto::randombytes_into(&mut private_key_buf);
-				PrivateKey(private_key_buf)
-			};
+			let private_key = PrivateKey::generate();
 
 			if let Some(identity) = PrivateIdentity::from_private_key(&private_key) {
 				return identity;
@@ -129,9 +136,7 @@ impl PrivateIdentity {
 	}
 
 	pub fn from_private_key(private_key: &PrivateKey) -> Option<PrivateIdentity> {
-		let input = curve25519::Scalar(*private_key.as_slice());
-		let public_key_buf = curve25519::scalarmult_base(&input).0;
-		let public_key = PublicKey::from_buffer(&public_key_buf);
+		let public_key = PublicKey::from_private_key(private_key);
 
 		match Address::from_public_key(&public_key) {
 			Some(address) => Some(PrivateIdentity {
@@ -0,0 +1,420 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::num::Int;
+use mio::net::SockAddr;
+use encoding_scheme::EncodingScheme;
+use PublicKey;
+use Session;
+use SwitchCore;
+
+
+/// Seconds without any message before an established peer is unresponsive
+pub const UNRESPONSIVE_AFTER: u64 = 20;
+
+/// Longest wait in seconds between two connection attempts to a peer
+pub const MAX_RETRY_DELAY: u64 = 300;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PeerState {
+	/// Nothing valid has been received from the peer yet
+	Unauthenticated,
+	/// The peer answered but the CryptoAuth session isn't established yet
+	Handshake,
+	Established,
+	/// The session is established but the peer went quiet
+	Unresponsive
+}
+
+
+
+/// Snapshot of a peer for reporting
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+	pub address: SockAddr,
+	pub public_key: PublicKey,
+	pub user: Option<String>,
+	pub label: Option<String>,
+	pub interface: u32,
+	pub state: PeerState,
+	pub bytes_in: u64,
+	pub bytes_out: u64,
+	pub last_message: u64
+}
+
+
+
+#[derive(Debug)]
+pub struct Peer {
+	device_idx: usize,
+	address: SockAddr,
+	interface: u32,
+	outgoing: bool,
+	session: Session,
+	label: Option<String>,
+
+	state: PeerState,
+	bytes_in: u64,
+	bytes_out: u64,
+	last_message: u64,
+
+	retry_delay: u64,
+	next_attempt: u64
+}
+
+impl Peer {
+	pub fn device_idx(&self) -> usize {
+		self.device_idx
+	}
+
+	pub fn address(&self) -> &SockAddr {
+		&self.address
+	}
+
+	/// Switch interface number the peer is reachable through
+	pub fn interface(&self) -> u32 {
+		self.interface
+	}
+
+	/// Whether we initiated the connection
+	pub fn is_outgoing(&self) -> bool {
+		self.outgoing
+	}
+
+	/// Name given to the peer in the configuration
+	pub fn label(&self) -> Option<&str> {
+		self.label.as_ref().map(|l| l.as_slice())
+	}
+
+	pub fn set_label(&mut self, label: &str) {
+		self.label = Some(label.to_string());
+	}
+
+	pub fn state(&self) -> PeerState {
+		self.state
+	}
+
+	pub fn session(&self) -> &Session {
+		&self.session
+	}
+
+	pub fn session_mut(&mut self) -> &mut Session {
+		&mut self.session
+	}
+
+	pub fn last_message(&self) -> u64 {
+		self.last_message
+	}
+
+	/// Records a message that was successfully decrypted
+	pub fn received(&mut self, len: usize, now: u64) {
+		self.bytes_in += len as u64;
+		self.last_message = now;
+		self.state = if self.session.is_established() {
+			self.retry_delay = 0;
+			PeerState::Established
+		} else {
+			PeerState::Handshake
+		};
+	}
+
+	pub fn sent(&mut self, len: usize) {
+		self.bytes_out += len as u64;
+	}
+
+	/// Starts the handshake over after the session was reset
+	pub fn reset(&mut self) {
+		self.session.reset();
+		self.state = PeerState::Unauthenticated;
+	}
+
+	/// Whether the next connection attempt to an outgoing peer is due
+	pub fn retry_due(&self, now: u64) -> bool {
+		now >= self.next_attempt
+	}
+
+	/// Records a connection attempt. The wait before the next one starts at
+	/// `initial_delay` and doubles with every attempt that doesn't lead to an
+	/// established session.
+	pub fn attempted(&mut self, now: u64, initial_delay: u64) {
+		self.retry_delay = if self.retry_delay == 0 {
+			initial_delay
+		} else {
+			min(self.retry_delay * 2, MAX_RETRY_DELAY)
+		};
+		self.next_attempt = now + self.retry_delay;
+	}
+
+	/// Marks an established peer unresponsive if it has been quiet for too long
+	pub fn check_responsive(&mut self, now: u64) {
+		if self.state == PeerState::Established &&
+		   now.saturating_sub(self.last_message) >= UNRESPONSIVE_AFTER {
+			self.state = PeerState::Unresponsive;
+		}
+	}
+
+	pub fn stats(&self) -> PeerStats {
+		PeerStats {
+			address: self.address.clone(),
+			public_key: *self.session.her_public_key(),
+			user: self.session.user().map(|u| u.to_string()),
+			label: self.label.clone(),
+			interface: self.interface,
+			state: self.state,
+			bytes_in: self.bytes_in,
+			bytes_out: self.bytes_out,
+			last_message: self.last_message
+		}
+	}
+}
+
+
+
+/// Keeps one entry per peer endpoint and assigns each of them an interface
+/// of the switch.
+#[derive(Debug)]
+pub struct InterfaceController {
+	switch_core: SwitchCore,
+	peers: HashMap<SockAddr, Peer>
+}
+
+impl InterfaceController {
+	pub fn new() -> InterfaceController {
+		InterfaceController {
+			switch_core: SwitchCore::new(),
+			peers: HashMap::new()
+		}
+	}
+
+	/// Controller whose switch writes its directors in the given encoding scheme
+	pub fn with_scheme(scheme: Box<EncodingScheme>) -> InterfaceController {
+		InterfaceController {
+			switch_core: SwitchCore::with_scheme(scheme),
+			peers: HashMap::new()
+		}
+	}
+
+	pub fn switch_core(&self) -> &SwitchCore {
+		&self.switch_core
+	}
+
+	/// Adds a peer and returns the interface number assigned to it, or None
+	/// if the switch has no free interfaces left.
+	pub fn add_peer(&mut self,
+	                device_idx: usize,
+	                address: SockAddr,
+	                session: Session,
+	                outgoing: bool,
+	                now: u64) -> Option<u32> {
+		self.remove_peer(&address);
+
+		let interface = match self.switch_core.add_interface(device_idx, address.clone()) {
+			Some(i) => i,
+			None => return None
+		};
+
+		self.peers.insert(address.clone(), Peer {
+			device_idx: device_idx,
+			address: address,
+			interface: interface,
+			outgoing: outgoing,
+			session: session,
+			label: None,
+			state: PeerState::Unauthenticated,
+			bytes_in: 0,
+			bytes_out: 0,
+			last_message: now,
+			retry_delay: 0,
+			next_attempt: now
+		});
+		Some(interface)
+	}
+
+	pub fn remove_peer(&mut self, address: &SockAddr) {
+		if let Some(peer) = self.peers.remove(address) {
+			self.switch_core.remove_interface(peer.interface);
+		}
+	}
+
+	/// Records the encoding scheme the peer behind an interface announced
+	pub fn set_peer_scheme(&mut self, interface: u32, scheme: Box<EncodingScheme>) {
+		self.switch_core.set_interface_scheme(interface, scheme);
+	}
+
+	pub fn peer(&self, address: &SockAddr) -> Option<&Peer> {
+		self.peers.get(address)
+	}
+
+	/// Whether any peer uses the public key, whatever its endpoint
+	pub fn has_peer_with_key(&self, public_key: &PublicKey) -> bool {
+		self.peers.values().any(|p| p.session.her_public_key() == public_key)
+	}
+
+	pub fn peer_mut(&mut self, address: &SockAddr) -> Option<&mut Peer> {
+		self.peers.get_mut(address)
+	}
+
+	pub fn peer_by_interface(&self, interface: u32) -> Option<&Peer> {
+		match self.switch_core.interface(interface) {
+			Some(i) => self.peers.get(&i.address),
+			None => None
+		}
+	}
+
+	pub fn peer_by_interface_mut(&mut self, interface: u32) -> Option<&mut Peer> {
+		match self.switch_core.interface(interface) {
+			Some(i) => self.peers.get_mut(&i.address),
+			None => None
+		}
+	}
+
+	pub fn peers(&self) -> hash_map::Iter<SockAddr, Peer> {
+		self.peers.iter()
+	}
+
+	pub fn peers_mut(&mut self) -> hash_map::IterMut<SockAddr, Peer> {
+		self.peers.iter_mut()
+	}
+
+	pub fn peer_stats(&self) -> Vec<PeerStats> {
+		self.peers.values().map(|p| p.stats()).collect()
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{InterfaceController, PeerState, UNRESPONSIVE_AFTER, MAX_RETRY_DELAY};
+	use mio::net::SockAddr;
+	use encoding_scheme::from_name;
+	use Message;
+	use PrivateIdentity;
+	use Session;
+
+	fn sessions() -> (Session, Session) {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		(Session::new(&alice, &bob.public_key, None), Session::new(&bob, &alice.public_key, None))
+	}
+
+	#[test]
+	fn test_add_remove() {
+		let mut controller = InterfaceController::new();
+		let first = SockAddr::parse("127.0.0.1:1000").unwrap();
+		let second = SockAddr::parse("127.0.0.1:2000").unwrap();
+
+		let (session, _) = sessions();
+		assert_eq!(controller.add_peer(0, first.clone(), session, true, 100), Some(0));
+		let (session, _) = sessions();
+		assert_eq!(controller.add_peer(0, second.clone(), session, false, 100), Some(2));
+
+		assert_eq!(controller.peer_stats().len(), 2);
+		assert_eq!(controller.switch_core().interface(2).unwrap().address, second);
+		assert!(controller.peer_by_interface_mut(0).unwrap().is_outgoing());
+
+		let key = *controller.peer(&first).unwrap().session().her_public_key();
+		assert!(controller.has_peer_with_key(&key));
+
+		controller.remove_peer(&first);
+		assert!(controller.peer(&first).is_none());
+		assert!(controller.switch_core().interface(0).is_none());
+		assert_eq!(controller.peer_stats().len(), 1);
+		assert!(!controller.has_peer_with_key(&key));
+	}
+
+	#[test]
+	fn test_scheme() {
+		// Fixed four bit directors leave room for 15 peers next to the self interface
+		let mut controller = InterfaceController::with_scheme(from_name("fixed4").unwrap());
+		for port in range(1000, 1015) {
+			let (session, _) = sessions();
+			let address = SockAddr::parse(format!("127.0.0.1:{}", port).as_slice()).unwrap();
+			assert!(controller.add_peer(0, address, session, true, 100).is_some());
+		}
+
+		let (session, _) = sessions();
+		let address = SockAddr::parse("127.0.0.1:2000").unwrap();
+		assert_eq!(controller.add_peer(0, address, session, true, 100), None);
+	}
+
+	#[test]
+	fn test_states() {
+		let mut controller = InterfaceController::new();
+		let address = SockAddr::parse("127.0.0.1:1000").unwrap();
+		let (mut alice, bob) = sessions();
+		controller.add_peer(0, address.clone(), bob, false, 100);
+
+		let peer = controller.peer_mut(&address).unwrap();
+		assert_eq!(peer.state(), PeerState::Unauthenticated);
+
+		// Hello
+		let mut message = Message::from_slice(&[]);
+		alice.encrypt(&mut message).unwrap();
+		let len = message.len();
+		peer.session_mut().receive(&mut message).unwrap();
+		peer.received(len, 101);
+		assert_eq!(peer.state(), PeerState::Handshake);
+
+		// Key
+		let mut message = Message::from_slice(&[]);
+		peer.session_mut().encrypt(&mut message).unwrap();
+		peer.sent(message.len());
+		alice.receive(&mut message).unwrap();
+
+		// First data packet
+		let mut message = Message::from_slice(&[1, 2, 3]);
+		alice.encrypt(&mut message).unwrap();
+		let data_len = message.len();
+		peer.session_mut().receive(&mut message).unwrap();
+		peer.received(data_len, 102);
+		assert_eq!(peer.state(), PeerState::Established);
+
+		let stats = peer.stats();
+		assert_eq!(stats.bytes_in, (len + data_len) as u64);
+		assert!(stats.bytes_out > 0);
+		assert_eq!(stats.last_message, 102);
+
+		peer.check_responsive(102 + UNRESPONSIVE_AFTER - 1);
+		assert_eq!(peer.state(), PeerState::Established);
+		peer.check_responsive(102 + UNRESPONSIVE_AFTER);
+		assert_eq!(peer.state(), PeerState::Unresponsive);
+
+		peer.received(10, 200);
+		assert_eq!(peer.state(), PeerState::Established);
+
+		// Timestamps taken before the last message don't count as quiet time
+		peer.check_responsive(150);
+		assert_eq!(peer.state(), PeerState::Established);
+
+		peer.reset();
+		assert_eq!(peer.state(), PeerState::Unauthenticated);
+	}
+
+	#[test]
+	fn test_retry_backoff() {
+		let mut controller = InterfaceController::new();
+		let address = SockAddr::parse("127.0.0.1:1000").unwrap();
+		let (alice, _) = sessions();
+		controller.add_peer(1, address.clone(), alice, true, 100);
+
+		let peer = controller.peer_mut(&address).unwrap();
+		assert!(peer.retry_due(100));
+
+		peer.attempted(100, 10);
+		assert!(!peer.retry_due(109));
+		assert!(peer.retry_due(110));
+
+		peer.attempted(110, 10);
+		assert!(!peer.retry_due(129));
+		assert!(peer.retry_due(130));
+
+		for _ in range(0, 10) {
+			peer.attempted(130, 10);
+		}
+		assert!(!peer.retry_due(130 + MAX_RETRY_DELAY - 1));
+		assert!(peer.retry_due(130 + MAX_RETRY_DELAY));
+	}
+}
@@ -1,13 +1,16 @@
-#![feature(collections, core, hash, io, std_misc)]
+#![feature(collections, core, hash, io, libc, std_misc)]
 
 #[cfg(test)] extern crate test;
+extern crate libc;
 extern crate mio;
 extern crate sodiumoxide;
 extern crate "rustc-serialize" as rustc_serialize;
+extern crate time;
 extern crate tuntap;
 
 pub use address::Address;
 pub use config::Config;
+pub use dht::{DhtMessage, DhtQuery, DhtBody, NodeEntry, ROUTER_HANDLE};
 pub use error::{CjdrsError, CjdrsResult};
 pub use event_handler::{EventHandler, EventReceiver, Task};
 pub use identity::{
@@ -16,8 +19,18 @@ pub use identity::{
 	PrivateKey,
 	PublicKey};
 pub use device::NetDevice;
+pub use interface_controller::{InterfaceController, PeerState, PeerStats};
+pub use message::Message;
+pub use node_store::{NodeStore, Node};
 pub use route::Route;
+pub use password_store::PasswordStore;
+pub use pending_queue::{PendingQueue, PendingPacket};
+pub use replay_protector::ReplayProtector;
 pub use router::Router;
+pub use session::{Session, SessionState, SessionTimeouts};
+pub use session_manager::{SessionManager, SESSION_HANDLE};
+pub use switch_core::SwitchCore;
+pub use switch_error::SwitchError;
 pub use util::debug;
 
 mod macros;
@@ -30,11 +43,27 @@ pub mod util;
 
 mod address;
 mod config;
+mod dht;
 mod error;
 mod event_handler;
 mod identity;
+mod interface_controller;
+mod message;
+mod node_store;
+mod password_store;
+mod pending_queue;
+mod replay_protector;
 mod route;
 mod router;
+mod search;
+mod session;
+mod session_manager;
+mod switch_core;
+mod switch_error;
+
+
+/// Version of the cjdns protocol announced to peers
+pub const PROTOCOL_VERSION: u32 = 16;
 
 
 pub fn init() {
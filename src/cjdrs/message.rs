@@ -0,0 +1,178 @@
+use std::iter::repeat;
+use std::slice::bytes::copy_memory;
+
+
+/// Size of the buffer used for a single packet
+pub const MESSAGE_SIZE: usize = 2048;
+
+/// Space reserved in front of a received packet so that headers can be
+/// prepended in place when it is wrapped again
+pub const DEFAULT_HEADROOM: usize = 512;
+
+
+
+/// A packet buffer with reserved space in front of the content. Headers are
+/// prepended and removed by moving the start of the content, so packets can
+/// be wrapped, unwrapped, encrypted and decrypted without copying.
+#[derive(Debug)]
+pub struct Message {
+	buffer: Vec<u8>,
+	initial_headroom: usize,
+	start: usize,
+	end: usize
+}
+
+impl Message {
+	pub fn new(size: usize, headroom: usize) -> Message {
+		assert!(headroom <= size);
+
+		Message {
+			buffer: repeat(0u8).take(size).collect(),
+			initial_headroom: headroom,
+			start: headroom,
+			end: headroom
+		}
+	}
+
+	pub fn from_slice(content: &[u8]) -> Message {
+		let mut message = Message::new(DEFAULT_HEADROOM + content.len(), DEFAULT_HEADROOM);
+		copy_memory(message.receive_space(), content);
+		message.set_len(content.len());
+		message
+	}
+
+	/// Empties the message and restores the original headroom
+	pub fn clear(&mut self) {
+		self.start = self.initial_headroom;
+		self.end = self.initial_headroom;
+	}
+
+	/// Space after the start of the content that a packet can be read into.
+	/// Use `set_len` afterwards to tell how much of it was filled.
+	pub fn receive_space(&mut self) -> &mut [u8] {
+		&mut self.buffer[self.start..]
+	}
+
+	pub fn set_len(&mut self, len: usize) {
+		assert!(self.start + len <= self.buffer.len());
+		self.end = self.start + len;
+	}
+
+	pub fn len(&self) -> usize {
+		self.end - self.start
+	}
+
+	pub fn headroom(&self) -> usize {
+		self.start
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		&self.buffer[self.start..self.end]
+	}
+
+	pub fn as_mut_slice(&mut self) -> &mut [u8] {
+		&mut self.buffer[self.start..self.end]
+	}
+
+	/// Prepends bytes to the content
+	pub fn push(&mut self, bytes: &[u8]) {
+		self.push_zeros(bytes.len());
+		copy_memory(self.as_mut_slice(), bytes);
+	}
+
+	/// Prepends zero bytes to the content
+	pub fn push_zeros(&mut self, count: usize) {
+		assert!(count <= self.start, "Message out of headroom");
+
+		self.start -= count;
+		for b in self.buffer[self.start..self.start + count].iter_mut() {
+			*b = 0;
+		}
+	}
+
+	pub fn push_u16(&mut self, val: u16) {
+		self.push(&[(val >> 8) as u8, val as u8]);
+	}
+
+	pub fn push_u32(&mut self, val: u32) {
+		self.push(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+	}
+
+	/// Removes bytes from the front of the content and returns them
+	pub fn pop(&mut self, count: usize) -> &[u8] {
+		assert!(count <= self.len(), "Message too short");
+
+		self.start += count;
+		&self.buffer[self.start - count..self.start]
+	}
+
+	pub fn pop_u16(&mut self) -> u16 {
+		let bytes = self.pop(2);
+		(bytes[0] as u16) << 8 | bytes[1] as u16
+	}
+
+	pub fn pop_u32(&mut self) -> u32 {
+		let bytes = self.pop(4);
+		(bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::Message;
+
+	#[test]
+	fn test_push_pop() {
+		let mut message = Message::new(64, 16);
+		assert_eq!(message.len(), 0);
+
+		message.receive_space()[0] = 5;
+		message.receive_space()[1] = 6;
+		message.set_len(2);
+		assert_eq!(message.as_slice(), [5, 6].as_slice());
+
+		message.push(&[3, 4]);
+		message.push_u16(0x0102);
+		assert_eq!(message.as_slice(), [1, 2, 3, 4, 5, 6].as_slice());
+		assert_eq!(message.headroom(), 12);
+
+		message.push_u32(0xAABBCCDD);
+		assert_eq!(message.pop(4), [0xAA, 0xBB, 0xCC, 0xDD].as_slice());
+		assert_eq!(message.pop(2), [1, 2].as_slice());
+		assert_eq!(message.as_slice(), [3, 4, 5, 6].as_slice());
+
+		message.push_zeros(2);
+		assert_eq!(message.as_slice(), [0, 0, 3, 4, 5, 6].as_slice());
+
+		message.push_u32(0x01020304);
+		assert_eq!(message.pop_u16(), 0x0102);
+		assert_eq!(message.pop_u32(), 0x03040000);
+
+		message.clear();
+		assert_eq!(message.len(), 0);
+		assert_eq!(message.headroom(), 16);
+	}
+
+	#[test]
+	fn test_from_slice() {
+		let message = Message::from_slice(&[1, 2, 3]);
+		assert_eq!(message.as_slice(), [1, 2, 3].as_slice());
+		assert!(message.headroom() > 0);
+	}
+
+	#[test]
+	#[should_fail]
+	fn test_out_of_headroom() {
+		let mut message = Message::new(64, 4);
+		message.push(&[1, 2, 3, 4, 5]);
+	}
+
+	#[test]
+	#[should_fail]
+	fn test_pop_too_much() {
+		let mut message = Message::from_slice(&[1, 2, 3]);
+		message.pop(4);
+	}
+}
@@ -0,0 +1,217 @@
+use std::num::Int;
+use encoding_scheme::FormList;
+use Address;
+use PublicKey;
+use Route;
+
+
+/// Nodes kept per bucket
+pub const BUCKET_SIZE: usize = 8;
+
+/// One bucket per bit of shared address prefix
+const BUCKET_COUNT: usize = 128;
+
+
+
+/// What we know about another node of the network
+#[derive(Debug, Clone)]
+pub struct Node {
+	pub public_key: PublicKey,
+	pub address: Address,
+	pub route: Route,
+	/// Encoding scheme of the node's switch
+	pub scheme: FormList,
+	pub version: u32,
+	/// How well the node can be reached, higher is better
+	pub reach: u32,
+	pub last_seen: u64
+}
+
+
+
+/// Known nodes in Kademlia buckets. A node goes to the bucket of the number
+/// of leading bits its address shares with ours, so we know many nodes close
+/// to us and a few far away.
+#[derive(Debug)]
+pub struct NodeStore {
+	own_address: Address,
+	buckets: Vec<Vec<Node>>
+}
+
+impl NodeStore {
+	pub fn new(own_address: &Address) -> NodeStore {
+		NodeStore {
+			own_address: *own_address,
+			buckets: range(0, BUCKET_COUNT).map(|_| Vec::with_capacity(BUCKET_SIZE)).collect()
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.buckets.iter().fold(0, |len, b| len + b.len())
+	}
+
+	/// Adds a node or updates the one with the same address. If its bucket
+	/// is full, the node replaces the one with the lowest reach, or the one
+	/// seen longest ago among those, if it's better itself. Returns whether
+	/// the node was stored.
+	pub fn add(&mut self, node: Node) -> bool {
+		let bucket = match self.bucket_index(&node.address) {
+			Some(i) => &mut self.buckets[i],
+			None => return false
+		};
+
+		if let Some(known) = bucket.iter_mut().find(|n| n.address == node.address) {
+			*known = node;
+			return true;
+		}
+
+		if bucket.len() < BUCKET_SIZE {
+			bucket.push(node);
+			return true;
+		}
+
+		let least_useful = match bucket.iter().enumerate().min_by(|&(_, n)| (n.reach, n.last_seen)) {
+			Some((i, n)) if (node.reach, node.last_seen) > (n.reach, n.last_seen) => i,
+			_ => return false
+		};
+		bucket[least_useful] = node;
+		true
+	}
+
+	pub fn remove(&mut self, address: &Address) -> Option<Node> {
+		let bucket = match self.bucket_index(address) {
+			Some(i) => &mut self.buckets[i],
+			None => return None
+		};
+		match bucket.iter().position(|n| n.address == *address) {
+			Some(i) => Some(bucket.swap_remove(i)),
+			None => None
+		}
+	}
+
+	pub fn get(&self, address: &Address) -> Option<&Node> {
+		match self.bucket_index(address) {
+			Some(i) => self.buckets[i].iter().find(|n| n.address == *address),
+			None => None
+		}
+	}
+
+	pub fn get_mut(&mut self, address: &Address) -> Option<&mut Node> {
+		match self.bucket_index(address) {
+			Some(i) => self.buckets[i].iter_mut().find(|n| n.address == *address),
+			None => None
+		}
+	}
+
+	/// Up to `count` nodes closest to the target address, closest first
+	pub fn closest(&self, target: &Address, count: usize) -> Vec<&Node> {
+		let mut nodes: Vec<&Node> = self.buckets.iter().flat_map(|b| b.iter()).collect();
+		nodes.sort_by(|a, b| Address::xor_compare((&a.address, target), (&b.address, target)));
+		nodes.truncate(count);
+		nodes
+	}
+
+	/// Bucket of an address, None for our own
+	fn bucket_index(&self, address: &Address) -> Option<usize> {
+		let (high, low) = self.own_address.xor_distance(address);
+		let shared = if high != 0 {
+			high.leading_zeros() as usize
+		} else {
+			64 + low.leading_zeros() as usize
+		};
+
+		if shared < BUCKET_COUNT { Some(shared) } else { None }
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{NodeStore, Node, BUCKET_SIZE};
+	use encoding_scheme::{EncodingScheme, Variable3x5x8};
+	use Address;
+	use PublicKey;
+	use Route;
+
+	fn node(address: &str, reach: u32, last_seen: u64) -> Node {
+		Node {
+			public_key: PublicKey::from_buffer(&[0; 32]),
+			address: Address::from_string(address).unwrap(),
+			route: Route::new(0b1_0101),
+			scheme: Variable3x5x8.form_list().unwrap(),
+			version: 16,
+			reach: reach,
+			last_seen: last_seen
+		}
+	}
+
+	fn node_store() -> NodeStore {
+		NodeStore::new(&Address::from_string("fc00::").unwrap())
+	}
+
+	#[test]
+	fn test_add_get_remove() {
+		let mut store = node_store();
+		assert!(store.add(node("fc00::1", 10, 100)));
+		assert!(store.add(node("fc00::1:0", 10, 100)));
+		assert_eq!(store.len(), 2);
+
+		// Updating a known node
+		assert!(store.add(node("fc00::1", 20, 200)));
+		assert_eq!(store.len(), 2);
+		let address = Address::from_string("fc00::1").unwrap();
+		assert_eq!(store.get(&address).unwrap().reach, 20);
+
+		store.get_mut(&address).unwrap().reach = 5;
+		assert_eq!(store.get(&address).unwrap().reach, 5);
+
+		assert!(store.remove(&address).is_some());
+		assert!(store.get(&address).is_none());
+		assert!(store.remove(&address).is_none());
+		assert_eq!(store.len(), 1);
+
+		// Never ourselves
+		assert!(!store.add(node("fc00::", 10, 100)));
+	}
+
+	#[test]
+	fn test_eviction() {
+		let mut store = node_store();
+
+		// All share the same 48 bits with our address
+		for i in range(0, BUCKET_SIZE) {
+			let address = format!("fc00::{:x}", 0x8000 + i);
+			assert!(store.add(node(address.as_slice(), 10 + i as u32, 100)));
+		}
+		assert_eq!(store.len(), BUCKET_SIZE);
+
+		// Less useful than anything in the bucket
+		assert!(!store.add(node("fc00::9000", 10, 99)));
+		assert!(store.get(&Address::from_string("fc00::9000").unwrap()).is_none());
+
+		// Replaces the one with the lowest reach
+		assert!(store.add(node("fc00::9001", 10, 101)));
+		assert_eq!(store.len(), BUCKET_SIZE);
+		assert!(store.get(&Address::from_string("fc00::8000").unwrap()).is_none());
+		assert!(store.get(&Address::from_string("fc00::9001").unwrap()).is_some());
+
+		// Other buckets aren't affected
+		assert!(store.add(node("fc00::1", 0, 0)));
+	}
+
+	#[test]
+	fn test_closest() {
+		let mut store = node_store();
+		for address in ["fc00::1", "fc00::2", "fc00::3", "fc00::ff"].iter() {
+			store.add(node(*address, 10, 100));
+		}
+
+		let target = Address::from_string("fc00::3").unwrap();
+		let closest: Vec<String> = store.closest(&target, 3).iter().map(|n| n.address.to_string()).collect();
+		assert_eq!(closest, vec![
+			"fc00:0000:0000:0000:0000:0000:0000:0003",
+			"fc00:0000:0000:0000:0000:0000:0000:0002",
+			"fc00:0000:0000:0000:0000:0000:0000:0001"]);
+	}
+}
@@ -0,0 +1,112 @@
+use std::mem::size_of;
+use message::Message;
+use packet::{ParseResult, Packet, buffer_to_type};
+use identity::{PublicKey, PUB_KEY_SIZE};
+use util::BigEndian;
+
+#[cfg(test)] pub const BEACON_HEADER_LENGTH: usize = 56;
+
+/// Length of the password a beacon hands out to peers
+pub const BEACON_PASSWORD_LENGTH: usize = 20;
+
+
+
+/// Beacon in the same layout as cjdns. It's sent from the socket peers
+/// connect to, so the sender's address tells where to connect.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct BeaconHeader {
+	version: BigEndian<u32>,
+	password: [u8; BEACON_PASSWORD_LENGTH],
+	public_key: [u8; PUB_KEY_SIZE]
+}
+
+impl BeaconHeader {
+	/// Beacon announcing that peers can connect with `password`
+	pub fn new(version: u32,
+	           password: &[u8; BEACON_PASSWORD_LENGTH],
+	           public_key: &PublicKey) -> BeaconHeader {
+		BeaconHeader {
+			version: BigEndian::new(version),
+			password: *password,
+			public_key: *public_key.as_slice()
+		}
+	}
+
+	pub fn push_to(&self, message: &mut Message) {
+		message.push(&self.public_key);
+		message.push(&self.password);
+		message.push_u32(self.version.val());
+	}
+}
+
+
+
+pub type Beacon<'a> = Packet<'a, BeaconHeader, ()>;
+
+impl<'a> Beacon<'a> {
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<Beacon> {
+		let header: &BeaconHeader = try!(buffer_to_type(buffer));
+
+		if buffer.len() != size_of::<BeaconHeader>() {
+			return Err("Beacon has trailing data");
+		}
+
+		Ok(Beacon {
+			slice: buffer,
+			header: header,
+			data: ()
+		})
+	}
+
+	pub fn version(&self) -> u32 {
+		self.header.version.val()
+	}
+
+	pub fn password(&self) -> &[u8; BEACON_PASSWORD_LENGTH] {
+		&self.header.password
+	}
+
+	pub fn public_key(&self) -> PublicKey {
+		PublicKey::from_buffer(&self.header.public_key)
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem::size_of;
+	use identity::PublicKey;
+	use message::Message;
+
+	#[test]
+	fn test_sizeof() {
+		assert_eq!(size_of::<BeaconHeader>(), BEACON_HEADER_LENGTH);
+	}
+
+	#[test]
+	fn test_push_parse() {
+		let public_key = PublicKey::from_buffer(&[0xAA; 32]);
+		let header = BeaconHeader::new(16, &[0x61; BEACON_PASSWORD_LENGTH], &public_key);
+
+		let mut message = Message::from_slice(&[]);
+		header.push_to(&mut message);
+		assert_eq!(message.len(), BEACON_HEADER_LENGTH);
+		assert_eq!(&message.as_slice()[..5], [0, 0, 0, 16, 0x61].as_slice());
+		assert_eq!(&message.as_slice()[23..25], [0x61, 0xAA].as_slice());
+
+		let beacon = Beacon::from_buffer(message.as_slice()).unwrap();
+		assert_eq!(beacon.version(), 16);
+		assert_eq!(*beacon.password(), [0x61; BEACON_PASSWORD_LENGTH]);
+		assert_eq!(beacon.public_key(), public_key);
+	}
+
+	#[test]
+	fn test_invalid_length() {
+		assert!(Beacon::from_buffer(&[0; BEACON_HEADER_LENGTH - 1]).is_err());
+		assert!(Beacon::from_buffer(&[0; BEACON_HEADER_LENGTH + 1]).is_err());
+		assert!(Beacon::from_buffer(&[0; BEACON_HEADER_LENGTH]).is_ok());
+	}
+}
@@ -0,0 +1,393 @@
+use std::mem::size_of;
+use message::Message;
+use packet::{ParseResult, Packet, SwitchHeader, buffer_to_type};
+use identity::{PublicKey, PUB_KEY_SIZE};
+use util::{self, BigEndian};
+use SwitchError;
+
+#[cfg(test)] pub const CONTROL_HEADER_LENGTH: usize = 4;
+#[cfg(test)] pub const PING_HEADER_LENGTH: usize = 8;
+#[cfg(test)] pub const KEY_PING_HEADER_LENGTH: usize = 40;
+#[cfg(test)] pub const ERROR_HEADER_LENGTH: usize = 16;
+
+/// Session handle that marks a frame following a switch header as a
+/// control message rather than CryptoAuth traffic
+pub const CONTROL_HANDLE: u32 = 0xFFFF_FFFF;
+
+/// Largest payload a ping may carry
+pub const MAX_PING_DATA: usize = 256;
+
+const PING_MAGIC: u32 = 0x09F9_1102;
+const PONG_MAGIC: u32 = 0x9D74_E35B;
+const KEY_PING_MAGIC: u32 = 0x0123_4567;
+const KEY_PONG_MAGIC: u32 = 0x89AB_CDEF;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ControlType {
+	Error,
+	Ping,
+	Pong,
+	KeyPing,
+	KeyPong
+}
+
+impl ControlType {
+	pub fn to_u16(&self) -> u16 {
+		match *self {
+			ControlType::Error => 2,
+			ControlType::Ping => 3,
+			ControlType::Pong => 4,
+			ControlType::KeyPing => 5,
+			ControlType::KeyPong => 6
+		}
+	}
+
+	fn from_u16(control_type: u16) -> Option<ControlType> {
+		match control_type {
+			2 => Some(ControlType::Error),
+			3 => Some(ControlType::Ping),
+			4 => Some(ControlType::Pong),
+			5 => Some(ControlType::KeyPing),
+			6 => Some(ControlType::KeyPong),
+			_ => None
+		}
+	}
+
+	fn magic(&self) -> Option<u32> {
+		match *self {
+			ControlType::Error => None,
+			ControlType::Ping => Some(PING_MAGIC),
+			ControlType::Pong => Some(PONG_MAGIC),
+			ControlType::KeyPing => Some(KEY_PING_MAGIC),
+			ControlType::KeyPong => Some(KEY_PONG_MAGIC)
+		}
+	}
+}
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct ControlHeader {
+	checksum: BigEndian<u16>,
+	control_type: BigEndian<u16>
+}
+
+impl ControlHeader {
+	/// Prepends the control header to a message holding the control payload
+	/// and fills in the checksum over both.
+	fn push_to(message: &mut Message, control_type: ControlType) {
+		message.push_u16(control_type.to_u16());
+		message.push_u16(0);
+
+		let checksum = util::checksum(message.as_slice());
+		message.pop(2);
+		message.push_u16(checksum);
+	}
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct PingHeader {
+	magic: BigEndian<u32>,
+	version: BigEndian<u32>
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct KeyPingHeader {
+	magic: BigEndian<u32>,
+	version: BigEndian<u32>,
+	public_key: [u8; PUB_KEY_SIZE]
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct ErrorHeader {
+	error_type: BigEndian<u32>,
+	cause: SwitchHeader
+}
+
+
+
+pub type ControlPing<'a> = Packet<'a, PingHeader, &'a [u8]>;
+pub type ControlKeyPing<'a> = Packet<'a, KeyPingHeader, &'a [u8]>;
+pub type ControlError<'a> = Packet<'a, ErrorHeader, &'a [u8]>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Control<'a> {
+	Error(ControlError<'a>),
+	Ping(ControlPing<'a>),
+	Pong(ControlPing<'a>),
+	KeyPing(ControlKeyPing<'a>),
+	KeyPong(ControlKeyPing<'a>)
+}
+
+impl<'a> Control<'a> {
+	/// Parses a control message starting at the control header
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<Control> {
+		let header: &ControlHeader = try!(buffer_to_type(buffer));
+
+		if util::checksum(buffer) != 0 {
+			return Err("Control message checksum mismatch");
+		}
+
+		let control_type = match ControlType::from_u16(header.control_type.val()) {
+			Some(t) => t,
+			None => return Err("Unknown control message type")
+		};
+
+		let content = &buffer[size_of::<ControlHeader>()..];
+		if let Some(magic) = control_type.magic() {
+			let content_magic: &BigEndian<u32> = try!(buffer_to_type(content));
+			if content_magic.val() != magic {
+				return Err("Control message magic mismatch");
+			}
+		}
+
+		Ok(match control_type {
+			ControlType::Error => Control::Error(try!(ControlError::from_buffer(buffer))),
+			ControlType::Ping => Control::Ping(try!(ControlPing::from_buffer(buffer))),
+			ControlType::Pong => Control::Pong(try!(ControlPing::from_buffer(buffer))),
+			ControlType::KeyPing => Control::KeyPing(try!(ControlKeyPing::from_buffer(buffer))),
+			ControlType::KeyPong => Control::KeyPong(try!(ControlKeyPing::from_buffer(buffer)))
+		})
+	}
+
+	pub fn control_type(&self) -> ControlType {
+		match *self {
+			Control::Error(..) => ControlType::Error,
+			Control::Ping(..) => ControlType::Ping,
+			Control::Pong(..) => ControlType::Pong,
+			Control::KeyPing(..) => ControlType::KeyPing,
+			Control::KeyPong(..) => ControlType::KeyPong
+		}
+	}
+}
+
+
+
+impl<'a> ControlPing<'a> {
+	/// Prepends a ping or pong header to a message holding the ping data
+	pub fn push_header(message: &mut Message, is_pong: bool, version: u32) {
+		assert!(message.len() <= MAX_PING_DATA);
+
+		let control_type = if is_pong { ControlType::Pong } else { ControlType::Ping };
+		message.push_u32(version);
+		message.push_u32(control_type.magic().unwrap());
+		ControlHeader::push_to(message, control_type);
+	}
+
+	fn from_buffer(buffer: &[u8]) -> ParseResult<ControlPing> {
+		let content = &buffer[size_of::<ControlHeader>()..];
+		let header: &PingHeader = try!(buffer_to_type(content));
+		let data = &content[size_of::<PingHeader>()..];
+
+		if data.len() > MAX_PING_DATA {
+			return Err("Ping data too long");
+		}
+
+		Ok(ControlPing {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn version(&self) -> u32 {
+		self.header.version.val()
+	}
+}
+
+
+
+impl<'a> ControlKeyPing<'a> {
+	/// Prepends a key ping or key pong header to a message holding the ping data
+	pub fn push_header(message: &mut Message,
+	                   is_pong: bool,
+	                   version: u32,
+	                   public_key: &PublicKey) {
+		assert!(message.len() <= MAX_PING_DATA);
+
+		let control_type = if is_pong { ControlType::KeyPong } else { ControlType::KeyPing };
+		message.push(public_key.as_slice());
+		message.push_u32(version);
+		message.push_u32(control_type.magic().unwrap());
+		ControlHeader::push_to(message, control_type);
+	}
+
+	fn from_buffer(buffer: &[u8]) -> ParseResult<ControlKeyPing> {
+		let content = &buffer[size_of::<ControlHeader>()..];
+		let header: &KeyPingHeader = try!(buffer_to_type(content));
+		let data = &content[size_of::<KeyPingHeader>()..];
+
+		if data.len() > MAX_PING_DATA {
+			return Err("Ping data too long");
+		}
+
+		Ok(ControlKeyPing {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn version(&self) -> u32 {
+		self.header.version.val()
+	}
+
+	pub fn public_key(&self) -> PublicKey {
+		PublicKey::from_buffer(&self.header.public_key)
+	}
+}
+
+
+
+impl<'a> ControlError<'a> {
+	/// Prepends an error header to a message holding the beginning of the
+	/// frame that caused the error, after its switch header.
+	pub fn push_header(message: &mut Message, error: SwitchError, cause: &SwitchHeader) {
+		cause.push_to(message);
+		message.push_u32(error.to_u32());
+		ControlHeader::push_to(message, ControlType::Error);
+	}
+
+	fn from_buffer(buffer: &[u8]) -> ParseResult<ControlError> {
+		let content = &buffer[size_of::<ControlHeader>()..];
+		let header: &ErrorHeader = try!(buffer_to_type(content));
+		let data = &content[size_of::<ErrorHeader>()..];
+
+		Ok(ControlError {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	/// None if the error code is unknown
+	pub fn error_type(&self) -> Option<SwitchError> {
+		SwitchError::from_u32(self.header.error_type.val())
+	}
+
+	/// Switch header of the frame that caused the error
+	pub fn cause(&self) -> &SwitchHeader {
+		&self.header.cause
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem::size_of;
+	use identity::PublicKey;
+	use message::Message;
+	use packet::SwitchHeader;
+	use SwitchError;
+
+	#[test]
+	fn test_sizeof() {
+		assert_eq!(size_of::<ControlHeader>(), CONTROL_HEADER_LENGTH);
+		assert_eq!(size_of::<PingHeader>(), PING_HEADER_LENGTH);
+		assert_eq!(size_of::<KeyPingHeader>(), KEY_PING_HEADER_LENGTH);
+		assert_eq!(size_of::<ErrorHeader>(), ERROR_HEADER_LENGTH);
+	}
+
+	#[test]
+	fn test_parse_ping() {
+		let buffer = [
+			0xE3, 0xFE, 0x00, 0x03,
+			0x09, 0xF9, 0x11, 0x02,
+			0x00, 0x00, 0x00, 0x01,
+			0x01, 0x02];
+		match Control::from_buffer(&buffer).unwrap() {
+			Control::Ping(ping) => {
+				assert_eq!(ping.version(), 1);
+				assert_eq!(ping.data, [0x01, 0x02].as_slice());
+			},
+			_ => panic!("Ping parsed as a different control message")
+		}
+	}
+
+	#[test]
+	fn test_push_ping() {
+		let mut message = Message::from_slice(&[]);
+		ControlPing::push_header(&mut message, false, 1);
+		assert_eq!(message.as_slice(), [
+			0xE5, 0x00, 0x00, 0x03,
+			0x09, 0xF9, 0x11, 0x02,
+			0x00, 0x00, 0x00, 0x01].as_slice());
+
+		let mut message = Message::from_slice(&[0xAA, 0xBB, 0xCC]);
+		ControlPing::push_header(&mut message, true, 7);
+		match Control::from_buffer(message.as_slice()).unwrap() {
+			Control::Pong(pong) => {
+				assert_eq!(pong.version(), 7);
+				assert_eq!(pong.data, [0xAA, 0xBB, 0xCC].as_slice());
+			},
+			_ => panic!("Pong parsed as a different control message")
+		}
+	}
+
+	#[test]
+	fn test_push_key_ping() {
+		let public_key = PublicKey::from_buffer(&[0xAA; 32]);
+
+		for &is_pong in [false, true].iter() {
+			let mut message = Message::from_slice(&[0x01]);
+			ControlKeyPing::push_header(&mut message, is_pong, 3, &public_key);
+			assert_eq!(message.len(), CONTROL_HEADER_LENGTH + KEY_PING_HEADER_LENGTH + 1);
+
+			let key_ping = match Control::from_buffer(message.as_slice()).unwrap() {
+				Control::KeyPing(p) => { assert!(!is_pong); p },
+				Control::KeyPong(p) => { assert!(is_pong); p },
+				_ => panic!("Key ping parsed as a different control message")
+			};
+			assert_eq!(key_ping.version(), 3);
+			assert_eq!(key_ping.public_key(), public_key);
+			assert_eq!(key_ping.data, [0x01].as_slice());
+		}
+	}
+
+	#[test]
+	fn test_push_error() {
+		let cause = SwitchHeader::new(0x13);
+		let mut message = Message::from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+		ControlError::push_header(&mut message, SwitchError::Undeliverable, &cause);
+		assert_eq!(&message.as_slice()[2..8], [0x00, 0x02, 0x00, 0x00, 0x00, 0x08].as_slice());
+
+		match Control::from_buffer(message.as_slice()).unwrap() {
+			Control::Error(error) => {
+				assert_eq!(error.error_type(), Some(SwitchError::Undeliverable));
+				assert_eq!(*error.cause(), cause);
+				assert_eq!(error.data, [0xFF, 0xFF, 0xFF, 0xFF].as_slice());
+			},
+			_ => panic!("Error parsed as a different control message")
+		}
+	}
+
+	#[test]
+	fn test_invalid() {
+		// Bad checksum
+		let buffer = [
+			0xE5, 0x01, 0x00, 0x03,
+			0x09, 0xF9, 0x11, 0x02,
+			0x00, 0x00, 0x00, 0x01];
+		assert!(Control::from_buffer(&buffer).is_err());
+
+		// Pong magic in a ping
+		let mut message = Message::from_slice(&[]);
+		ControlPing::push_header(&mut message, true, 1);
+		let mut buffer = message.as_slice().to_vec();
+		buffer[3] = 3;
+		buffer[1] += 1;
+		assert!(Control::from_buffer(buffer.as_slice()).is_err());
+
+		// Unknown type
+		assert!(Control::from_buffer(&[0xFF, 0xFE, 0x00, 0x01]).is_err());
+		assert!(Control::from_buffer(&[0x00, 0x00, 0x00]).is_err());
+	}
+}
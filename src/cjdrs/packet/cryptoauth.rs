@@ -1,12 +1,54 @@
-use std::mem::{size_of, transmute};
-use std::raw::Slice;
-use crypto::{SharedSecret, Nonce, CryptoBox};
+use std::mem::size_of;
+use message::Message;
 use packet::{ParseResult, Packet, buffer_to_type};
 use identity::{PublicKey, PUB_KEY_SIZE};
 use util::BigEndian;
 use debug::as_hex;
 
 #[cfg(test)] pub const CRYPTOAUTH_HEADER_LENGTH: usize = 120;
+#[cfg(test)] pub const CRYPTOAUTH_DATA_HEADER_LENGTH: usize = 20;
+
+/// Bytes of a handshake packet in front of the authenticator
+pub const HANDSHAKE_UNENCRYPTED_LENGTH: usize = 72;
+
+/// Bytes of a data packet in front of the authenticator
+pub const DATA_UNENCRYPTED_LENGTH: usize = 4;
+
+pub const FIRST_DATA_NONCE: u32 = 4;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HandshakeStage {
+	Hello,
+	RepeatHello,
+	Key,
+	RepeatKey
+}
+
+impl HandshakeStage {
+	pub fn to_u32(&self) -> u32 {
+		*self as u32
+	}
+
+	fn from_u32(stage: u32) -> Option<HandshakeStage> {
+		match stage {
+			0 => Some(HandshakeStage::Hello),
+			1 => Some(HandshakeStage::RepeatHello),
+			2 => Some(HandshakeStage::Key),
+			3 => Some(HandshakeStage::RepeatKey),
+			_ => None
+		}
+	}
+
+	pub fn is_hello(&self) -> bool {
+		*self == HandshakeStage::Hello || *self == HandshakeStage::RepeatHello
+	}
+
+	pub fn is_key(&self) -> bool {
+		*self == HandshakeStage::Key || *self == HandshakeStage::RepeatKey
+	}
+}
 
 
 
@@ -20,6 +62,23 @@ pub struct Challenge {
 }
 
 impl Challenge {
+	pub fn new(challenge_type: u8, lookup: &[u8; 7]) -> Challenge {
+		Challenge {
+			challenge_type: challenge_type,
+			lookup: *lookup,
+			require_auth_and_derivation_count: BigEndian::new(0),
+			additional: BigEndian::new(0)
+		}
+	}
+
+	pub fn challenge_type(&self) -> u8 {
+		self.challenge_type
+	}
+
+	pub fn lookup(&self) -> &[u8; 7] {
+		&self.lookup
+	}
+
 	pub fn require_auth(&self) -> bool {
 		self.require_auth_and_derivation_count.val() >> 15 != 0
 	}
@@ -27,6 +86,13 @@ impl Challenge {
 	pub fn derivations(&self) -> u16 {
 		self.require_auth_and_derivation_count.val() & (!0 >> 1)
 	}
+
+	fn push_to(&self, message: &mut Message) {
+		message.push_u16(self.additional.val());
+		message.push_u16(self.require_auth_and_derivation_count.val());
+		message.push(&self.lookup);
+		message.push(&[self.challenge_type]);
+	}
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -40,46 +106,87 @@ pub struct CryptoAuthHeader {
 	encrypted_temp_key: [u8; 32]
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct CryptoAuthDataHeader {
+	nonce: BigEndian<u32>,
+	authenticator: [u8; 16]
+}
+
+
 
+pub type CryptoAuthHandshake<'a> = Packet<'a, CryptoAuthHeader, &'a [u8]>;
+pub type CryptoAuthData<'a> = Packet<'a, CryptoAuthDataHeader, &'a [u8]>;
 
-pub type CryptoAuth<'a> = Packet<'a, CryptoAuthHeader, &'a [u8]>;
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CryptoAuth<'a> {
+	Handshake(CryptoAuthHandshake<'a>),
+	Data(CryptoAuthData<'a>)
+}
 
 impl<'a> CryptoAuth<'a> {
 	pub fn from_buffer(buffer: &[u8]) -> ParseResult<CryptoAuth> {
 		let stage_or_nonce: &BigEndian<u32> = try!(buffer_to_type(buffer));
-		
+
 		match stage_or_nonce.val() {
-			0 | 1 => {
-				let header: &CryptoAuthHeader = try!(buffer_to_type(buffer));
-				let data = &buffer[size_of::<CryptoAuthHeader>()..];
-
-				println!("");
-				println!("Stage:                  0x{:08X}", header.stage.val());
-				println!("Auth challenge");
-				println!("    Challenge type:     {}", header.auth_challenge.challenge_type);
-				println!("    Lookup:             {}", as_hex(&header.auth_challenge.lookup));
-				println!("    Require auth:       {}", header.auth_challenge.require_auth());
-				println!("    Derivations:        {}", header.auth_challenge.derivations());
-				println!("    Additional:         0x{:04X}", header.auth_challenge.additional.val());
-				println!("Nonce:                  {}", as_hex(&header.nonce));
-				println!("Perm public key:        {}", PublicKey::from_slice(&header.public_key));
-				println!("Poly1305 authenticator: {}", as_hex(&header.authenticator));
-				println!("Temporary public key:   {}", as_hex(&header.encrypted_temp_key));
-				println!("Data:                   {}", as_hex(data));
-				println!("");
-				
-
-
-				Ok(CryptoAuth {
-					slice: buffer,
-					header: header,
-					data: data
-				})
-			},
-			n => {
-				println!("Unknown type {}", n);
-				unimplemented!();
-			}
+			0 ... 3 => Ok(CryptoAuth::Handshake(try!(CryptoAuthHandshake::from_buffer(buffer)))),
+			_ => Ok(CryptoAuth::Data(try!(CryptoAuthData::from_buffer(buffer))))
+		}
+	}
+}
+
+
+
+impl<'a> CryptoAuthHandshake<'a> {
+	/// Prepends the header of a Hello or Key packet to a message holding the
+	/// authenticator followed by the encrypted temporary key and any payload.
+	pub fn push_header(message: &mut Message,
+	                   stage: HandshakeStage,
+	                   challenge: &Challenge,
+	                   nonce: &[u8; 24],
+	                   public_key: &PublicKey) {
+		assert!(message.len() >= 16 + PUB_KEY_SIZE);
+
+		message.push(public_key.as_slice());
+		message.push(nonce);
+		challenge.push_to(message);
+		message.push_u32(stage.to_u32());
+	}
+
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<CryptoAuthHandshake> {
+		let header: &CryptoAuthHeader = try!(buffer_to_type(buffer));
+		let data = &buffer[size_of::<CryptoAuthHeader>()..];
+
+		if HandshakeStage::from_u32(header.stage.val()).is_none() {
+			return Err("Not a handshake packet");
+		}
+
+		println!("");
+		println!("Stage:                  0x{:08X}", header.stage.val());
+		println!("Auth challenge");
+		println!("    Challenge type:     {}", header.auth_challenge.challenge_type);
+		println!("    Lookup:             {}", as_hex(&header.auth_challenge.lookup));
+		println!("    Require auth:       {}", header.auth_challenge.require_auth());
+		println!("    Derivations:        {}", header.auth_challenge.derivations());
+		println!("    Additional:         0x{:04X}", header.auth_challenge.additional.val());
+		println!("Nonce:                  {}", as_hex(&header.nonce));
+		println!("Perm public key:        {}", PublicKey::from_slice(&header.public_key));
+		println!("Poly1305 authenticator: {}", as_hex(&header.authenticator));
+		println!("Temporary public key:   {}", as_hex(&header.encrypted_temp_key));
+		println!("Data:                   {}", as_hex(data));
+		println!("");
+
+		Ok(CryptoAuthHandshake {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn stage(&self) -> HandshakeStage {
+		match HandshakeStage::from_u32(self.header.stage.val()) {
+			Some(stage) => stage,
+			None => unreachable!()
 		}
 	}
 
@@ -87,22 +194,44 @@ impl<'a> CryptoAuth<'a> {
 		&self.header.auth_challenge
 	}
 
+	pub fn nonce(&self) -> &[u8; 24] {
+		&self.header.nonce
+	}
+
 	pub fn public_key(&self) -> PublicKey {
 		PublicKey::from_buffer(&self.header.public_key)
 	}
+}
+
+
+
+impl<'a> CryptoAuthData<'a> {
+	/// Prepends the header of a data packet to a message holding the
+	/// authenticator followed by the encrypted payload.
+	pub fn push_header(message: &mut Message, nonce: u32) {
+		assert!(nonce >= FIRST_DATA_NONCE);
+		assert!(message.len() >= 16);
+
+		message.push_u32(nonce);
+	}
+
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<CryptoAuthData> {
+		let header: &CryptoAuthDataHeader = try!(buffer_to_type(buffer));
+		let data = &buffer[size_of::<CryptoAuthDataHeader>()..];
 
-	pub fn decrypt(&mut self, shared_secret: &SharedSecret) -> Option<Vec<u8>> {
-		let encrypted_part: &[u8] = unsafe {
-			transmute(Slice {
-				data: &self.header.authenticator,
-				len: 16 + 32 + self.data.len()
-			})
-		};
+		if header.nonce.val() < FIRST_DATA_NONCE {
+			return Err("Not a data packet");
+		}
 
-		CryptoBox::decrypt(
-			encrypted_part,
-			&Nonce::Hers(self.header.nonce),
-			shared_secret)
+		Ok(CryptoAuthData {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn nonce(&self) -> u32 {
+		self.header.nonce.val()
 	}
 }
 
@@ -113,9 +242,77 @@ impl<'a> CryptoAuth<'a> {
 mod tests {
 	use super::*;
 	use std::mem::size_of;
-	
+	use identity::PublicKey;
+	use message::Message;
+
 	#[test]
 	fn test_sizeof() {
 		assert_eq!(size_of::<CryptoAuthHeader>(), CRYPTOAUTH_HEADER_LENGTH);
+		assert_eq!(size_of::<CryptoAuthDataHeader>(), CRYPTOAUTH_DATA_HEADER_LENGTH);
+		assert_eq!(HANDSHAKE_UNENCRYPTED_LENGTH, CRYPTOAUTH_HEADER_LENGTH - 16 - 32);
+		assert_eq!(DATA_UNENCRYPTED_LENGTH, CRYPTOAUTH_DATA_HEADER_LENGTH - 16);
+	}
+
+	#[test]
+	fn test_stage_detection() {
+		let mut buffer = [0u8; CRYPTOAUTH_HEADER_LENGTH];
+
+		for stage in range(0u8, 4) {
+			buffer[3] = stage;
+			match CryptoAuth::from_buffer(&buffer).unwrap() {
+				CryptoAuth::Handshake(packet) => assert_eq!(packet.stage() as u8, stage),
+				CryptoAuth::Data(..) => panic!("Handshake parsed as data")
+			}
+		}
+
+		buffer[3] = 4;
+		match CryptoAuth::from_buffer(&buffer).unwrap() {
+			CryptoAuth::Handshake(..) => panic!("Data parsed as handshake"),
+			CryptoAuth::Data(packet) => assert_eq!(packet.nonce(), 4)
+		}
+	}
+
+	#[test]
+	fn test_push_handshake_header() {
+		let challenge = Challenge::new(1, &[1, 2, 3, 4, 5, 6, 7]);
+		let public_key = PublicKey::from_buffer(&[0xAA; 32]);
+
+		let mut message = Message::from_slice(&[0xBB; 16 + 32 + 3]);
+		CryptoAuthHandshake::push_header(
+			&mut message, HandshakeStage::Key, &challenge, &[0xCC; 24], &public_key);
+		assert_eq!(message.len(), CRYPTOAUTH_HEADER_LENGTH + 3);
+		assert_eq!(&message.as_slice()[..12], [0, 0, 0, 2, 1, 1, 2, 3, 4, 5, 6, 7].as_slice());
+
+		match CryptoAuth::from_buffer(message.as_slice()).unwrap() {
+			CryptoAuth::Handshake(packet) => {
+				assert_eq!(packet.stage(), HandshakeStage::Key);
+				assert_eq!(*packet.challenge(), challenge);
+				assert_eq!(packet.public_key(), public_key);
+				assert_eq!(*packet.nonce(), [0xCC; 24]);
+				assert_eq!(packet.data, [0xBB, 0xBB, 0xBB].as_slice());
+			},
+			CryptoAuth::Data(..) => panic!("Handshake parsed as data")
+		}
+	}
+
+	#[test]
+	fn test_push_data_header() {
+		let mut message = Message::from_slice(&[0xBB; 16 + 2]);
+		CryptoAuthData::push_header(&mut message, 0x01020304);
+		assert_eq!(&message.as_slice()[..4], [1, 2, 3, 4].as_slice());
+
+		match CryptoAuth::from_buffer(message.as_slice()).unwrap() {
+			CryptoAuth::Handshake(..) => panic!("Data parsed as handshake"),
+			CryptoAuth::Data(packet) => {
+				assert_eq!(packet.nonce(), 0x01020304);
+				assert_eq!(packet.data, [0xBB, 0xBB].as_slice());
+			}
+		}
+	}
+
+	#[test]
+	fn test_too_short() {
+		assert!(CryptoAuth::from_buffer(&[0u8; CRYPTOAUTH_HEADER_LENGTH - 1]).is_err());
+		assert!(CryptoAuth::from_buffer(&[0, 0, 0, 4]).is_err());
 	}
 }
@@ -0,0 +1,147 @@
+use std::mem::size_of;
+use message::Message;
+use packet::{ParseResult, Packet, buffer_to_type};
+use util::{self, BigEndian};
+use Address;
+
+#[cfg(test)] pub const ICMPV6_HEADER_LENGTH: usize = 8;
+
+/// Next header value of ICMPv6 in an IPv6 header
+pub const ICMPV6_NEXT_HEADER: u8 = 58;
+
+/// Longest part of an undeliverable packet that is echoed back in an error,
+/// so the error fits into the IPv6 minimum MTU of 1280 bytes
+pub const MAX_INVOKING_PACKET_LENGTH: usize = 1280 - 40 - 8;
+
+const DESTINATION_UNREACHABLE: u8 = 1;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UnreachableCode {
+	NoRoute,
+	AddressUnreachable
+}
+
+impl UnreachableCode {
+	pub fn to_u8(&self) -> u8 {
+		match *self {
+			UnreachableCode::NoRoute => 0,
+			UnreachableCode::AddressUnreachable => 3
+		}
+	}
+}
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct Icmpv6Header {
+	icmp_type: u8,
+	code: u8,
+	checksum: BigEndian<u16>,
+	_unused: BigEndian<u32>
+}
+
+
+
+pub type Icmpv6<'a> = Packet<'a, Icmpv6Header, &'a [u8]>;
+
+impl<'a> Icmpv6<'a> {
+	/// Prepends a destination unreachable header to a message holding the
+	/// beginning of the undeliverable packet. The checksum covers the
+	/// addresses of the IPv6 packet the message is going to be sent in.
+	pub fn push_unreachable(message: &mut Message,
+	                        code: UnreachableCode,
+	                        source: &Address,
+	                        destination: &Address) {
+		assert!(message.len() <= MAX_INVOKING_PACKET_LENGTH);
+
+		message.push_u32(0);
+		message.push_u16(0);
+		message.push(&[DESTINATION_UNREACHABLE, code.to_u8()]);
+
+		let sum = checksum(message.as_slice(), source, destination);
+		message.pop(4);
+		message.push_u16(sum);
+		message.push(&[DESTINATION_UNREACHABLE, code.to_u8()]);
+	}
+
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<Icmpv6> {
+		let header: &Icmpv6Header = try!(buffer_to_type(buffer));
+		let data = &buffer[size_of::<Icmpv6Header>()..];
+
+		Ok(Icmpv6 {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn icmp_type(&self) -> u8 {
+		self.header.icmp_type
+	}
+
+	pub fn code(&self) -> u8 {
+		self.header.code
+	}
+
+	/// Error messages have types below 128, informational ones above
+	pub fn is_error(&self) -> bool {
+		self.header.icmp_type < 128
+	}
+
+	/// Whether the checksum is right for a packet between the addresses
+	pub fn is_valid(&self, source: &Address, destination: &Address) -> bool {
+		checksum(self.slice, source, destination) == 0
+	}
+}
+
+
+/// Checksum of an ICMPv6 message including the IPv6 pseudo header
+fn checksum(message: &[u8], source: &Address, destination: &Address) -> u16 {
+	let len = message.len() as u32;
+	let mut data = Vec::with_capacity(40 + message.len());
+	data.push_all(source.as_slice());
+	data.push_all(destination.as_slice());
+	data.push_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+	data.push_all(&[0, 0, 0, ICMPV6_NEXT_HEADER]);
+	data.push_all(message);
+	util::checksum(data.as_slice())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem::size_of;
+	use message::Message;
+	use Address;
+
+	#[test]
+	fn test_sizeof() {
+		assert_eq!(size_of::<Icmpv6Header>(), ICMPV6_HEADER_LENGTH);
+	}
+
+	#[test]
+	fn test_push_unreachable() {
+		let source = Address::from_string("fc00::1").unwrap();
+		let destination = Address::from_string("fc00::2").unwrap();
+
+		let mut message = Message::from_slice(&[0x60, 0x00, 0x00, 0x00, 0xAA]);
+		Icmpv6::push_unreachable(&mut message, UnreachableCode::AddressUnreachable, &source, &destination);
+		assert_eq!(message.len(), ICMPV6_HEADER_LENGTH + 5);
+		assert_eq!(&message.as_slice()[4..8], [0x00, 0x00, 0x00, 0x00].as_slice());
+
+		let icmp = Icmpv6::from_buffer(message.as_slice()).unwrap();
+		assert_eq!(icmp.icmp_type(), 1);
+		assert_eq!(icmp.code(), 3);
+		assert!(icmp.is_error());
+		assert!(icmp.is_valid(&source, &destination));
+		assert!(!icmp.is_valid(&source, &Address::from_string("fc00::3").unwrap()));
+		assert_eq!(icmp.data, [0x60, 0x00, 0x00, 0x00, 0xAA].as_slice());
+
+		assert!(Icmpv6::from_buffer(&[1, 0, 0]).is_err());
+	}
+}
@@ -1,10 +1,14 @@
 use std::mem::size_of;
 use Address;
+use message::Message;
 use packet::{ParseResult, Packet, buffer_to_type};
 use util::BigEndian;
 
 #[cfg(test)] pub const IPV6_HEADER_LENGTH: usize = 40;
 
+/// Hop limit of packets we create
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -53,10 +57,33 @@ impl<'a> IPv6<'a> {
 		})
 	}
 
+	/// Prepends an IPv6 header to a message holding the payload
+	pub fn push_header(message: &mut Message,
+	                   next_header: u8,
+	                   source: &Address,
+	                   destination: &Address) {
+		assert!(message.len() <= 0xFFFF);
+
+		let payload_length = message.len() as u16;
+		message.push(destination.as_slice());
+		message.push(source.as_slice());
+		message.push(&[next_header, DEFAULT_HOP_LIMIT]);
+		message.push_u16(payload_length);
+		message.push_u32(6 << 28);
+	}
+
 	pub fn get_data(&self) -> &'a [u8] {
 		self.data
 	}
 
+	pub fn get_next_header(&self) -> u8 {
+		self.header.next_header
+	}
+
+	pub fn get_source(&self) -> Option<Address> {
+		Address::from_slice(&self.header.source_addr)
+	}
+
 	pub fn get_destination(&self) -> Option<Address> {
 		Address::from_slice(&self.header.destination_addr)
 	}
@@ -68,9 +95,28 @@ impl<'a> IPv6<'a> {
 mod tests {
 	use super::*;
 	use std::mem::size_of;
+	use message::Message;
+	use Address;
 	
 	#[test]
 	fn test_sizeof() {
 		assert_eq!(size_of::<IPv6Header>(), IPV6_HEADER_LENGTH);
 	}
+
+	#[test]
+	fn test_push_header() {
+		let source = Address::from_string("fc00::1").unwrap();
+		let destination = Address::from_string("fc00::2").unwrap();
+
+		let mut message = Message::from_slice(&[0xAA, 0xBB]);
+		IPv6::push_header(&mut message, 58, &source, &destination);
+		assert_eq!(message.len(), IPV6_HEADER_LENGTH + 2);
+		assert_eq!(&message.as_slice()[..8], [0x60, 0x00, 0x00, 0x00, 0x00, 0x02, 58, 64].as_slice());
+
+		let packet = IPv6::from_buffer(message.as_slice()).unwrap();
+		assert_eq!(packet.get_source(), Some(source));
+		assert_eq!(packet.get_destination(), Some(destination));
+		assert_eq!(packet.get_next_header(), 58);
+		assert_eq!(packet.get_data(), [0xAA, 0xBB].as_slice());
+	}
 }
@@ -1,11 +1,37 @@
+pub use self::icmpv6::{
+	Icmpv6,
+	UnreachableCode,
+	ICMPV6_NEXT_HEADER,
+	MAX_INVOKING_PACKET_LENGTH};
 pub use self::ipv6::IPv6;
 pub use self::tun::Tun;
-pub use self::cryptoauth::CryptoAuth;
+pub use self::beacon::{Beacon, BeaconHeader, BEACON_PASSWORD_LENGTH};
+pub use self::cryptoauth::{
+	CryptoAuth,
+	CryptoAuthHandshake,
+	CryptoAuthData,
+	Challenge,
+	HandshakeStage,
+	HANDSHAKE_UNENCRYPTED_LENGTH,
+	DATA_UNENCRYPTED_LENGTH,
+	FIRST_DATA_NONCE};
+pub use self::control::{
+	Control,
+	ControlType,
+	ControlPing,
+	ControlKeyPing,
+	ControlError,
+	CONTROL_HANDLE};
+pub use self::switch::{Switch, SwitchHeader, SWITCH_HEADER_LENGTH};
 
 use std::mem;
 
+mod icmpv6;
 mod ipv6;
+mod beacon;
+mod control;
 mod cryptoauth;
+mod switch;
 mod tun;
 
 pub type ParseResult<P> = Result<P, &'static str>;
@@ -26,3 +52,11 @@ fn buffer_to_type<S>(buffer: &[u8]) -> ParseResult<&S> {
 		Ok(unsafe { mem::transmute(buffer.as_ptr()) })
 	}
 }
+
+fn buffer_to_type_mut<S>(buffer: &mut [u8]) -> ParseResult<&mut S> {
+	if buffer.len() < mem::size_of::<S>() {
+		Err("Buffer too short for conversion to type")
+	} else {
+		Ok(unsafe { mem::transmute(buffer.as_mut_ptr()) })
+	}
+}
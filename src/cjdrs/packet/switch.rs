@@ -0,0 +1,207 @@
+use std::mem::size_of;
+use message::Message;
+use packet::{ParseResult, Packet, buffer_to_type, buffer_to_type_mut};
+use util::BigEndian;
+
+pub const SWITCH_HEADER_LENGTH: usize = 12;
+
+pub const CURRENT_VERSION: u8 = 1;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(packed)]
+pub struct SwitchHeader {
+	label: BigEndian<u64>,
+	congestion_and_suppress_errors: u8,
+	version_and_label_shift: u8,
+	penalty: BigEndian<u16>
+}
+
+impl SwitchHeader {
+	pub fn new(label: u64) -> SwitchHeader {
+		let mut header = SwitchHeader {
+			label: BigEndian::new(label),
+			congestion_and_suppress_errors: 0,
+			version_and_label_shift: 0,
+			penalty: BigEndian::new(0)
+		};
+		header.set_version(CURRENT_VERSION);
+		header
+	}
+
+	pub fn from_buffer_mut(buffer: &mut [u8]) -> ParseResult<&mut SwitchHeader> {
+		buffer_to_type_mut(buffer)
+	}
+
+	pub fn label(&self) -> u64 {
+		self.label.val()
+	}
+
+	pub fn set_label(&mut self, label: u64) {
+		self.label.set(label);
+	}
+
+	/// Congestion level in range 0-127
+	pub fn congestion(&self) -> u8 {
+		self.congestion_and_suppress_errors >> 1
+	}
+
+	pub fn set_congestion(&mut self, congestion: u8) {
+		assert!(congestion <= 0x7F);
+		self.congestion_and_suppress_errors =
+			(congestion << 1) | (self.congestion_and_suppress_errors & 0x01);
+	}
+
+	/// If set, no error messages are sent back when the frame can't be delivered
+	pub fn suppress_errors(&self) -> bool {
+		self.congestion_and_suppress_errors & 0x01 != 0
+	}
+
+	pub fn set_suppress_errors(&mut self, suppress_errors: bool) {
+		self.congestion_and_suppress_errors =
+			(self.congestion_and_suppress_errors & 0xFE) | (suppress_errors as u8);
+	}
+
+	/// Switch protocol version in range 0-3
+	pub fn version(&self) -> u8 {
+		self.version_and_label_shift >> 6
+	}
+
+	pub fn set_version(&mut self, version: u8) {
+		assert!(version <= 0x03);
+		self.version_and_label_shift =
+			(version << 6) | (self.version_and_label_shift & 0x3F);
+	}
+
+	/// Number of bits the label has been shifted, in range 0-63
+	pub fn label_shift(&self) -> u8 {
+		self.version_and_label_shift & 0x3F
+	}
+
+	pub fn set_label_shift(&mut self, label_shift: u8) {
+		assert!(label_shift <= 0x3F);
+		self.version_and_label_shift =
+			(self.version_and_label_shift & 0xC0) | label_shift;
+	}
+
+	pub fn penalty(&self) -> u16 {
+		self.penalty.val()
+	}
+
+	pub fn set_penalty(&mut self, penalty: u16) {
+		self.penalty.set(penalty);
+	}
+
+	/// Prepends the header to a message
+	pub fn push_to(&self, message: &mut Message) {
+		message.push_u16(self.penalty());
+		message.push(&[self.congestion_and_suppress_errors, self.version_and_label_shift]);
+		message.push_u32(self.label() as u32);
+		message.push_u32((self.label() >> 32) as u32);
+	}
+}
+
+
+
+pub type Switch<'a> = Packet<'a, SwitchHeader, &'a [u8]>;
+
+impl<'a> Switch<'a> {
+	pub fn from_buffer(buffer: &[u8]) -> ParseResult<Switch> {
+		let header: &SwitchHeader = try!(buffer_to_type(buffer));
+		let data = &buffer[size_of::<SwitchHeader>()..];
+
+		Ok(Switch {
+			slice: buffer,
+			header: header,
+			data: data
+		})
+	}
+
+	pub fn get_data(&self) -> &'a [u8] {
+		self.data
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem::size_of;
+	use message::Message;
+
+	#[test]
+	fn test_sizeof() {
+		assert_eq!(size_of::<SwitchHeader>(), SWITCH_HEADER_LENGTH);
+	}
+
+	#[test]
+	fn test_parse() {
+		let buffer = [
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13,
+			0x40, 0x40, 0x00, 0x00,
+			0xAA, 0xBB];
+		let packet = Switch::from_buffer(&buffer).unwrap();
+		assert_eq!(packet.header.label(), 0x13);
+		assert_eq!(packet.header.congestion(), 0x20);
+		assert!(!packet.header.suppress_errors());
+		assert_eq!(packet.header.version(), 1);
+		assert_eq!(packet.header.label_shift(), 0);
+		assert_eq!(packet.header.penalty(), 0);
+		assert_eq!(packet.get_data(), [0xAA, 0xBB].as_slice());
+
+		let buffer = [
+			0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+			0x0B, 0x87, 0x12, 0x34];
+		let packet = Switch::from_buffer(&buffer).unwrap();
+		assert_eq!(packet.header.label(), 0x0123456789ABCDEF);
+		assert_eq!(packet.header.congestion(), 5);
+		assert!(packet.header.suppress_errors());
+		assert_eq!(packet.header.version(), 2);
+		assert_eq!(packet.header.label_shift(), 7);
+		assert_eq!(packet.header.penalty(), 0x1234);
+		assert_eq!(packet.get_data(), [].as_slice());
+
+		assert!(Switch::from_buffer(&buffer[..11]).is_err());
+	}
+
+	#[test]
+	fn test_write() {
+		let mut header = SwitchHeader::new(0x0000000000000013);
+		let mut message = Message::from_slice(&[0xAA]);
+		header.push_to(&mut message);
+		assert_eq!(message.as_slice(), [
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13,
+			0x00, 0x40, 0x00, 0x00,
+			0xAA].as_slice());
+
+		header.set_label(0x0123456789ABCDEF);
+		header.set_congestion(5);
+		header.set_suppress_errors(true);
+		header.set_version(2);
+		header.set_label_shift(7);
+		header.set_penalty(0x1234);
+		let mut message = Message::from_slice(&[]);
+		header.push_to(&mut message);
+		assert_eq!(message.as_slice(), [
+			0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
+			0x0B, 0x87, 0x12, 0x34].as_slice());
+	}
+
+	#[test]
+	fn test_modify_in_place() {
+		let mut buffer = [
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13,
+			0x00, 0x40, 0x00, 0x00];
+		{
+			let header = SwitchHeader::from_buffer_mut(&mut buffer).unwrap();
+			header.set_label(0x0000000000000001);
+			header.set_suppress_errors(true);
+			header.set_label_shift(4);
+		}
+		assert_eq!(buffer, [
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+			0x01, 0x44, 0x00, 0x00]);
+	}
+}
@@ -1,10 +1,13 @@
 use std::mem::size_of;
+use message::Message;
 use packet::{ParseResult, Packet, buffer_to_type};
 use packet;
 use util::BigEndian;
 
 #[cfg(test)] pub const TUN_HEADER_LENGTH: usize = 4;
 
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -16,7 +19,7 @@ pub struct TunHeader {
 
 impl TunHeader {
 	fn is_ipv6(&self) -> bool {
-		self.protocol_type.val() == 0x86DD
+		self.protocol_type.val() == ETHERTYPE_IPV6
 	}
 }
 
@@ -25,6 +28,12 @@ impl TunHeader {
 pub type Tun<'a> = Packet<'a, TunHeader, packet::IPv6<'a>>;
 
 impl<'a> Tun<'a> {
+	/// Prepends a tun header to a message holding an IPv6 packet
+	pub fn push_header(message: &mut Message) {
+		message.push_u16(ETHERTYPE_IPV6);
+		message.push_u16(0);
+	}
+
 	pub fn from_buffer(buffer: &[u8]) -> ParseResult<Tun> {
 		let header: &TunHeader = try!(buffer_to_type(buffer));
 
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use crypto::{self, PasswordHash};
+use packet::Challenge;
+use Address;
+use CjdrsError;
+use CjdrsResult;
+use Config;
+use PublicKey;
+
+
+/// Password hashes are looked up with the hash of the password itself.
+pub const AUTH_TYPE_PASSWORD: u8 = 1;
+
+/// Password hashes are looked up with the hash of the login name.
+pub const AUTH_TYPE_LOGIN: u8 = 2;
+
+
+
+#[derive(Debug, Clone)]
+pub struct AuthorizedPassword {
+	user: String,
+	password_hash: PasswordHash,
+	restrict_to: Option<Address>
+}
+
+impl AuthorizedPassword {
+	pub fn user(&self) -> &str {
+		self.user.as_slice()
+	}
+
+	pub fn password_hash(&self) -> &PasswordHash {
+		&self.password_hash
+	}
+
+	/// Whether a peer with the given key may use this password
+	pub fn allows(&self, public_key: &PublicKey) -> bool {
+		match self.restrict_to {
+			Some(ref address) => Address::from_public_key(public_key) == Some(*address),
+			None => true
+		}
+	}
+}
+
+
+
+/// Passwords that peers may use to connect to us, indexed by the auth type
+/// and lookup bytes they send in the handshake challenge.
+#[derive(Debug)]
+pub struct PasswordStore {
+	passwords: HashMap<(u8, [u8; 7]), AuthorizedPassword>
+}
+
+impl PasswordStore {
+	pub fn new() -> PasswordStore {
+		PasswordStore { passwords: HashMap::new() }
+	}
+
+	pub fn from_config(config: &Config) -> CjdrsResult<PasswordStore> {
+		let mut store = PasswordStore::new();
+
+		for entry in config.authorizedPasswords.iter() {
+			let restrict_to = match entry.ipv6 {
+				Some(ref ipv6) => match Address::from_string(ipv6.as_slice()) {
+					Some(address) => Some(address),
+					None => fail!(CjdrsError::InvalidAddress(ipv6.clone()))
+				},
+				None => None
+			};
+
+			try!(store.add(entry.user.as_slice(), entry.password.as_slice(), restrict_to));
+		}
+
+		Ok(store)
+	}
+
+	/// Adds the credentials of a user. Passwords and user names have to be
+	/// unique, peers only tell us which one they use by its hash.
+	pub fn add(&mut self, user: &str, password: &str, restrict_to: Option<Address>) -> CjdrsResult<()> {
+		let password_hash = PasswordHash::from_password(password);
+		let password_key = (AUTH_TYPE_PASSWORD, password_hash.lookup());
+		let login_key = (AUTH_TYPE_LOGIN, crypto::login_lookup(user));
+		if self.passwords.contains_key(&password_key) || self.passwords.contains_key(&login_key) {
+			fail!(CjdrsError::DuplicatePassword(user.to_string()));
+		}
+
+		let authorized = AuthorizedPassword {
+			user: user.to_string(),
+			password_hash: password_hash,
+			restrict_to: restrict_to
+		};
+
+		self.passwords.insert(password_key, authorized.clone());
+		self.passwords.insert(login_key, authorized);
+		Ok(())
+	}
+
+	/// Revokes all credentials of a user
+	pub fn remove(&mut self, user: &str) {
+		let keys: Vec<(u8, [u8; 7])> = self.passwords.iter()
+			.filter(|&(_, p)| p.user() == user)
+			.map(|(k, _)| *k)
+			.collect();
+
+		for key in keys.iter() {
+			self.passwords.remove(key);
+		}
+	}
+
+	pub fn lookup(&self, challenge: &Challenge) -> Option<&AuthorizedPassword> {
+		match challenge.challenge_type() {
+			AUTH_TYPE_PASSWORD | AUTH_TYPE_LOGIN =>
+				self.passwords.get(&(challenge.challenge_type(), *challenge.lookup())),
+			_ => None
+		}
+	}
+
+	/// Number of users that have credentials
+	pub fn len(&self) -> usize {
+		self.passwords.keys().filter(|&&(auth_type, _)| auth_type == AUTH_TYPE_LOGIN).count()
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{PasswordStore, AUTH_TYPE_PASSWORD, AUTH_TYPE_LOGIN};
+	use crypto::{self, PasswordHash};
+	use packet::Challenge;
+	use PrivateIdentity;
+
+	#[test]
+	fn test_lookup() {
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", None).unwrap();
+		store.add("bob", "second password", None).unwrap();
+		assert_eq!(store.len(), 2);
+
+		let hash = PasswordHash::from_password("second password");
+		let challenge = Challenge::new(AUTH_TYPE_PASSWORD, &hash.lookup());
+		let found = store.lookup(&challenge).unwrap();
+		assert_eq!(found.user(), "bob");
+		assert_eq!(*found.password_hash(), hash);
+	}
+
+	#[test]
+	fn test_lookup_login() {
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", None).unwrap();
+		store.add("bob", "second password", None).unwrap();
+
+		let challenge = Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup("alice"));
+		let found = store.lookup(&challenge).unwrap();
+		assert_eq!(found.user(), "alice");
+		assert_eq!(*found.password_hash(), PasswordHash::from_password("first password"));
+
+		let challenge = Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup("carol"));
+		assert!(store.lookup(&challenge).is_none());
+	}
+
+	#[test]
+	fn test_unknown_lookup() {
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", None).unwrap();
+
+		let hash = PasswordHash::from_password("not configured");
+		assert!(store.lookup(&Challenge::new(AUTH_TYPE_PASSWORD, &hash.lookup())).is_none());
+
+		let hash = PasswordHash::from_password("first password");
+		assert!(store.lookup(&Challenge::new(0, &hash.lookup())).is_none());
+	}
+
+	#[test]
+	fn test_remove() {
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", None).unwrap();
+		store.add("bob", "second password", None).unwrap();
+
+		store.remove("alice");
+		assert_eq!(store.len(), 1);
+
+		let hash = PasswordHash::from_password("first password");
+		assert!(store.lookup(&Challenge::new(AUTH_TYPE_PASSWORD, &hash.lookup())).is_none());
+		assert!(store.lookup(&Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup("alice"))).is_none());
+		assert!(store.lookup(&Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup("bob"))).is_some());
+	}
+
+	#[test]
+	fn test_ipv6_restriction() {
+		let allowed = PrivateIdentity::generate();
+		let other = PrivateIdentity::generate();
+
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", Some(allowed.address)).unwrap();
+
+		let challenge = Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup("alice"));
+		let found = store.lookup(&challenge).unwrap();
+		assert!(found.allows(&allowed.public_key));
+		assert!(!found.allows(&other.public_key));
+	}
+
+	#[test]
+	fn test_duplicates() {
+		let mut store = PasswordStore::new();
+		store.add("alice", "first password", None).unwrap();
+
+		assert!(store.add("bob", "first password", None).is_err());
+		assert!(store.add("alice", "second password", None).is_err());
+		assert_eq!(store.len(), 1);
+
+		let hash = PasswordHash::from_password("first password");
+		let found = store.lookup(&Challenge::new(AUTH_TYPE_PASSWORD, &hash.lookup())).unwrap();
+		assert_eq!(found.user(), "alice");
+	}
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use Address;
+
+
+/// Packets held for a single destination
+pub const MAX_PACKETS_PER_DESTINATION: usize = 16;
+
+/// Destinations packets are held for at the same time
+pub const MAX_DESTINATIONS: usize = 64;
+
+/// Seconds a packet is held before it's given up on
+pub const MAX_PACKET_AGE: u64 = 20;
+
+
+
+/// An IPv6 packet from the tun device
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PendingPacket {
+	/// Device the packet came from, which errors are sent back to
+	pub device_idx: usize,
+	pub data: Vec<u8>,
+	pub queued: u64
+}
+
+
+
+/// Packets to destinations that can't be reached yet, held until there is a
+/// route and a session to send them along.
+#[derive(Debug)]
+pub struct PendingQueue {
+	queues: HashMap<Address, Vec<PendingPacket>>
+}
+
+impl PendingQueue {
+	pub fn new() -> PendingQueue {
+		PendingQueue {
+			queues: HashMap::new()
+		}
+	}
+
+	/// Number of packets held
+	pub fn len(&self) -> usize {
+		self.queues.values().fold(0, |len, q| len + q.len())
+	}
+
+	/// Whether packets are held for the destination
+	pub fn contains(&self, destination: &Address) -> bool {
+		self.queues.contains_key(destination)
+	}
+
+	pub fn destinations(&self) -> Vec<Address> {
+		self.queues.keys().map(|a| *a).collect()
+	}
+
+	/// Holds a packet for the destination. Returns it if the destination's
+	/// queue is full or too many destinations are waiting already.
+	pub fn push(&mut self, destination: &Address, packet: PendingPacket) -> Result<(), PendingPacket> {
+		if !self.queues.contains_key(destination) {
+			if self.queues.len() >= MAX_DESTINATIONS {
+				return Err(packet);
+			}
+			self.queues.insert(*destination, vec![]);
+		}
+
+		let queue = match self.queues.get_mut(destination) {
+			Some(q) => q,
+			None => unreachable!()
+		};
+		if queue.len() >= MAX_PACKETS_PER_DESTINATION {
+			return Err(packet);
+		}
+		queue.push(packet);
+		Ok(())
+	}
+
+	/// Removes and returns the packets for the destination, oldest first
+	pub fn take(&mut self, destination: &Address) -> Vec<PendingPacket> {
+		self.queues.remove(destination).unwrap_or(vec![])
+	}
+
+	/// Removes and returns the packets held for too long
+	pub fn expire(&mut self, now: u64) -> Vec<PendingPacket> {
+		let mut expired = vec![];
+		for queue in self.queues.values_mut() {
+			while !queue.is_empty() && now >= queue[0].queued + MAX_PACKET_AGE {
+				expired.push(queue.remove(0));
+			}
+		}
+
+		let empty: Vec<Address> = self.queues.iter()
+			.filter(|&(_, q)| q.is_empty())
+			.map(|(a, _)| *a)
+			.collect();
+		for address in empty.iter() {
+			self.queues.remove(address);
+		}
+		expired
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		PendingQueue,
+		PendingPacket,
+		MAX_PACKETS_PER_DESTINATION,
+		MAX_DESTINATIONS,
+		MAX_PACKET_AGE};
+	use Address;
+
+	fn packet(data: u8, queued: u64) -> PendingPacket {
+		PendingPacket { device_idx: 0, data: vec![data], queued: queued }
+	}
+
+	#[test]
+	fn test_push_take() {
+		let mut queue = PendingQueue::new();
+		let destination = Address::from_string("fc00::1").unwrap();
+
+		assert!(queue.push(&destination, packet(1, 0)).is_ok());
+		assert!(queue.push(&destination, packet(2, 0)).is_ok());
+		assert!(queue.contains(&destination));
+		assert_eq!(queue.len(), 2);
+		assert_eq!(queue.destinations(), vec![destination]);
+
+		let packets = queue.take(&destination);
+		assert_eq!(packets, vec![packet(1, 0), packet(2, 0)]);
+		assert!(!queue.contains(&destination));
+		assert!(queue.take(&destination).is_empty());
+	}
+
+	#[test]
+	fn test_limits() {
+		let mut queue = PendingQueue::new();
+		let destination = Address::from_string("fc00::1").unwrap();
+
+		for i in range(0, MAX_PACKETS_PER_DESTINATION) {
+			assert!(queue.push(&destination, packet(i as u8, 0)).is_ok());
+		}
+		assert_eq!(queue.push(&destination, packet(0xFF, 0)), Err(packet(0xFF, 0)));
+
+		for i in range(1, MAX_DESTINATIONS) {
+			let address = Address::from_string(format!("fc00::1:{:x}", i).as_slice()).unwrap();
+			assert!(queue.push(&address, packet(0, 0)).is_ok());
+		}
+		let address = Address::from_string("fc00::2:0").unwrap();
+		assert!(queue.push(&address, packet(0, 0)).is_err());
+
+		// Known destinations still have room
+		let address = Address::from_string("fc00::1:1").unwrap();
+		assert!(queue.push(&address, packet(1, 0)).is_ok());
+	}
+
+	#[test]
+	fn test_expire() {
+		let mut queue = PendingQueue::new();
+		let first = Address::from_string("fc00::1").unwrap();
+		let second = Address::from_string("fc00::2").unwrap();
+		queue.push(&first, packet(1, 10)).unwrap();
+		queue.push(&first, packet(2, 15)).unwrap();
+		queue.push(&second, packet(3, 10)).unwrap();
+
+		assert!(queue.expire(10 + MAX_PACKET_AGE - 1).is_empty());
+
+		let mut expired = queue.expire(10 + MAX_PACKET_AGE);
+		expired.sort_by(|a, b| a.data.cmp(&b.data));
+		assert_eq!(expired, vec![packet(1, 10), packet(3, 10)]);
+		assert_eq!(queue.destinations(), vec![first]);
+		assert_eq!(queue.take(&first), vec![packet(2, 15)]);
+	}
+}
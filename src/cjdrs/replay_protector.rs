@@ -0,0 +1,169 @@
+use std::num::Int;
+
+const WINDOW_SIZE: u32 = 64;
+
+
+/// Sliding window over the most recently received data packet nonces.
+/// Bit `n` of the window tells whether nonce `highest - n` has been seen.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReplayProtector {
+	highest: u32,
+	window: u64,
+
+	duplicates: u32,
+	lost_packets: u32,
+	received_out_of_range: u32
+}
+
+impl ReplayProtector {
+	/// Creates a replay protector for a stream whose first nonce is `first_nonce`
+	pub fn new(first_nonce: u32) -> ReplayProtector {
+		assert!(first_nonce > 0);
+
+		ReplayProtector {
+			highest: first_nonce - 1,
+			window: !0,
+			duplicates: 0,
+			lost_packets: 0,
+			received_out_of_range: 0
+		}
+	}
+
+	/// Checks a nonce of an authenticated packet and marks it as seen.
+	/// Returns false if the packet should be dropped.
+	pub fn check_nonce(&mut self, nonce: u32) -> bool {
+		if nonce > self.highest {
+			self.advance(nonce - self.highest);
+			self.highest = nonce;
+			self.window |= 1;
+			return true;
+		}
+
+		let offset = self.highest - nonce;
+		if offset >= WINDOW_SIZE {
+			self.received_out_of_range = self.received_out_of_range.saturating_add(1);
+			return false;
+		}
+
+		let bit = 1u64 << offset as usize;
+		if self.window & bit != 0 {
+			self.duplicates = self.duplicates.saturating_add(1);
+			false
+		} else {
+			self.window |= bit;
+			true
+		}
+	}
+
+	fn advance(&mut self, shift: u32) {
+		if shift >= WINDOW_SIZE {
+			// Every unseen nonce in the window and every nonce skipped over
+			// is lost
+			self.lost_packets = self.lost_packets
+				.saturating_add(self.window.count_zeros())
+				.saturating_add(shift - WINDOW_SIZE);
+			self.window = 0;
+		} else {
+			let dropped = self.window >> (WINDOW_SIZE - shift) as usize;
+			self.lost_packets = self.lost_packets.saturating_add(shift - dropped.count_ones());
+			self.window <<= shift as usize;
+		}
+	}
+
+	/// Packets that were received more than once
+	pub fn duplicates(&self) -> u32 {
+		self.duplicates
+	}
+
+	/// Packets that left the window without ever being received
+	pub fn lost_packets(&self) -> u32 {
+		self.lost_packets
+	}
+
+	/// Packets that arrived too late to be checked against the window
+	pub fn received_out_of_range(&self) -> u32 {
+		self.received_out_of_range
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::ReplayProtector;
+
+	#[test]
+	fn test_in_order() {
+		let mut rp = ReplayProtector::new(4);
+		for nonce in range(4u32, 1000) {
+			assert!(rp.check_nonce(nonce));
+		}
+		assert_eq!(rp.duplicates(), 0);
+		assert_eq!(rp.lost_packets(), 0);
+		assert_eq!(rp.received_out_of_range(), 0);
+	}
+
+	#[test]
+	fn test_reordered() {
+		let mut rp = ReplayProtector::new(4);
+		for &nonce in [5, 4, 7, 6, 10, 8, 9, 70, 11, 12].iter() {
+			assert!(rp.check_nonce(nonce));
+		}
+		assert_eq!(rp.duplicates(), 0);
+		assert_eq!(rp.lost_packets(), 0);
+		assert_eq!(rp.received_out_of_range(), 0);
+	}
+
+	#[test]
+	fn test_replayed() {
+		let mut rp = ReplayProtector::new(4);
+		assert!(rp.check_nonce(4));
+		assert!(rp.check_nonce(5));
+		assert!(!rp.check_nonce(4));
+		assert!(!rp.check_nonce(5));
+		assert!(rp.check_nonce(20));
+		assert!(!rp.check_nonce(20));
+		assert!(rp.check_nonce(6));
+		assert!(!rp.check_nonce(6));
+		assert_eq!(rp.duplicates(), 4);
+		assert_eq!(rp.received_out_of_range(), 0);
+	}
+
+	#[test]
+	fn test_before_first_nonce() {
+		let mut rp = ReplayProtector::new(4);
+		assert!(!rp.check_nonce(3));
+		assert!(!rp.check_nonce(0));
+		assert!(rp.check_nonce(4));
+	}
+
+	#[test]
+	fn test_out_of_range() {
+		let mut rp = ReplayProtector::new(4);
+		assert!(rp.check_nonce(1000));
+		assert!(!rp.check_nonce(5));
+		assert!(!rp.check_nonce(1000 - 64));
+		assert!(rp.check_nonce(1000 - 63));
+		assert_eq!(rp.received_out_of_range(), 2);
+		assert_eq!(rp.duplicates(), 0);
+	}
+
+	#[test]
+	fn test_lost() {
+		let mut rp = ReplayProtector::new(4);
+		assert!(rp.check_nonce(4));
+		assert!(rp.check_nonce(6));
+		assert!(rp.check_nonce(8));
+		assert_eq!(rp.lost_packets(), 0);
+
+		// Nonces 5 and 7 fall out of the window, 9 to 71 may still arrive
+		assert!(rp.check_nonce(8 + 64));
+		assert_eq!(rp.lost_packets(), 2);
+		assert!(rp.check_nonce(9));
+
+		// A large jump loses everything that doesn't fit in the window
+		let mut rp = ReplayProtector::new(4);
+		assert!(rp.check_nonce(4 + 200));
+		assert_eq!(rp.lost_packets(), 200 - 63);
+	}
+}
@@ -1,6 +1,13 @@
 use std::num::Int;
 use std::fmt;
 use std::u64;
+use encoding_scheme::{EncodingScheme, FormList};
+
+
+/// Longest label cjdns nodes splice together, which keeps the top four bits
+/// of a label free
+pub const MAX_ROUTE_LEN: u8 = 60;
+
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Route {
@@ -17,7 +24,10 @@ impl Route {
 	/// Splice
 	#[inline]
 	pub fn combine(&self, other: &Route) -> Option<Route> {
-		if (self.bit_len() - 1) + (self.bit_len() - 1) > 59 { // TODO Why 59?
+		if self.bits == 0 || other.bits == 0 {
+			return None
+		}
+		if (self.bit_len() - 1) + other.bit_len() > MAX_ROUTE_LEN {
 			// Route too long
 			return None
 		}
@@ -26,6 +36,25 @@ impl Route {
 		Some(Route { bits: combined_route })
 	}
 
+	/// AB + BC = AC, where BC is written in the encoding scheme of B. The
+	/// switch at B replaces the director towards C with the return path
+	/// towards A, which is written in form `return_form` of B's scheme, so the
+	/// director towards C gets widened to that form if it's narrower.
+	pub fn extend(&self, other: &Route, scheme: &FormList, return_form: usize) -> Option<Route> {
+		let other_form = match scheme.form_num(other.bits) {
+			Some(f) => f,
+			None => return None
+		};
+
+		if return_form <= other_form {
+			return self.combine(other);
+		}
+		match scheme.convert_label(other.bits, return_form) {
+			Some(bits) => self.combine(&Route::new(bits)),
+			None => None
+		}
+	}
+
 	/// AC - AB = BC
 	/// Unsplice
 	#[inline]
@@ -48,6 +77,60 @@ impl Route {
 		}
 	}
 
+	/// Parses a label in the dotted hex notation cjdns uses, such as
+	/// `0000.0000.0000.0013`.
+	pub fn from_string(string: &str) -> Option<Route> {
+		let groups: Vec<&str> = string.split('.').collect();
+		if groups.len() != 4 {
+			return None;
+		}
+
+		let mut bits = 0u64;
+		for group in groups.iter() {
+			if group.len() != 4 {
+				return None;
+			}
+			match u16::from_str_radix(*group, 16) {
+				Ok(g) => bits = bits << 16 | g as u64,
+				Err(..) => return None
+			}
+		}
+		Some(Route::new(bits))
+	}
+
+	/// Splits the label into the interface numbers chosen at every hop, using
+	/// the encoding scheme of each node along the way. Returns None if the
+	/// label isn't valid in the schemes or there are more hops than schemes.
+	pub fn hops(&self, schemes: &[&EncodingScheme]) -> Option<Vec<u32>> {
+		let mut bits = self.bits;
+		let mut hops = vec![];
+
+		for scheme in schemes.iter() {
+			if bits == 1 {
+				break;
+			}
+
+			let (len, number) = match (scheme.bits_used_for_label(bits), scheme.decompress(bits)) {
+				(Some(l), Some(n)) => (l, n),
+				_ => return None
+			};
+			if len as usize >= 64 || bits >> len as usize == 0 {
+				// The director ran into the end of the label
+				return None;
+			}
+
+			hops.push(number);
+			bits >>= len as usize;
+		}
+
+		if bits == 1 { Some(hops) } else { None }
+	}
+
+	#[inline]
+	pub fn bits(&self) -> u64 {
+		self.bits
+	}
+
 	#[inline]
 	pub fn bit_len(&self) -> u8 {
 		64 - self.bits.leading_zeros() as u8
@@ -56,7 +139,11 @@ impl Route {
 
 impl fmt::Display for Route {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{:064b}", self.bits)
+		write!(f, "{:04x}.{:04x}.{:04x}.{:04x}",
+			(self.bits >> 48) as u16,
+			(self.bits >> 32) as u16,
+			(self.bits >> 16) as u16,
+			(self.bits      ) as u16)
 	}
 }
 
@@ -71,6 +158,7 @@ impl fmt::Debug for Route {
 #[cfg(test)]
 mod tests {
 	use route::Route;
+	use encoding_scheme::{EncodingScheme, Fixed4, Variable3x5x8, Variable4x8};
 
 	#[test]
 	fn test_combine() {
@@ -78,6 +166,49 @@ mod tests {
 		let bc = Route::new(0b0000000000000000000000000000000000000000000000000000110101010100);
 		let ac = Route::new(0b0000000000000000000000000000000000110101010100011101110101011001);
 		assert_eq!(ab.combine(&bc).unwrap(), ac);
+		assert_eq!(ac.get_end(&ab), bc);
+		assert!(ac.goes_through(&ab));
+		assert!(!ac.goes_through(&bc));
+	}
+
+	#[test]
+	fn test_combine_length() {
+		// 59 bits of route plus one hop of four bits
+		let long = Route::new(1 << 58 | 0b0101);
+		let hop = Route::new(0b1_0011);
+		assert_eq!(long.combine(&hop), None);
+		assert_eq!(hop.combine(&long), None);
+
+		let fits = Route::new(1 << 55 | 0b0101);
+		let combined = fits.combine(&hop).unwrap();
+		assert_eq!(combined.bit_len(), 60);
+		assert_eq!(combined.get_end(&fits), hop);
+		assert_eq!(hop.combine(&fits).unwrap().bit_len(), 60);
+
+		assert_eq!(Route::new(0).combine(&hop), None);
+		assert_eq!(hop.combine(&Route::new(0)), None);
+	}
+
+	#[test]
+	fn test_extend() {
+		let scheme = Variable3x5x8.form_list().unwrap();
+		let ab = Route::new(0b1_0101);
+		let bc = Route::new(0b1_0111);
+
+		// Return path as narrow as the next hop
+		assert_eq!(ab.extend(&bc, &scheme, 0), ab.combine(&bc));
+
+		// The switch at B needs room for a five bit return path
+		let ac = ab.extend(&bc, &scheme, 1).unwrap();
+		assert_eq!(ac, Route::new(0b1_0001010_0101));
+		assert_eq!(ac.get_end(&ab), Route::new(0b1_0001010));
+
+		// Interface 33 of B doesn't fit into three bits, but is already wider
+		let bc = Route::new(0b1_00100000_00);
+		assert_eq!(ab.extend(&bc, &scheme, 0), ab.combine(&bc));
+
+		assert_eq!(ab.extend(&Route::new(0b0001), &scheme, 1), None);
+		assert_eq!(Route::new(1 << 55 | 0b0101).extend(&bc, &scheme, 0), None);
 	}
 
 
@@ -93,4 +224,45 @@ mod tests {
 		assert_eq!(Route::new(0b1111111111111111111111111111111111111111111111111111111111111111).bit_len(), 64);
 		assert_eq!(Route::new(0b0000000000000000000000000000000000000000000001011101110101011001).bit_len(), 19);
 	}
+
+	#[test]
+	fn test_to_from_string() {
+		let route = Route::new(0x0000_0000_0000_0013);
+		assert_eq!(route.to_string(), "0000.0000.0000.0013");
+		assert_eq!(Route::from_string("0000.0000.0000.0013"), Some(route));
+
+		let route = Route::new(0xfedc_ba98_7654_3210);
+		assert_eq!(route.to_string(), "fedc.ba98.7654.3210");
+		assert_eq!(Route::from_string("FEDC.BA98.7654.3210"), Some(route));
+
+		assert_eq!(Route::from_string("0000.0000.0013"), None);
+		assert_eq!(Route::from_string("0000.0000.0000.013"), None);
+		assert_eq!(Route::from_string("0000.0000.0000.00013"), None);
+		assert_eq!(Route::from_string("0000.0000.0000.001g"), None);
+		assert_eq!(Route::from_string("0000.0000.0000.0013."), None);
+	}
+
+	#[test]
+	fn test_hops() {
+		let v358 = Variable3x5x8;
+		let v48 = Variable4x8;
+		let fixed4 = Fixed4;
+
+		// Interface 2 in v3x5x8, then 3 in v4x8
+		let route = Route::new(0b1_00111_0101);
+		assert_eq!(route.hops(&[&v358 as &EncodingScheme, &v48]), Some(vec![2, 3]));
+
+		// Interface 33 in the ten bit form, then 5 in fixed4
+		let route = Route::new(0b1_0101_00100000_00);
+		assert_eq!(route.hops(&[&v358 as &EncodingScheme, &fixed4]), Some(vec![33, 5]));
+
+		assert_eq!(Route::new(1).hops(&[]), Some(vec![]));
+
+		// Not enough schemes
+		assert_eq!(route.hops(&[&v358 as &EncodingScheme]), None);
+
+		// No terminating bit after the last director
+		assert_eq!(Route::new(0b0101).hops(&[&v358 as &EncodingScheme]), None);
+		assert_eq!(Route::new(0).hops(&[&v358 as &EncodingScheme]), None);
+	}
 }
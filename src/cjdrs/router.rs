@@ -1,23 +1,332 @@
-use std::collections::HashMap;
+use std::num::Int;
+use crypto;
+use dht::{DhtMessage, DhtQuery, DhtBody, NodeEntry};
+use encoding_scheme::{EncodingScheme, FormList, Variable3x5x8};
+use node_store::BUCKET_SIZE;
+use search::{Search, SearchResult, SEARCH_WIDTH};
 use Address;
+use Node;
+use NodeStore;
+use PublicKey;
 use Route;
+use PROTOCOL_VERSION;
+
+
+/// Reach of nodes we have a direct session with
+const PEER_REACH: u32 = 1000;
+
+
 
 #[derive(Debug)]
 pub struct Router {
-	node_store: HashMap<Address, Route>
+	own_address: Address,
+	node_store: NodeStore,
+	searches: Vec<Search>
 }
 
 impl Router {
 	pub fn new(own_ip: &Address) -> Router {
-		let mut router = Router { node_store: HashMap::new() };
-		router.node_store.insert(own_ip.clone(), Route::new(0b1));
-		router
+		Router {
+			own_address: *own_ip,
+			node_store: NodeStore::new(own_ip),
+			searches: vec![]
+		}
+	}
+
+	pub fn node_store(&self) -> &NodeStore {
+		&self.node_store
+	}
+
+	pub fn node_store_mut(&mut self) -> &mut NodeStore {
+		&mut self.node_store
+	}
+
+	/// Route to a known node, the self route for our own address
+	pub fn get_route(&self, address: &Address) -> Option<Route> {
+		if *address == self.own_address {
+			return Some(Route::new(0b1));
+		}
+		self.node_store.get(address).map(|n| n.route)
+	}
+
+	/// Stores a node we just established a session with, along with its
+	/// encoding scheme if it can be written as forms
+	pub fn add_peer(&mut self,
+	                public_key: &PublicKey,
+	                address: &Address,
+	                route: Route,
+	                scheme: Option<FormList>,
+	                now: u64) {
+		let node = Node {
+			public_key: *public_key,
+			address: *address,
+			route: route,
+			scheme: scheme.unwrap_or_else(|| default_scheme()),
+			version: 0,
+			reach: PEER_REACH,
+			last_seen: now
+		};
+		self.node_store.add(node);
+	}
+
+	/// Starts looking for an address by asking the closest nodes we know.
+	/// Returns false if there's nobody to ask or a search is already running.
+	pub fn search(&mut self, target: &Address, now: u64) -> bool {
+		if self.searches.iter().any(|s| s.target() == target) {
+			return false;
+		}
+
+		let nodes: Vec<(Address, NodeEntry)> = self.node_store.closest(target, SEARCH_WIDTH).iter()
+			.map(|n| (n.address, node_entry(*n)))
+			.collect();
+		if nodes.is_empty() {
+			return false;
+		}
+
+		self.searches.push(Search::new(target, nodes, now));
+		true
+	}
+
+	pub fn is_searching(&self, target: &Address) -> bool {
+		self.searches.iter().any(|s| s.target() == target)
+	}
+
+	/// Expires unanswered queries and returns the next queries of all
+	/// searches with the routes to send them along
+	pub fn search_queries(&mut self, now: u64) -> Vec<(Route, DhtMessage)> {
+		let mut queries = vec![];
+
+		for search in self.searches.iter_mut() {
+			for address in search.expire(now).iter() {
+				if let Some(node) = self.node_store.get_mut(address) {
+					node.reach /= 2;
+				}
+			}
+
+			for (txid, node) in search.next_queries(now, || random_txid()).into_iter() {
+				let query = DhtQuery::FindNode(*search.target());
+				queries.push((node.label, DhtMessage::query(txid_bytes(txid), PROTOCOL_VERSION, query)));
+			}
+		}
+		queries
+	}
+
+	/// Removes the searches that are over and returns their targets with the
+	/// route if they were found. Found routes replace the ones in the node
+	/// store.
+	pub fn finish_searches(&mut self, now: u64) -> Vec<(Address, Option<Route>)> {
+		let mut finished = vec![];
+		let mut i = 0;
+		while i < self.searches.len() {
+			let found = match self.searches[i].result(now) {
+				SearchResult::Running => {
+					i += 1;
+					continue;
+				},
+				SearchResult::Found(node) => Some(node),
+				SearchResult::Failed => None
+			};
+			let search = self.searches.swap_remove(i);
+			if let Some(ref node) = found {
+				self.store_found(search.target(), node, now);
+			}
+			finished.push((*search.target(), found.map(|n| n.label)));
+		}
+		finished
+	}
+
+	/// Nodes we know closest to the target of a find node query, with the
+	/// routes from us
+	pub fn find_node(&self, target: &Address) -> Vec<NodeEntry> {
+		self.node_store.closest(target, BUCKET_SIZE).iter().map(|n| node_entry(*n)).collect()
+	}
+
+	/// Passes the nodes in a reply on to the search that sent the query and
+	/// learns the node that answered and the nodes it knows. Replies that
+	/// didn't come back along the route the query was sent on are ignored.
+	pub fn handle_reply(&mut self, reply: &DhtMessage, return_label: &Route, now: u64) {
+		let nodes = match reply.body {
+			DhtBody::Reply(ref nodes) => nodes,
+			DhtBody::Query(..) => return
+		};
+		let txid = match txid_from_bytes(reply.txid.as_slice()) {
+			Some(t) => t,
+			None => return
+		};
+		let (index, (address, responder)) = match self.searches.iter()
+				.enumerate()
+				.filter_map(|(i, s)| s.responder(txid).map(|r| (i, r)))
+				.next() {
+			Some(r) => r,
+			None => return
+		};
+		if responder.label != *return_label {
+			return;
+		}
+
+		let scheme = match reply.scheme {
+			Some(ref s) => s.clone(),
+			None => default_scheme()
+		};
+		let updated = match self.node_store.get_mut(&address) {
+			Some(node) => {
+				node.reach = node.reach.saturating_add(1);
+				node.last_seen = now;
+				node.version = reply.version;
+				node.scheme = scheme.clone();
+				true
+			},
+			None => false
+		};
+		if !updated {
+			self.node_store.add(Node {
+				public_key: responder.public_key,
+				address: address,
+				route: responder.label,
+				scheme: scheme.clone(),
+				version: reply.version,
+				reach: 1,
+				last_seen: now
+			});
+		}
+
+		let mut learned = vec![];
+		for entry in nodes.iter() {
+			let address = match Address::from_public_key(&entry.public_key) {
+				Some(a) => a,
+				None => continue
+			};
+			if address == self.own_address {
+				continue;
+			}
+
+			let route = match reply.return_form {
+				Some(form) => responder.label.extend(&entry.label, &scheme, form),
+				None => responder.label.combine(&entry.label)
+			};
+			let route = match route {
+				Some(r) => r,
+				None => continue
+			};
+
+			if self.node_store.get(&address).is_none() {
+				self.node_store.add(Node {
+					public_key: entry.public_key,
+					address: address,
+					route: route,
+					scheme: default_scheme(),
+					version: entry.version,
+					reach: 0,
+					last_seen: now
+				});
+			}
+			learned.push((address, NodeEntry { label: route, .. *entry }));
+		}
+
+		self.searches[index].handle_reply(txid, learned);
 	}
 
-	pub fn get_route(&self, address: &Address) -> Route {
-		match self.node_store.get(address) {
-			Some(route) => route.clone(),
-			None => Route::new(0b10)
+	fn store_found(&mut self, address: &Address, found: &NodeEntry, now: u64) {
+		let updated = match self.node_store.get_mut(address) {
+			Some(node) => {
+				node.route = found.label;
+				node.version = found.version;
+				node.last_seen = now;
+				true
+			},
+			None => false
+		};
+		if !updated {
+			self.node_store.add(Node {
+				public_key: found.public_key,
+				address: *address,
+				route: found.label,
+				scheme: default_scheme(),
+				version: found.version,
+				reach: 0,
+				last_seen: now
+			});
 		}
 	}
 }
+
+
+/// Encoding scheme assumed for nodes that haven't told us theirs
+fn default_scheme() -> FormList {
+	match Variable3x5x8.form_list() {
+		Some(s) => s,
+		None => unreachable!()
+	}
+}
+
+fn node_entry(node: &Node) -> NodeEntry {
+	NodeEntry {
+		public_key: node.public_key,
+		label: node.route,
+		version: node.version
+	}
+}
+
+/// Transaction ids are random so that replies can't be forged by guessing
+fn random_txid() -> u32 {
+	match txid_from_bytes(crypto::randombytes(4).as_slice()) {
+		Some(t) => t,
+		None => unreachable!()
+	}
+}
+
+fn txid_bytes(txid: u32) -> Vec<u8> {
+	vec![(txid >> 24) as u8, (txid >> 16) as u8, (txid >> 8) as u8, txid as u8]
+}
+
+fn txid_from_bytes(bytes: &[u8]) -> Option<u32> {
+	if bytes.len() != 4 {
+		return None;
+	}
+	Some(bytes.iter().fold(0u32, |txid, b| txid << 8 | *b as u32))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::Router;
+	use dht::NodeEntry;
+	use PrivateIdentity;
+	use Route;
+	use PROTOCOL_VERSION;
+
+	#[test]
+	fn test_search() {
+		let me = PrivateIdentity::generate();
+		let peer = PrivateIdentity::generate();
+		let target = PrivateIdentity::generate();
+		let peer_route = Route::new(0b1_0011);
+
+		let mut router = Router::new(&me.address);
+		assert!(!router.search(&target.address, 0));
+		router.add_peer(&peer.public_key, &peer.address, peer_route, None, 0);
+		assert!(router.search(&target.address, 0));
+		assert!(!router.search(&target.address, 0));
+
+		let queries = router.search_queries(0);
+		assert_eq!(queries.len(), 1);
+		let (route, ref query) = queries[0];
+		assert_eq!(route, peer_route);
+
+		let found = NodeEntry { public_key: target.public_key, label: Route::new(0b1_0101), version: 16 };
+		let reply = query.reply(PROTOCOL_VERSION, vec![found]);
+
+		// Only the node that was asked can answer
+		router.handle_reply(&reply, &Route::new(0b1_0101), 1);
+		assert_eq!(router.finish_searches(1), vec![]);
+		assert!(router.get_route(&target.address).is_none());
+
+		router.handle_reply(&reply, &peer_route, 1);
+		let target_route = peer_route.combine(&found.label).unwrap();
+		assert_eq!(router.finish_searches(1), vec![(target.address, Some(target_route))]);
+		assert!(!router.is_searching(&target.address));
+		assert_eq!(router.get_route(&target.address), Some(target_route));
+		assert_eq!(router.node_store().get(&peer.address).unwrap().reach, super::PEER_REACH + 1);
+	}
+}
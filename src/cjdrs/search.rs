@@ -0,0 +1,289 @@
+use dht::NodeEntry;
+use Address;
+
+
+/// Queries a search keeps in flight at the same time
+pub const PARALLEL_QUERIES: usize = 3;
+
+/// Closest nodes to the target that have to answer before a search gives up
+pub const SEARCH_WIDTH: usize = 8;
+
+/// Seconds to wait for the answer to a query
+pub const QUERY_TIMEOUT: u64 = 4;
+
+/// Seconds after which a search gives up
+pub const SEARCH_TIMEOUT: u64 = 30;
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum CandidateState {
+	New,
+	Queried { txid: u32, sent: u64 },
+	Answered,
+	/// The query timed out
+	Failed
+}
+
+
+/// A node that may know the target. Its label is the route from us.
+#[derive(Debug, Clone)]
+struct Candidate {
+	address: Address,
+	node: NodeEntry,
+	state: CandidateState
+}
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SearchResult {
+	Running,
+	Found(NodeEntry),
+	Failed
+}
+
+
+
+/// Iterative lookup of an address. The closest known nodes to the target
+/// are asked for nodes that are closer still, until the target turns up or
+/// the closest nodes have all answered without bringing us nearer.
+#[derive(Debug)]
+pub struct Search {
+	target: Address,
+	/// Closest to the target first
+	candidates: Vec<Candidate>,
+	started: u64
+}
+
+impl Search {
+	pub fn new(target: &Address, nodes: Vec<(Address, NodeEntry)>, now: u64) -> Search {
+		let mut search = Search {
+			target: *target,
+			candidates: vec![],
+			started: now
+		};
+		search.add_candidates(nodes);
+		search
+	}
+
+	pub fn target(&self) -> &Address {
+		&self.target
+	}
+
+	/// Marks the next closest candidates queried, up to the number of
+	/// parallel queries, and returns them with the transaction ids drawn from
+	/// `new_txid`.
+	pub fn next_queries<F: FnMut() -> u32>(&mut self, now: u64, mut new_txid: F) -> Vec<(u32, NodeEntry)> {
+		let mut queries = vec![];
+		if self.found().is_some() {
+			return queries;
+		}
+
+		let mut in_flight = self.candidates.iter().filter(|c| c.is_queried()).count();
+		for i in self.window().into_iter() {
+			if in_flight >= PARALLEL_QUERIES {
+				break;
+			}
+
+			let candidate = &mut self.candidates[i];
+			if candidate.state != CandidateState::New {
+				continue;
+			}
+			let txid = new_txid();
+			candidate.state = CandidateState::Queried { txid: txid, sent: now };
+			queries.push((txid, candidate.node));
+			in_flight += 1;
+		}
+		queries
+	}
+
+	/// Node a query with the transaction id is waiting for
+	pub fn responder(&self, txid: u32) -> Option<(Address, NodeEntry)> {
+		self.candidates.iter()
+			.find(|c| c.txid() == Some(txid))
+			.map(|c| (c.address, c.node))
+	}
+
+	/// Records the answer to a query. The nodes must have labels from us.
+	/// Returns false if no query with the transaction id is in flight.
+	pub fn handle_reply(&mut self, txid: u32, nodes: Vec<(Address, NodeEntry)>) -> bool {
+		match self.candidates.iter_mut().find(|c| c.txid() == Some(txid)) {
+			Some(c) => c.state = CandidateState::Answered,
+			None => return false
+		}
+		self.add_candidates(nodes);
+		true
+	}
+
+	/// Gives up on queries that weren't answered in time and returns the
+	/// nodes they went to
+	pub fn expire(&mut self, now: u64) -> Vec<Address> {
+		let mut expired = vec![];
+		for candidate in self.candidates.iter_mut() {
+			if let CandidateState::Queried { sent, .. } = candidate.state {
+				if now >= sent + QUERY_TIMEOUT {
+					candidate.state = CandidateState::Failed;
+					expired.push(candidate.address);
+				}
+			}
+		}
+		expired
+	}
+
+	pub fn result(&self, now: u64) -> SearchResult {
+		if let Some(node) = self.found() {
+			return SearchResult::Found(node);
+		}
+		if now >= self.started + SEARCH_TIMEOUT {
+			return SearchResult::Failed;
+		}
+
+		let window = self.window();
+		if window.iter().all(|&i| self.candidates[i].state == CandidateState::Answered) {
+			SearchResult::Failed
+		} else {
+			SearchResult::Running
+		}
+	}
+
+	fn found(&self) -> Option<NodeEntry> {
+		self.candidates.iter().find(|c| c.address == self.target).map(|c| c.node)
+	}
+
+	/// Indexes of the closest candidates that haven't failed
+	fn window(&self) -> Vec<usize> {
+		self.candidates.iter()
+			.enumerate()
+			.filter(|&(_, c)| c.state != CandidateState::Failed)
+			.map(|(i, _)| i)
+			.take(SEARCH_WIDTH)
+			.collect()
+	}
+
+	fn add_candidates(&mut self, nodes: Vec<(Address, NodeEntry)>) {
+		for (address, node) in nodes.into_iter() {
+			if self.candidates.iter().any(|c| c.address == address) {
+				continue;
+			}
+			self.candidates.push(Candidate {
+				address: address,
+				node: node,
+				state: CandidateState::New
+			});
+		}
+
+		let target = self.target;
+		self.candidates.sort_by(|a, b| Address::xor_compare((&a.address, &target), (&b.address, &target)));
+	}
+}
+
+
+impl Candidate {
+	fn is_queried(&self) -> bool {
+		self.txid().is_some()
+	}
+
+	fn txid(&self) -> Option<u32> {
+		match self.state {
+			CandidateState::Queried { txid, .. } => Some(txid),
+			_ => None
+		}
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{Search, SearchResult, PARALLEL_QUERIES, QUERY_TIMEOUT, SEARCH_TIMEOUT};
+	use dht::NodeEntry;
+	use Address;
+	use PublicKey;
+	use Route;
+
+	fn node(address: &str, label: u64) -> (Address, NodeEntry) {
+		let entry = NodeEntry {
+			public_key: PublicKey::from_buffer(&[0; 32]),
+			label: Route::new(label),
+			version: 16
+		};
+		(Address::from_string(address).unwrap(), entry)
+	}
+
+	fn target() -> Address {
+		Address::from_string("fc00::ff").unwrap()
+	}
+
+	fn counter(txid: &mut u32) -> u32 {
+		*txid += 1;
+		*txid - 1
+	}
+
+	#[test]
+	fn test_converge() {
+		let mut txid = 100;
+		let nodes = vec![node("fc00::1", 0x11), node("fc00::2", 0x12), node("fc00::3", 0x13), node("fc00::4", 0x14)];
+		let mut search = Search::new(&target(), nodes, 0);
+		assert_eq!(search.result(0), SearchResult::Running);
+
+		// Closest first
+		let queries = search.next_queries(0, || counter(&mut txid));
+		assert_eq!(queries.len(), PARALLEL_QUERIES);
+		let labels: Vec<u64> = queries.iter().map(|&(_, n)| n.label.bits()).collect();
+		assert_eq!(labels, vec![0x14, 0x13, 0x12]);
+		assert_eq!(queries[0].0, 100);
+		assert_eq!(txid, 103);
+		assert!(search.next_queries(0, || counter(&mut txid)).is_empty());
+
+		assert_eq!(search.responder(100), Some(node("fc00::4", 0x14)));
+		assert!(search.handle_reply(100, vec![node("fc00::f0", 0x1_0014), node("fc00::3", 0x99)]));
+		assert!(!search.handle_reply(100, vec![]));
+		assert_eq!(search.responder(100), None);
+
+		// The node from the reply is closer than everything else
+		let queries = search.next_queries(1, || counter(&mut txid));
+		assert_eq!(queries, vec![(103, node("fc00::f0", 0x1_0014).1)]);
+
+		assert!(search.handle_reply(103, vec![node("fc00::ff", 0x2_0014)]));
+		assert_eq!(search.result(1), SearchResult::Found(node("fc00::ff", 0x2_0014).1));
+		assert!(search.next_queries(1, || counter(&mut txid)).is_empty());
+	}
+
+	#[test]
+	fn test_timeouts() {
+		let mut txid = 0;
+		let mut search = Search::new(&target(), vec![node("fc00::1", 0x11), node("fc00::2", 0x12)], 10);
+		assert_eq!(search.next_queries(10, || counter(&mut txid)).len(), 2);
+
+		assert!(search.expire(10 + QUERY_TIMEOUT - 1).is_empty());
+		assert_eq!(search.result(10 + QUERY_TIMEOUT - 1), SearchResult::Running);
+
+		let expired = search.expire(10 + QUERY_TIMEOUT);
+		assert_eq!(expired.len(), 2);
+		assert!(expired.contains(&Address::from_string("fc00::1").unwrap()));
+
+		// Late answers are ignored
+		assert!(!search.handle_reply(0, vec![node("fc00::ff", 0x2_0014)]));
+		assert_eq!(search.result(10 + QUERY_TIMEOUT), SearchResult::Failed);
+
+		let search = Search::new(&target(), vec![node("fc00::1", 0x11)], 10);
+		assert_eq!(search.result(10 + SEARCH_TIMEOUT), SearchResult::Failed);
+		assert_eq!(Search::new(&target(), vec![], 10).result(10), SearchResult::Failed);
+	}
+
+	#[test]
+	fn test_exhausted() {
+		let mut txid = 0;
+		let mut search = Search::new(&target(), vec![node("fc00::1", 0x11), node("fc00::2", 0x12)], 0);
+		search.next_queries(0, || counter(&mut txid));
+
+		// Farther away than what we had
+		assert!(search.handle_reply(0, vec![node("fc00::100", 0x1_0012)]));
+		assert_eq!(search.result(1), SearchResult::Running);
+		assert_eq!(search.next_queries(1, || counter(&mut txid)).len(), 1);
+
+		assert!(search.handle_reply(1, vec![]));
+		assert!(search.handle_reply(2, vec![]));
+		assert_eq!(search.result(2), SearchResult::Failed);
+	}
+}
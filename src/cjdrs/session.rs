@@ -0,0 +1,722 @@
+use crypto::{self, PasswordHash, SharedSecret, Nonce, CryptoBox};
+use identity::PUB_KEY_SIZE;
+use packet::{
+	CryptoAuth,
+	CryptoAuthHandshake,
+	CryptoAuthData,
+	Challenge,
+	HandshakeStage,
+	HANDSHAKE_UNENCRYPTED_LENGTH,
+	DATA_UNENCRYPTED_LENGTH,
+	FIRST_DATA_NONCE};
+use message::Message;
+use password_store::{AUTH_TYPE_PASSWORD, AUTH_TYPE_LOGIN};
+use replay_protector::ReplayProtector;
+use util;
+use Config;
+use PrivateIdentity;
+use PrivateKey;
+use PublicKey;
+
+
+pub type SessionResult<T> = Result<T, &'static str>;
+
+/// Sessions are rekeyed well before the nonce counter can wrap around
+const NONCE_RESET_THRESHOLD: u32 = 0xFFFF_0000;
+
+/// Timeouts used when the config doesn't set them
+pub const DEFAULT_RESET_AFTER_INACTIVITY: u64 = 60;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SessionState {
+	Init,
+	SentHello,
+	ReceivedHello,
+	SentKey,
+	ReceivedKey,
+	Established
+}
+
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SessionTimeouts {
+	/// Seconds without incoming traffic before an established session is reset
+	pub reset_after_inactivity: u64,
+	/// Seconds a handshake may take before it is started over
+	pub handshake_timeout: u64
+}
+
+impl SessionTimeouts {
+	pub fn from_config(config: &Config) -> SessionTimeouts {
+		SessionTimeouts {
+			reset_after_inactivity: config.resetAfterInactivitySeconds
+				.unwrap_or(DEFAULT_RESET_AFTER_INACTIVITY),
+			handshake_timeout: config.handshakeTimeoutSeconds
+				.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT)
+		}
+	}
+}
+
+
+
+#[derive(Debug)]
+pub struct Session {
+	my_identity: PrivateIdentity,
+	her_public_key: PublicKey,
+	password_hash: Option<PasswordHash>,
+	login: Option<String>,
+	user: Option<String>,
+
+	my_temp_private_key: PrivateKey,
+	my_temp_public_key: PublicKey,
+	her_temp_public_key: Option<PublicKey>,
+	session_secret: Option<SharedSecret>,
+
+	state: SessionState,
+	is_initiator: bool,
+	next_nonce: u32,
+	replay_protector: ReplayProtector,
+
+	handshake_started: u64,
+	last_received: u64
+}
+
+impl Session {
+	pub fn new(my_identity: &PrivateIdentity,
+	           her_public_key: &PublicKey,
+	           password_hash: Option<PasswordHash>)
+	           -> Session {
+		let my_temp_private_key = PrivateKey::generate();
+
+		Session {
+			my_identity: *my_identity,
+			her_public_key: *her_public_key,
+			password_hash: password_hash,
+			login: None,
+			user: None,
+
+			my_temp_private_key: my_temp_private_key,
+			my_temp_public_key: PublicKey::from_private_key(&my_temp_private_key),
+			her_temp_public_key: None,
+			session_secret: None,
+
+			state: SessionState::Init,
+			is_initiator: false,
+			next_nonce: FIRST_DATA_NONCE,
+			replay_protector: ReplayProtector::new(FIRST_DATA_NONCE),
+
+			handshake_started: util::timestamp(),
+			last_received: 0
+		}
+	}
+
+	pub fn state(&self) -> SessionState {
+		self.state
+	}
+
+	pub fn her_public_key(&self) -> &PublicKey {
+		&self.her_public_key
+	}
+
+	/// Authenticate with a login name instead of the password hash
+	pub fn set_login(&mut self, login: &str) {
+		self.login = Some(login.to_string());
+	}
+
+	/// Name of the authorized password the peer used to connect to us
+	pub fn user(&self) -> Option<&str> {
+		self.user.as_ref().map(|u| u.as_slice())
+	}
+
+	pub fn set_user(&mut self, user: &str) {
+		self.user = Some(user.to_string());
+	}
+
+	pub fn replay_protector(&self) -> &ReplayProtector {
+		&self.replay_protector
+	}
+
+	pub fn is_established(&self) -> bool {
+		self.state == SessionState::Established
+	}
+
+	/// Whether the other end waits for a packet from us to finish the
+	/// handshake: a Key after her Hello, or a first data packet after her Key
+	/// or her first data packet. Empty packets do.
+	pub fn is_reply_due(&self) -> bool {
+		match self.state {
+			SessionState::ReceivedHello => true,
+			SessionState::ReceivedKey |
+			SessionState::Established => self.next_nonce == FIRST_DATA_NONCE,
+			_ => false
+		}
+	}
+
+	pub fn reset(&mut self) {
+		self.my_temp_private_key = PrivateKey::generate();
+		self.my_temp_public_key = PublicKey::from_private_key(&self.my_temp_private_key);
+		self.her_temp_public_key = None;
+		self.session_secret = None;
+		self.state = SessionState::Init;
+		self.is_initiator = false;
+		self.next_nonce = FIRST_DATA_NONCE;
+		self.replay_protector = ReplayProtector::new(FIRST_DATA_NONCE);
+		self.handshake_started = util::timestamp();
+	}
+
+	/// Whether the session has gone stale and should be reset: the handshake
+	/// is taking too long, the peer has gone silent or the nonces are running out.
+	pub fn is_timed_out(&self, now: u64, timeouts: &SessionTimeouts) -> bool {
+		if self.state == SessionState::Established {
+			now >= self.last_received + timeouts.reset_after_inactivity ||
+			self.next_nonce >= NONCE_RESET_THRESHOLD
+		} else {
+			now >= self.handshake_started + timeouts.handshake_timeout
+		}
+	}
+
+	pub fn last_received(&self) -> u64 {
+		self.last_received
+	}
+
+
+	/// Wraps an outgoing message in place. Depending on the state of the
+	/// handshake the message is carried in a Hello, a Key or a data packet.
+	pub fn encrypt(&mut self, message: &mut Message) -> SessionResult<()> {
+		match self.state {
+			SessionState::Init |
+			SessionState::SentHello => {
+				self.encrypt_hello(message);
+				Ok(())
+			},
+
+			SessionState::ReceivedHello |
+			SessionState::SentKey => {
+				self.encrypt_key(message);
+				Ok(())
+			},
+
+			SessionState::ReceivedKey |
+			SessionState::Established => self.encrypt_data(message)
+		}
+	}
+
+	fn encrypt_hello(&mut self, message: &mut Message) {
+		let stage = match self.state {
+			SessionState::Init => HandshakeStage::Hello,
+			_ => HandshakeStage::RepeatHello
+		};
+
+		let shared_secret = self.get_shared_secret(
+			&self.my_identity.private_key,
+			&self.her_public_key);
+		self.encrypt_handshake(message, stage, &shared_secret);
+
+		self.is_initiator = true;
+		self.state = SessionState::SentHello;
+	}
+
+	fn encrypt_key(&mut self, message: &mut Message) {
+		let stage = match self.state {
+			SessionState::ReceivedHello => HandshakeStage::Key,
+			_ => HandshakeStage::RepeatKey
+		};
+
+		let her_temp_public_key = match self.her_temp_public_key {
+			Some(key) => key,
+			None => unreachable!()
+		};
+		let shared_secret = self.get_shared_secret(
+			&self.my_identity.private_key,
+			&her_temp_public_key);
+		self.encrypt_handshake(message, stage, &shared_secret);
+
+		self.state = SessionState::SentKey;
+	}
+
+	fn encrypt_handshake(&self,
+	                     message: &mut Message,
+	                     stage: HandshakeStage,
+	                     shared_secret: &SharedSecret) {
+		let challenge = match (self.password_hash, &self.login) {
+			(Some(..), &Some(ref login)) =>
+				Challenge::new(AUTH_TYPE_LOGIN, &crypto::login_lookup(login.as_slice())),
+			(Some(ref password_hash), &None) =>
+				Challenge::new(AUTH_TYPE_PASSWORD, &password_hash.lookup()),
+			(None, _) => {
+				let mut lookup = [0u8; 7];
+				crypto::randombytes_into(&mut lookup);
+				Challenge::new(0, &lookup)
+			}
+		};
+
+		let mut nonce = [0u8; 24];
+		crypto::randombytes_into(&mut nonce);
+
+		message.push(self.my_temp_public_key.as_slice());
+		CryptoBox::encrypt(message, &Nonce::Mine(nonce), shared_secret);
+		CryptoAuthHandshake::push_header(
+			message,
+			stage,
+			&challenge,
+			&nonce,
+			&self.my_identity.public_key);
+	}
+
+	fn encrypt_data(&mut self, message: &mut Message) -> SessionResult<()> {
+		if self.next_nonce == !0 {
+			return Err("Nonce counter exhausted");
+		}
+
+		let nonce = self.next_nonce;
+		{
+			let session_secret = match self.session_secret {
+				Some(ref s) => s,
+				None => unreachable!()
+			};
+			CryptoBox::encrypt(
+				message,
+				&Nonce::Mine(data_nonce(nonce, self.is_initiator)),
+				session_secret);
+		}
+
+		self.next_nonce += 1;
+		CryptoAuthData::push_header(message, nonce);
+		Ok(())
+	}
+
+
+	/// Unwraps an incoming CryptoAuth packet in place, leaving the decrypted
+	/// payload in the message.
+	pub fn receive(&mut self, message: &mut Message) -> SessionResult<()> {
+		let is_handshake = match try!(CryptoAuth::from_buffer(message.as_slice())) {
+			CryptoAuth::Handshake(..) => true,
+			CryptoAuth::Data(..) => false
+		};
+
+		if is_handshake {
+			try!(self.receive_handshake(message));
+		} else {
+			try!(self.receive_data(message));
+		}
+
+		self.last_received = util::timestamp();
+		Ok(())
+	}
+
+	fn receive_handshake(&mut self, message: &mut Message) -> SessionResult<()> {
+		let (stage, her_public_key, nonce) = {
+			let packet = try!(CryptoAuthHandshake::from_buffer(message.as_slice()));
+			(packet.stage(), packet.public_key(), *packet.nonce())
+		};
+
+		if her_public_key != self.her_public_key {
+			return Err("Handshake packet from an unexpected public key");
+		}
+
+		if stage.is_hello() {
+			self.receive_hello(message, &nonce)
+		} else {
+			assert!(stage.is_key());
+			self.receive_key(message, &nonce)
+		}
+	}
+
+	fn receive_hello(&mut self, message: &mut Message, nonce: &[u8; 24]) -> SessionResult<()> {
+		// Both ends sent a Hello at the same time. The one with the
+		// smaller permanent key backs off and becomes the responder.
+		if self.state == SessionState::SentHello &&
+		   self.my_identity.public_key.as_slice() > self.her_public_key.as_slice() {
+			return Err("Ignoring a crossed Hello, waiting for a Key");
+		}
+
+		let shared_secret = self.get_shared_secret(
+			&self.my_identity.private_key,
+			&self.her_public_key);
+		let her_temp_public_key = try!(decrypt_handshake(message, nonce, &shared_secret));
+
+		// A Hello with a new temporary key means she has restarted the handshake.
+		// Repeats of the Hello that led to an established session are stale.
+		let restarted = match self.her_temp_public_key {
+			Some(key) => key != her_temp_public_key,
+			None => false
+		};
+		if restarted {
+			self.reset();
+		} else if self.state == SessionState::Established {
+			return Err("Repeated Hello on an established session");
+		}
+
+		self.her_temp_public_key = Some(her_temp_public_key);
+		self.session_secret = Some(SharedSecret::without_password(
+			&self.my_temp_private_key,
+			&her_temp_public_key));
+		self.is_initiator = false;
+		self.state = SessionState::ReceivedHello;
+
+		Ok(())
+	}
+
+	fn receive_key(&mut self, message: &mut Message, nonce: &[u8; 24]) -> SessionResult<()> {
+		match self.state {
+			SessionState::SentHello |
+			SessionState::ReceivedKey |
+			SessionState::Established => {},
+			_ => return Err("Key packet received without sending a Hello")
+		}
+
+		if !self.is_initiator && self.state == SessionState::Established {
+			return Err("Key packet received by the responder");
+		}
+
+		let shared_secret = self.get_shared_secret(
+			&self.my_temp_private_key,
+			&self.her_public_key);
+		let her_temp_public_key = try!(decrypt_handshake(message, nonce, &shared_secret));
+
+		if let Some(ref key) = self.her_temp_public_key {
+			if *key != her_temp_public_key {
+				return Err("Repeated Key packet with a different temporary key");
+			}
+		}
+
+		if self.state != SessionState::Established {
+			self.her_temp_public_key = Some(her_temp_public_key);
+			self.session_secret = Some(SharedSecret::without_password(
+				&self.my_temp_private_key,
+				&her_temp_public_key));
+			self.is_initiator = true;
+			self.state = SessionState::ReceivedKey;
+		}
+
+		Ok(())
+	}
+
+	fn receive_data(&mut self, message: &mut Message) -> SessionResult<()> {
+		match self.state {
+			SessionState::ReceivedHello |
+			SessionState::SentKey |
+			SessionState::ReceivedKey |
+			SessionState::Established => {},
+			_ => return Err("Data packet received before handshake")
+		}
+
+		let nonce = {
+			let packet = try!(CryptoAuthData::from_buffer(message.as_slice()));
+			packet.nonce()
+		};
+		message.pop(DATA_UNENCRYPTED_LENGTH);
+
+		{
+			let session_secret = match self.session_secret {
+				Some(ref s) => s,
+				None => unreachable!()
+			};
+			let full_nonce = Nonce::Hers(data_nonce(nonce, !self.is_initiator));
+
+			if !CryptoBox::decrypt(message, &full_nonce, session_secret) {
+				return Err("Couldn't decrypt data packet");
+			}
+		}
+
+		// Only authenticated packets may move the replay window
+		if !self.replay_protector.check_nonce(nonce) {
+			return Err("Replayed or too old data packet");
+		}
+
+		self.state = SessionState::Established;
+		Ok(())
+	}
+
+
+	fn get_shared_secret(&self, my_private_key: &PrivateKey, her_public_key: &PublicKey)
+	                     -> SharedSecret {
+		match self.password_hash {
+			Some(ref password_hash) => SharedSecret::with_password(
+				my_private_key,
+				her_public_key,
+				password_hash),
+			None => SharedSecret::without_password(
+				my_private_key,
+				her_public_key)
+		}
+	}
+}
+
+
+/// Builds the 24 byte nonce of a data packet. The initiator's counter lives in
+/// the first four bytes and the responder's in the next four, so that the two
+/// directions never share a nonce.
+fn data_nonce(counter: u32, from_initiator: bool) -> [u8; 24] {
+	let mut nonce = [0u8; 24];
+	let offset = if from_initiator { 0 } else { 4 };
+	nonce[offset + 0] = (counter      ) as u8;
+	nonce[offset + 1] = (counter >>  8) as u8;
+	nonce[offset + 2] = (counter >> 16) as u8;
+	nonce[offset + 3] = (counter >> 24) as u8;
+	nonce
+}
+
+/// Decrypts a Hello or Key in place, leaving only the payload in the message,
+/// and returns the temporary key of the sender.
+fn decrypt_handshake(message: &mut Message,
+                     nonce: &[u8; 24],
+                     shared_secret: &SharedSecret) -> SessionResult<PublicKey> {
+	message.pop(HANDSHAKE_UNENCRYPTED_LENGTH);
+
+	if !CryptoBox::decrypt(message, &Nonce::Hers(*nonce), shared_secret) {
+		return Err("Couldn't decrypt handshake packet");
+	}
+
+	Ok(PublicKey::from_slice(message.pop(PUB_KEY_SIZE)))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{Session, SessionState, SessionTimeouts, NONCE_RESET_THRESHOLD, data_nonce};
+	use util;
+	use crypto::{self, PasswordHash};
+	use message::Message;
+	use packet::CryptoAuth;
+	use PrivateIdentity;
+
+	fn encrypt(from: &mut Session, content: &[u8]) -> Vec<u8> {
+		let mut message = Message::from_slice(content);
+		from.encrypt(&mut message).unwrap();
+		message.as_slice().to_vec()
+	}
+
+	fn deliver(to: &mut Session, packet: &[u8]) -> Result<Vec<u8>, &'static str> {
+		let mut message = Message::from_slice(packet);
+		try!(to.receive(&mut message));
+		Ok(message.as_slice().to_vec())
+	}
+
+	fn handshake(password_hash: Option<PasswordHash>) -> (Session, Session) {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut alice_session = Session::new(&alice, &bob.public_key, password_hash);
+		let mut bob_session = Session::new(&bob, &alice.public_key, password_hash);
+
+		let hello = encrypt(&mut alice_session, b"hello");
+		assert_eq!(alice_session.state(), SessionState::SentHello);
+		assert_eq!(deliver(&mut bob_session, hello.as_slice()).unwrap(), b"hello");
+		assert_eq!(bob_session.state(), SessionState::ReceivedHello);
+		assert!(bob_session.is_reply_due());
+
+		let key = encrypt(&mut bob_session, b"key");
+		assert_eq!(bob_session.state(), SessionState::SentKey);
+		assert!(!bob_session.is_reply_due());
+		assert_eq!(deliver(&mut alice_session, key.as_slice()).unwrap(), b"key");
+		assert_eq!(alice_session.state(), SessionState::ReceivedKey);
+		assert!(alice_session.is_reply_due());
+
+		let data = encrypt(&mut alice_session, b"first data");
+		assert!(!alice_session.is_reply_due());
+		assert_eq!(deliver(&mut bob_session, data.as_slice()).unwrap(), b"first data");
+		assert!(bob_session.is_established());
+		assert!(bob_session.is_reply_due());
+
+		(alice_session, bob_session)
+	}
+
+	#[test]
+	fn test_handshake_with_password() {
+		let (mut alice, mut bob) = handshake(Some(PasswordHash::from_password("secret")));
+
+		let data = encrypt(&mut bob, b"reply");
+		assert!(!bob.is_reply_due());
+		assert_eq!(deliver(&mut alice, data.as_slice()).unwrap(), b"reply");
+		assert!(alice.is_established());
+		assert!(!alice.is_reply_due());
+	}
+
+	#[test]
+	fn test_handshake_without_password() {
+		let (mut alice, mut bob) = handshake(None);
+
+		for i in range(0u8, 10) {
+			let data = encrypt(&mut alice, &[i]);
+			assert_eq!(deliver(&mut bob, data.as_slice()).unwrap(), [i]);
+			let data = encrypt(&mut bob, &[i, i]);
+			assert_eq!(deliver(&mut alice, data.as_slice()).unwrap(), [i, i]);
+		}
+	}
+
+	#[test]
+	fn test_handshake_with_login() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let password_hash = Some(PasswordHash::from_password("secret"));
+		let mut alice_session = Session::new(&alice, &bob.public_key, password_hash);
+		let mut bob_session = Session::new(&bob, &alice.public_key, password_hash);
+		alice_session.set_login("alice");
+
+		let hello = encrypt(&mut alice_session, b"");
+		match CryptoAuth::from_buffer(hello.as_slice()).unwrap() {
+			CryptoAuth::Handshake(packet) => {
+				assert_eq!(packet.challenge().challenge_type(), 2);
+				assert_eq!(*packet.challenge().lookup(), crypto::login_lookup("alice"));
+			},
+			CryptoAuth::Data(..) => panic!("Hello parsed as data")
+		}
+
+		deliver(&mut bob_session, hello.as_slice()).unwrap();
+		let key = encrypt(&mut bob_session, b"");
+		deliver(&mut alice_session, key.as_slice()).unwrap();
+		let data = encrypt(&mut alice_session, b"data");
+		assert_eq!(deliver(&mut bob_session, data.as_slice()).unwrap(), b"data");
+	}
+
+	#[test]
+	fn test_replayed_data() {
+		let (mut alice, mut bob) = handshake(None);
+
+		let first = encrypt(&mut alice, b"first");
+		let second = encrypt(&mut alice, b"second");
+		assert_eq!(deliver(&mut bob, second.as_slice()).unwrap(), b"second");
+		assert_eq!(deliver(&mut bob, first.as_slice()).unwrap(), b"first");
+		assert!(deliver(&mut bob, first.as_slice()).is_err());
+		assert!(deliver(&mut bob, second.as_slice()).is_err());
+		assert_eq!(bob.replay_protector().duplicates(), 2);
+	}
+
+	#[test]
+	fn test_handshake_timeout() {
+		let timeouts = SessionTimeouts { reset_after_inactivity: 60, handshake_timeout: 10 };
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut session = Session::new(&alice, &bob.public_key, None);
+		encrypt(&mut session, b"");
+
+		let now = util::timestamp();
+		assert!(!session.is_timed_out(now, &timeouts));
+		assert!(session.is_timed_out(now + 10, &timeouts));
+
+		session.reset();
+		assert_eq!(session.state(), SessionState::Init);
+		assert!(!session.is_timed_out(now, &timeouts));
+	}
+
+	#[test]
+	fn test_inactivity_timeout() {
+		let timeouts = SessionTimeouts { reset_after_inactivity: 60, handshake_timeout: 10 };
+		let (mut alice, bob) = handshake(None);
+
+		let now = bob.last_received();
+		assert!(!bob.is_timed_out(now + 59, &timeouts));
+		assert!(bob.is_timed_out(now + 61, &timeouts));
+
+		alice.next_nonce = NONCE_RESET_THRESHOLD;
+		alice.state = SessionState::Established;
+		alice.last_received = now;
+		assert!(alice.is_timed_out(now, &timeouts));
+	}
+
+	#[test]
+	fn test_repeat_hello() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut alice_session = Session::new(&alice, &bob.public_key, None);
+		let mut bob_session = Session::new(&bob, &alice.public_key, None);
+
+		let hello = encrypt(&mut alice_session, b"");
+		let repeat_hello = encrypt(&mut alice_session, b"");
+		assert_eq!(&repeat_hello[..4], [0, 0, 0, 1].as_slice());
+
+		deliver(&mut bob_session, hello.as_slice()).unwrap();
+		deliver(&mut bob_session, repeat_hello.as_slice()).unwrap();
+
+		let key = encrypt(&mut bob_session, b"");
+		let repeat_key = encrypt(&mut bob_session, b"");
+		assert_eq!(&repeat_key[..4], [0, 0, 0, 3].as_slice());
+
+		deliver(&mut alice_session, key.as_slice()).unwrap();
+		deliver(&mut alice_session, repeat_key.as_slice()).unwrap();
+
+		let data = encrypt(&mut alice_session, b"data");
+		assert_eq!(deliver(&mut bob_session, data.as_slice()).unwrap(), b"data");
+	}
+
+	#[test]
+	fn test_hello_after_established() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut alice_session = Session::new(&alice, &bob.public_key, None);
+		let mut bob_session = Session::new(&bob, &alice.public_key, None);
+
+		let hello = encrypt(&mut alice_session, b"");
+		deliver(&mut bob_session, hello.as_slice()).unwrap();
+		let key = encrypt(&mut bob_session, b"");
+		deliver(&mut alice_session, key.as_slice()).unwrap();
+		let data = encrypt(&mut alice_session, b"data");
+		deliver(&mut bob_session, data.as_slice()).unwrap();
+		assert!(bob_session.is_established());
+
+		// A replayed Hello doesn't stall the session
+		assert!(deliver(&mut bob_session, hello.as_slice()).is_err());
+		assert!(bob_session.is_established());
+		let data = encrypt(&mut alice_session, b"more data");
+		assert_eq!(deliver(&mut bob_session, data.as_slice()).unwrap(), b"more data");
+
+		// A new Hello starts the handshake over
+		alice_session.reset();
+		let hello = encrypt(&mut alice_session, b"");
+		deliver(&mut bob_session, hello.as_slice()).unwrap();
+		assert_eq!(bob_session.state(), SessionState::ReceivedHello);
+	}
+
+	#[test]
+	fn test_wrong_password() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut alice_session = Session::new(
+			&alice, &bob.public_key, Some(PasswordHash::from_password("right")));
+		let mut bob_session = Session::new(
+			&bob, &alice.public_key, Some(PasswordHash::from_password("wrong")));
+
+		let hello = encrypt(&mut alice_session, b"");
+		assert!(deliver(&mut bob_session, hello.as_slice()).is_err());
+		assert_eq!(bob_session.state(), SessionState::Init);
+	}
+
+	#[test]
+	fn test_data_nonce() {
+		assert_eq!(data_nonce(0x04030201, true).as_slice(), [
+			1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0,
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].as_slice());
+		assert_eq!(data_nonce(0x04030201, false).as_slice(), [
+			0, 0, 0, 0, 1, 2, 3, 4, 0, 0, 0, 0,
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].as_slice());
+	}
+
+	#[test]
+	fn test_data_before_handshake() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut session = Session::new(&alice, &bob.public_key, None);
+
+		let buffer = [0u8, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(deliver(&mut session, &buffer).is_err());
+		assert_eq!(session.state(), SessionState::Init);
+	}
+
+	#[test]
+	fn test_hello_from_wrong_key() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut session = Session::new(&alice, &bob.public_key, None);
+
+		let mut buffer = [0u8; 120];
+		for (i, b) in alice.public_key.as_slice().iter().enumerate() {
+			buffer[40 + i] = *b;
+		}
+		assert!(deliver(&mut session, &buffer).is_err());
+		assert_eq!(session.state(), SessionState::Init);
+	}
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use message::Message;
+use packet::CryptoAuth;
+use session::{SessionResult, SessionTimeouts};
+use Address;
+use PrivateIdentity;
+use PublicKey;
+use Route;
+use Session;
+
+
+/// Handle that marks a frame following a switch header as a packet of an
+/// end-to-end session. The address of the sender comes next, then the
+/// CryptoAuth packet.
+pub const SESSION_HANDLE: u32 = 0xFFFF_FFFD;
+
+/// Sessions kept at the same time
+pub const MAX_SESSIONS: usize = 1024;
+
+/// Bytes of the sender's address in front of the CryptoAuth packet
+const ADDRESS_LENGTH: usize = 16;
+
+
+
+/// A session with a node and the route it is reached on
+#[derive(Debug)]
+struct EndToEnd {
+	session: Session,
+	/// Reversed label of the last packet from the node, or the route we knew
+	/// before it answered
+	route: Route
+}
+
+
+
+/// End-to-end CryptoAuth sessions with the nodes we exchange tun traffic
+/// with, keyed by their address. cjdns keeps sessions like these too, but
+/// frames them differently, so they only work between cjdrs nodes.
+#[derive(Debug)]
+pub struct SessionManager {
+	my_identity: PrivateIdentity,
+	sessions: HashMap<Address, EndToEnd>
+}
+
+impl SessionManager {
+	pub fn new(my_identity: &PrivateIdentity) -> SessionManager {
+		SessionManager {
+			my_identity: *my_identity,
+			sessions: HashMap::new()
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.sessions.len()
+	}
+
+	pub fn contains(&self, address: &Address) -> bool {
+		self.sessions.contains_key(address)
+	}
+
+	pub fn is_established(&self, address: &Address) -> bool {
+		self.sessions.get(address).map(|e| e.session.is_established()).unwrap_or(false)
+	}
+
+	/// Whether the node waits for a packet from us to finish the handshake
+	pub fn is_reply_due(&self, address: &Address) -> bool {
+		self.sessions.get(address).map(|e| e.session.is_reply_due()).unwrap_or(false)
+	}
+
+	pub fn route(&self, address: &Address) -> Option<Route> {
+		self.sessions.get(address).map(|e| e.route)
+	}
+
+	/// Starts a session with a node reached on `route`. Returns false if there
+	/// is one already or there are too many.
+	pub fn start(&mut self, address: &Address, public_key: &PublicKey, route: Route) -> bool {
+		if self.sessions.contains_key(address) || self.sessions.len() >= MAX_SESSIONS {
+			return false;
+		}
+
+		self.sessions.insert(*address, EndToEnd {
+			session: Session::new(&self.my_identity, public_key, None),
+			route: route
+		});
+		true
+	}
+
+	/// Wraps a message for a node in a packet of its session and returns the
+	/// route to send it along. Depending on the state of the handshake the
+	/// packet is a Hello, a Key or a data packet.
+	pub fn encrypt(&mut self, address: &Address, message: &mut Message) -> SessionResult<Route> {
+		let end_to_end = match self.sessions.get_mut(address) {
+			Some(e) => e,
+			None => return Err("No session with the node")
+		};
+
+		try!(end_to_end.session.encrypt(message));
+		message.push(self.my_identity.address.as_slice());
+		message.push_u32(SESSION_HANDLE);
+		Ok(end_to_end.route)
+	}
+
+	/// Unwraps a session packet that came back on `route`, leaving the payload
+	/// in the message. Returns the sender and whether the packet established
+	/// the session. Only a Hello that decrypts starts a new session.
+	pub fn receive(&mut self, route: Route, message: &mut Message) -> SessionResult<(Address, bool)> {
+		if message.len() < ADDRESS_LENGTH {
+			return Err("Session packet too short");
+		}
+		let from = match Address::from_slice(message.pop(ADDRESS_LENGTH)) {
+			Some(a) => a,
+			None => return Err("Invalid sender address")
+		};
+
+		if !self.sessions.contains_key(&from) {
+			let public_key = match try!(CryptoAuth::from_buffer(message.as_slice())) {
+				CryptoAuth::Handshake(ref handshake) => handshake.public_key(),
+				CryptoAuth::Data(..) => return Err("Data packet without a session")
+			};
+			if Address::from_public_key(&public_key) != Some(from) {
+				return Err("Sender address doesn't belong to its key");
+			}
+			if self.sessions.len() >= MAX_SESSIONS {
+				return Err("Too many sessions");
+			}
+
+			let mut session = Session::new(&self.my_identity, &public_key, None);
+			try!(session.receive(message));
+			self.sessions.insert(from, EndToEnd { session: session, route: route });
+			return Ok((from, false));
+		}
+
+		let end_to_end = match self.sessions.get_mut(&from) {
+			Some(e) => e,
+			None => unreachable!()
+		};
+		let was_established = end_to_end.session.is_established();
+		try!(end_to_end.session.receive(message));
+
+		// Answers go back the way the last authentic packet came
+		end_to_end.route = route;
+		Ok((from, !was_established && end_to_end.session.is_established()))
+	}
+
+	/// Drops the sessions that timed out and returns the nodes they were with
+	pub fn expire(&mut self, now: u64, timeouts: &SessionTimeouts) -> Vec<Address> {
+		let expired: Vec<Address> = self.sessions.iter()
+			.filter(|&(_, e)| e.session.is_timed_out(now, timeouts))
+			.map(|(a, _)| *a)
+			.collect();
+
+		for address in expired.iter() {
+			self.sessions.remove(address);
+		}
+		expired
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{SessionManager, SESSION_HANDLE};
+	use session::{SessionResult, SessionTimeouts};
+	use util;
+	use Address;
+	use Message;
+	use PrivateIdentity;
+	use Route;
+
+	fn encrypt(from: &mut SessionManager, to: &Address, content: &[u8]) -> Vec<u8> {
+		let mut message = Message::from_slice(content);
+		from.encrypt(to, &mut message).unwrap();
+		assert_eq!(message.pop_u32(), SESSION_HANDLE);
+		message.as_slice().to_vec()
+	}
+
+	fn deliver(to: &mut SessionManager, route: Route, packet: &[u8])
+	           -> SessionResult<(Address, bool, Vec<u8>)> {
+		let mut message = Message::from_slice(packet);
+		let (from, established) = try!(to.receive(route, &mut message));
+		Ok((from, established, message.as_slice().to_vec()))
+	}
+
+	#[test]
+	fn test_handshake() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut alice_sessions = SessionManager::new(&alice);
+		let mut bob_sessions = SessionManager::new(&bob);
+		let to_bob = Route::new(0b1_0101_0011);
+		let to_alice = Route::new(0b1_1100_0110);
+
+		assert!(alice_sessions.start(&bob.address, &bob.public_key, to_bob));
+		assert!(!alice_sessions.start(&bob.address, &bob.public_key, to_bob));
+
+		// Hello and Key, each side answers with an empty packet
+		let hello = encrypt(&mut alice_sessions, &bob.address, b"");
+		assert_eq!(deliver(&mut bob_sessions, to_alice, hello.as_slice()), Ok((alice.address, false, vec![])));
+		assert_eq!(bob_sessions.route(&alice.address), Some(to_alice));
+		assert!(bob_sessions.is_reply_due(&alice.address));
+
+		let key = encrypt(&mut bob_sessions, &alice.address, b"");
+		assert_eq!(deliver(&mut alice_sessions, to_bob, key.as_slice()), Ok((bob.address, false, vec![])));
+		assert!(alice_sessions.is_reply_due(&bob.address));
+
+		let data = encrypt(&mut alice_sessions, &bob.address, b"");
+		assert_eq!(deliver(&mut bob_sessions, to_alice, data.as_slice()), Ok((alice.address, true, vec![])));
+		assert!(bob_sessions.is_established(&alice.address));
+		assert!(!alice_sessions.is_established(&bob.address));
+
+		let data = encrypt(&mut bob_sessions, &alice.address, b"");
+		assert_eq!(deliver(&mut alice_sessions, to_bob, data.as_slice()), Ok((bob.address, true, vec![])));
+		assert!(!alice_sessions.is_reply_due(&bob.address));
+		assert!(!bob_sessions.is_reply_due(&alice.address));
+
+		let data = encrypt(&mut alice_sessions, &bob.address, b"data");
+		assert_eq!(deliver(&mut bob_sessions, to_alice, data.as_slice()), Ok((alice.address, false, b"data".to_vec())));
+	}
+
+	#[test]
+	fn test_unknown_sender() {
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mallory = PrivateIdentity::generate();
+		let mut alice_sessions = SessionManager::new(&alice);
+		let mut bob_sessions = SessionManager::new(&bob);
+		let route = Route::new(0b1_0011);
+
+		alice_sessions.start(&bob.address, &bob.public_key, route);
+		let hello = encrypt(&mut alice_sessions, &bob.address, b"");
+
+		// The sender's address has to match the key in the Hello
+		let mut forged = mallory.address.as_slice().to_vec();
+		forged.push_all(&hello[16..]);
+		assert!(deliver(&mut bob_sessions, route, forged.as_slice()).is_err());
+		assert_eq!(bob_sessions.len(), 0);
+
+		// Data packets don't start sessions
+		let mut data = alice.address.as_slice().to_vec();
+		data.push_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(deliver(&mut bob_sessions, route, data.as_slice()).is_err());
+		assert_eq!(bob_sessions.len(), 0);
+
+		assert!(deliver(&mut bob_sessions, route, hello.as_slice()).is_ok());
+		assert!(bob_sessions.contains(&alice.address));
+	}
+
+	#[test]
+	fn test_expire() {
+		let timeouts = SessionTimeouts { reset_after_inactivity: 60, handshake_timeout: 10 };
+		let alice = PrivateIdentity::generate();
+		let bob = PrivateIdentity::generate();
+		let mut sessions = SessionManager::new(&alice);
+		sessions.start(&bob.address, &bob.public_key, Route::new(0b1_0011));
+
+		let now = util::timestamp();
+		assert!(sessions.expire(now, &timeouts).is_empty());
+		assert_eq!(sessions.expire(now + 10, &timeouts), vec![bob.address]);
+		assert!(!sessions.contains(&bob.address));
+		assert_eq!(sessions.route(&bob.address), None);
+	}
+}
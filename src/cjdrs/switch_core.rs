@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use mio::net::SockAddr;
+use encoding_scheme::{EncodingScheme, Variable3x5x8};
+use packet::SwitchHeader;
+use util::reverse_bits;
+use Route;
+use SwitchError;
+
+
+pub type SwitchResult<T> = Result<T, SwitchError>;
+
+/// Interface that delivers frames to this node instead of forwarding them
+pub const SELF_INTERFACE: u32 = 1;
+
+
+
+/// Where frames switched to an interface number are sent to
+#[derive(Debug)]
+pub struct SwitchInterface {
+	pub device_idx: usize,
+	pub address: SockAddr,
+	/// Encoding scheme of the node behind the interface, which routes going
+	/// on past it are written in
+	pub scheme: Box<EncodingScheme>
+}
+
+
+
+/// Forwards frames between peers by reading the next hop from the low bits
+/// of their label and replacing it with the reversed hop they came from.
+#[derive(Debug)]
+pub struct SwitchCore {
+	scheme: Box<EncodingScheme>,
+	interfaces: HashMap<u32, SwitchInterface>
+}
+
+impl SwitchCore {
+	pub fn new() -> SwitchCore {
+		SwitchCore::with_scheme(Box::new(Variable3x5x8))
+	}
+
+	/// Switch that writes its directors in the given encoding scheme
+	pub fn with_scheme(scheme: Box<EncodingScheme>) -> SwitchCore {
+		SwitchCore {
+			scheme: scheme,
+			interfaces: HashMap::new()
+		}
+	}
+
+	pub fn scheme(&self) -> &EncodingScheme {
+		&*self.scheme
+	}
+
+	/// Assigns the lowest free interface number to a peer endpoint.
+	/// Returns None if every number is taken.
+	pub fn add_interface(&mut self, device_idx: usize, address: SockAddr) -> Option<u32> {
+		let number = range(0, self.scheme.max_number() + 1)
+			.filter(|&n| n != SELF_INTERFACE && !self.interfaces.contains_key(&n))
+			.next();
+
+		if let Some(n) = number {
+			// Until the peer tells us its scheme, assume the cjdns default
+			self.interfaces.insert(n, SwitchInterface {
+				device_idx: device_idx,
+				address: address,
+				scheme: Box::new(Variable3x5x8)
+			});
+		}
+		number
+	}
+
+	pub fn remove_interface(&mut self, number: u32) {
+		self.interfaces.remove(&number);
+	}
+
+	pub fn interface(&self, number: u32) -> Option<&SwitchInterface> {
+		self.interfaces.get(&number)
+	}
+
+	/// Records the encoding scheme the node behind an interface announced
+	pub fn set_interface_scheme(&mut self, number: u32, scheme: Box<EncodingScheme>) {
+		if let Some(interface) = self.interfaces.get_mut(&number) {
+			interface.scheme = scheme;
+		}
+	}
+
+	/// Rewrites the label of a frame that arrived on `source` for the next hop
+	/// and returns the interface it has to be sent out on.
+	pub fn switch(&self, source: u32, header: &mut SwitchHeader) -> SwitchResult<u32> {
+		let label = Route::new(header.label());
+		let (bits, destination) = match (self.scheme.bits_used_for_label(label.bits()),
+		                                 self.scheme.decompress(label.bits())) {
+			(Some(b), Some(d)) => (b, d),
+			_ => return Err(SwitchError::MalformedAddress)
+		};
+
+		if destination == source {
+			return Err(SwitchError::LoopRoute);
+		}
+		if destination != SELF_INTERFACE && !self.interfaces.contains_key(&destination) {
+			return Err(SwitchError::MalformedAddress);
+		}
+
+		let source_bits = self.scheme.bits_used_for_number(source);
+		let (return_bits, return_path) = if source_bits > bits {
+			// Nothing follows the return path of a frame for us, so it may be
+			// wider than the director it replaces
+			if destination != SELF_INTERFACE {
+				return Err(SwitchError::MalformedAddress);
+			}
+			(source_bits, self.scheme.compress(source))
+		} else {
+			match self.compress_to_width(source, bits) {
+				Some(path) => (bits, path),
+				None => return Err(SwitchError::MalformedAddress)
+			}
+		};
+
+		let remaining = Route::new(label.bits() >> bits as usize);
+		if remaining.bit_len() + return_bits > 64 {
+			return Err(SwitchError::ReturnPathInvalid);
+		}
+
+		header.set_label(remaining.bits() | reverse_bits(return_path));
+		header.set_label_shift((header.label_shift() + return_bits) & 0x3F);
+
+		Ok(destination)
+	}
+
+	/// Director for an interface number written `bits` wide, so a return path
+	/// fills exactly the space of the director it replaces. The self route is
+	/// `0001` in any width, like cjdns does it.
+	fn compress_to_width(&self, number: u32, bits: u8) -> Option<u64> {
+		let director = self.scheme.compress(number);
+		if self.scheme.bits_used_for_number(number) == bits || number == SELF_INTERFACE {
+			return Some(director);
+		}
+
+		let form_list = match self.scheme.form_list() {
+			Some(f) => f,
+			None => return None
+		};
+		match form_list.forms().iter().position(|f| f.len() == bits) {
+			Some(form_num) => form_list.convert_label(director, form_num),
+			None => None
+		}
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{SwitchCore, SELF_INTERFACE};
+	use encoding_scheme::{EncodingScheme, Fixed4, FormList, Form};
+	use util::reverse_bits;
+	use SwitchError;
+	use mio::net::SockAddr;
+	use packet::SwitchHeader;
+
+	fn switch_core() -> SwitchCore {
+		let mut core = SwitchCore::new();
+		assert_eq!(core.add_interface(0, SockAddr::parse("127.0.0.1:1000").unwrap()), Some(0));
+		assert_eq!(core.add_interface(0, SockAddr::parse("127.0.0.1:2000").unwrap()), Some(2));
+		assert_eq!(core.add_interface(0, SockAddr::parse("127.0.0.1:3000").unwrap()), Some(3));
+		core
+	}
+
+	#[test]
+	fn test_interface_numbers() {
+		let mut core = switch_core();
+		core.remove_interface(2);
+		assert!(core.interface(2).is_none());
+		assert_eq!(core.add_interface(1, SockAddr::parse("127.0.0.1:4000").unwrap()), Some(2));
+		assert_eq!(core.interface(2).unwrap().device_idx, 1);
+		assert!(core.interface(SELF_INTERFACE).is_none());
+	}
+
+	#[test]
+	fn test_interface_scheme() {
+		let mut core = switch_core();
+		assert_eq!(core.interface(2).unwrap().scheme.max_number(), 256);
+
+		core.set_interface_scheme(2, Box::new(Fixed4));
+		assert_eq!(core.interface(2).unwrap().scheme.max_number(), 15);
+		assert_eq!(core.interface(3).unwrap().scheme.max_number(), 256);
+
+		// Frames are still switched in our own scheme
+		let mut header = SwitchHeader::new(0b1_0101);
+		assert_eq!(core.switch(0, &mut header), Ok(2));
+	}
+
+	#[test]
+	fn test_forward() {
+		let core = switch_core();
+
+		// Interface 2 with one more hop to interface 3 behind it
+		let mut header = SwitchHeader::new(0b1_0111_0101);
+		assert_eq!(core.switch(0, &mut header), Ok(2));
+		assert_eq!(header.label(), 0xC000_0000_0000_0017);
+		assert_eq!(header.label_shift(), 4);
+
+		// The next hop shifts again and prepends its own return path
+		let mut header = SwitchHeader::new(0b1_0111);
+		assert_eq!(core.switch(2, &mut header), Ok(3));
+		assert_eq!(header.label(), 0xA000_0000_0000_0001);
+	}
+
+	#[test]
+	fn test_narrow_source() {
+		let mut core = switch_core();
+		for _ in range(0, 7) {
+			assert!(core.add_interface(0, SockAddr::parse("127.0.0.1:4000").unwrap()).is_some());
+		}
+		assert!(core.interface(10).is_some());
+
+		// Interface 10 takes seven bits, so the return path to interface 0
+		// is widened from 0011 to 0000010
+		let mut header = SwitchHeader::new(0b1_0100110);
+		assert_eq!(core.switch(0, &mut header), Ok(10));
+		assert_eq!(header.label(), 0x4000_0000_0000_0001);
+		assert_eq!(header.label_shift(), 7);
+		assert_eq!(core.scheme().decompress(reverse_bits(header.label())), Some(0));
+		assert_eq!(core.scheme().bits_used_for_label(reverse_bits(header.label())), Some(7));
+	}
+
+	#[test]
+	fn test_deliver_to_self() {
+		let core = switch_core();
+		let mut header = SwitchHeader::new(0b0001);
+		assert_eq!(core.switch(3, &mut header), Ok(SELF_INTERFACE));
+		assert_eq!(header.label(), 0xE000_0000_0000_0000);
+	}
+
+	#[test]
+	fn test_invalid_labels() {
+		let core = switch_core();
+
+		// No interface 5
+		assert_eq!(core.switch(0, &mut SwitchHeader::new(0b1_1011)), Err(SwitchError::MalformedAddress));
+
+		// Back to the source
+		assert_eq!(core.switch(2, &mut SwitchHeader::new(0b1_0101)), Err(SwitchError::LoopRoute));
+
+		// Return path wider than the director it replaces
+		assert_eq!(core.switch(40, &mut SwitchHeader::new(0b1_0101)), Err(SwitchError::MalformedAddress));
+
+		// No room left for the return path
+		assert_eq!(core.switch(40, &mut SwitchHeader::new(0x0FFF_FFFF_FFFF_FFF1)),
+		           Err(SwitchError::ReturnPathInvalid));
+	}
+
+	#[test]
+	fn test_schemes() {
+		let mut core = SwitchCore::with_scheme(Box::new(Fixed4));
+		for _ in range(0, 15) {
+			assert!(core.add_interface(0, SockAddr::parse("127.0.0.1:1000").unwrap()).is_some());
+		}
+		assert_eq!(core.add_interface(0, SockAddr::parse("127.0.0.1:1000").unwrap()), None);
+
+		let mut header = SwitchHeader::new(0b0011_0101);
+		assert_eq!(core.switch(2, &mut header), Ok(5));
+		assert_eq!(header.label(), 0x4000_0000_0000_0003);
+
+		// Labels matching none of the forms
+		let form_list = FormList::new(vec![Form::new(4, 2, 0b01), Form::new(8, 2, 0b10)]).unwrap();
+		let core = SwitchCore::with_scheme(Box::new(form_list));
+		assert_eq!(core.switch(0, &mut SwitchHeader::new(0b0011)), Err(SwitchError::MalformedAddress));
+	}
+}
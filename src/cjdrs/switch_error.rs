@@ -0,0 +1,89 @@
+use std::fmt;
+
+
+/// Reasons a switch can give for not delivering a frame, with the codes used
+/// in cjdns ERROR control messages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SwitchError {
+	MalformedAddress,
+	Flood,
+	LinkLimitExceeded,
+	Oversize,
+	Undersize,
+	Authentication,
+	Invalid,
+	Undeliverable,
+	LoopRoute,
+	ReturnPathInvalid
+}
+
+impl SwitchError {
+	pub fn to_u32(&self) -> u32 {
+		match *self {
+			SwitchError::MalformedAddress => 1,
+			SwitchError::Flood => 2,
+			SwitchError::LinkLimitExceeded => 3,
+			SwitchError::Oversize => 4,
+			SwitchError::Undersize => 5,
+			SwitchError::Authentication => 6,
+			SwitchError::Invalid => 7,
+			SwitchError::Undeliverable => 8,
+			SwitchError::LoopRoute => 9,
+			SwitchError::ReturnPathInvalid => 10
+		}
+	}
+
+	pub fn from_u32(code: u32) -> Option<SwitchError> {
+		match code {
+			1 => Some(SwitchError::MalformedAddress),
+			2 => Some(SwitchError::Flood),
+			3 => Some(SwitchError::LinkLimitExceeded),
+			4 => Some(SwitchError::Oversize),
+			5 => Some(SwitchError::Undersize),
+			6 => Some(SwitchError::Authentication),
+			7 => Some(SwitchError::Invalid),
+			8 => Some(SwitchError::Undeliverable),
+			9 => Some(SwitchError::LoopRoute),
+			10 => Some(SwitchError::ReturnPathInvalid),
+			_ => None
+		}
+	}
+
+	pub fn description(&self) -> &'static str {
+		match *self {
+			SwitchError::MalformedAddress => "Label points to a nonexistent interface",
+			SwitchError::Flood => "Frame dropped because of flooding",
+			SwitchError::LinkLimitExceeded => "Link is over capacity",
+			SwitchError::Oversize => "Frame too big for the link",
+			SwitchError::Undersize => "Frame too short",
+			SwitchError::Authentication => "Frame failed authentication",
+			SwitchError::Invalid => "Frame is invalid",
+			SwitchError::Undeliverable => "Frame can't be delivered to the next hop",
+			SwitchError::LoopRoute => "Label routes the frame back to where it came from",
+			SwitchError::ReturnPathInvalid => "No room in the label for the return path"
+		}
+	}
+}
+
+impl fmt::Display for SwitchError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.description())
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::SwitchError;
+
+	#[test]
+	fn test_codes() {
+		for code in range(1, 11) {
+			assert_eq!(SwitchError::from_u32(code).unwrap().to_u32(), code);
+		}
+		assert_eq!(SwitchError::from_u32(0), None);
+		assert_eq!(SwitchError::from_u32(11), None);
+		assert_eq!(SwitchError::Undeliverable.to_u32(), 8);
+	}
+}
@@ -0,0 +1,465 @@
+//! Bencode as used by cjdns for router and admin messages.
+//! http://www.bittorrent.org/beps/bep_0003.html#bencoding
+//!
+//! Decoding is strict: integers must not have leading zeros or be `-0`,
+//! dictionary keys must be sorted and unique and nothing may follow the
+//! value, so every value has exactly one encoding.
+
+use std::collections::BTreeMap;
+use std::num::Int;
+use std::{i64, str};
+
+
+pub type BencodeResult<T> = Result<T, &'static str>;
+
+/// Lists and dictionaries nested deeper than this are rejected
+const MAX_DEPTH: usize = 32;
+
+
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Bencode {
+	Integer(i64),
+	ByteString(Vec<u8>),
+	List(Vec<Bencode>),
+	Dict(BTreeMap<Vec<u8>, Bencode>)
+}
+
+impl Bencode {
+	pub fn decode(data: &[u8]) -> BencodeResult<Bencode> {
+		let mut parser = Parser { data: data, pos: 0 };
+		let value = try!(parser.value(0));
+		if parser.pos != data.len() {
+			return Err("Trailing data after bencoded value");
+		}
+		Ok(value)
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut out = vec![];
+		self.encode_to(&mut out);
+		out
+	}
+
+	fn encode_to(&self, out: &mut Vec<u8>) {
+		match *self {
+			Bencode::Integer(i) => {
+				out.push(b'i');
+				out.push_all(i.to_string().as_bytes());
+				out.push(b'e');
+			},
+			Bencode::ByteString(ref s) => encode_bytes(s.as_slice(), out),
+			Bencode::List(ref list) => {
+				out.push(b'l');
+				for value in list.iter() {
+					value.encode_to(out);
+				}
+				out.push(b'e');
+			},
+			Bencode::Dict(ref dict) => {
+				// BTreeMap iterates in key order
+				out.push(b'd');
+				for (key, value) in dict.iter() {
+					encode_bytes(key.as_slice(), out);
+					value.encode_to(out);
+				}
+				out.push(b'e');
+			}
+		}
+	}
+
+	pub fn as_int(&self) -> Option<i64> {
+		match *self {
+			Bencode::Integer(i) => Some(i),
+			_ => None
+		}
+	}
+
+	pub fn as_bytes(&self) -> Option<&[u8]> {
+		match *self {
+			Bencode::ByteString(ref s) => Some(s.as_slice()),
+			_ => None
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		self.as_bytes().and_then(|s| str::from_utf8(s).ok())
+	}
+
+	pub fn as_list(&self) -> Option<&[Bencode]> {
+		match *self {
+			Bencode::List(ref l) => Some(l.as_slice()),
+			_ => None
+		}
+	}
+
+	pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Bencode>> {
+		match *self {
+			Bencode::Dict(ref d) => Some(d),
+			_ => None
+		}
+	}
+
+	/// Value of a dictionary entry
+	pub fn get(&self, key: &str) -> Option<&Bencode> {
+		self.as_dict().and_then(|d| d.get(key.as_bytes()))
+	}
+}
+
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+	out.push_all(bytes.len().to_string().as_bytes());
+	out.push(b':');
+	out.push_all(bytes);
+}
+
+
+
+/// Conversion of our message types to bencode
+pub trait ToBencode {
+	fn to_bencode(&self) -> Bencode;
+}
+
+/// Conversion of decoded bencode to our message types
+pub trait FromBencode: Sized {
+	fn from_bencode(value: &Bencode) -> BencodeResult<Self>;
+}
+
+pub fn encode<T: ToBencode>(value: &T) -> Vec<u8> {
+	value.to_bencode().encode()
+}
+
+pub fn decode<T: FromBencode>(data: &[u8]) -> BencodeResult<T> {
+	FromBencode::from_bencode(&try!(Bencode::decode(data)))
+}
+
+
+impl ToBencode for Bencode {
+	fn to_bencode(&self) -> Bencode {
+		self.clone()
+	}
+}
+
+impl FromBencode for Bencode {
+	fn from_bencode(value: &Bencode) -> BencodeResult<Bencode> {
+		Ok(value.clone())
+	}
+}
+
+impl ToBencode for String {
+	fn to_bencode(&self) -> Bencode {
+		Bencode::ByteString(self.as_bytes().to_vec())
+	}
+}
+
+impl FromBencode for String {
+	fn from_bencode(value: &Bencode) -> BencodeResult<String> {
+		match value.as_str() {
+			Some(s) => Ok(s.to_string()),
+			None => Err("Expected a UTF-8 string")
+		}
+	}
+}
+
+impl<T: ToBencode> ToBencode for Vec<T> {
+	fn to_bencode(&self) -> Bencode {
+		Bencode::List(self.iter().map(|v| v.to_bencode()).collect())
+	}
+}
+
+impl<T: FromBencode> FromBencode for Vec<T> {
+	fn from_bencode(value: &Bencode) -> BencodeResult<Vec<T>> {
+		match value.as_list() {
+			Some(l) => l.iter().map(|v| FromBencode::from_bencode(v)).collect(),
+			None => Err("Expected a list")
+		}
+	}
+}
+
+macro_rules! bencode_integer {
+	($t:ty) => (
+		impl ToBencode for $t {
+			fn to_bencode(&self) -> Bencode {
+				Bencode::Integer(*self as i64)
+			}
+		}
+
+		impl FromBencode for $t {
+			fn from_bencode(value: &Bencode) -> BencodeResult<$t> {
+				let i = match value.as_int() {
+					Some(i) => i,
+					None => return Err("Expected an integer")
+				};
+				let min: $t = Int::min_value();
+				let max: $t = Int::max_value();
+				if i < min as i64 || i > max as i64 {
+					return Err("Integer out of range");
+				}
+				Ok(i as $t)
+			}
+		}
+	)
+}
+
+bencode_integer!(i64);
+bencode_integer!(u32);
+bencode_integer!(u16);
+bencode_integer!(u8);
+
+
+
+struct Parser<'a> {
+	data: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Parser<'a> {
+	fn peek(&self) -> BencodeResult<u8> {
+		match self.data.get(self.pos) {
+			Some(&b) => Ok(b),
+			None => Err("Unexpected end of bencoded data")
+		}
+	}
+
+	fn next(&mut self) -> BencodeResult<u8> {
+		let b = try!(self.peek());
+		self.pos += 1;
+		Ok(b)
+	}
+
+	fn value(&mut self, depth: usize) -> BencodeResult<Bencode> {
+		if depth > MAX_DEPTH {
+			return Err("Bencoded data nested too deeply");
+		}
+
+		match try!(self.peek()) {
+			b'i' => {
+				self.pos += 1;
+				let i = try!(self.integer(b'e'));
+				Ok(Bencode::Integer(i))
+			},
+			b'0'...b'9' => Ok(Bencode::ByteString(try!(self.bytes()).to_vec())),
+			b'l' => {
+				self.pos += 1;
+				let mut list = vec![];
+				while try!(self.peek()) != b'e' {
+					list.push(try!(self.value(depth + 1)));
+				}
+				self.pos += 1;
+				Ok(Bencode::List(list))
+			},
+			b'd' => {
+				self.pos += 1;
+				let mut dict = BTreeMap::new();
+				let mut last_key: Option<&[u8]> = None;
+				while try!(self.peek()) != b'e' {
+					match try!(self.peek()) {
+						b'0'...b'9' => (),
+						_ => return Err("Dictionary key is not a string")
+					}
+					let key = try!(self.bytes());
+					if let Some(last) = last_key {
+						if key <= last {
+							return Err("Dictionary keys not sorted or not unique");
+						}
+					}
+					last_key = Some(key);
+
+					let value = try!(self.value(depth + 1));
+					dict.insert(key.to_vec(), value);
+				}
+				self.pos += 1;
+				Ok(Bencode::Dict(dict))
+			},
+			_ => Err("Invalid bencode value")
+		}
+	}
+
+	/// Reads a decimal integer up to the terminator
+	fn integer(&mut self, terminator: u8) -> BencodeResult<i64> {
+		let negative = try!(self.peek()) == b'-';
+		if negative {
+			self.pos += 1;
+		}
+
+		let start = self.pos;
+		let mut value = 0i64;
+		loop {
+			let b = try!(self.next());
+			if b == terminator {
+				break;
+			}
+			if b < b'0' || b > b'9' {
+				return Err("Invalid digit in bencoded integer");
+			}
+
+			// Accumulating negatively reaches i64::MIN as well
+			let digit = (b - b'0') as i64;
+			value = match value.checked_mul(10).and_then(|v| v.checked_sub(digit)) {
+				Some(v) => v,
+				None => return Err("Bencoded integer out of range")
+			};
+		}
+
+		let digits = &self.data[start..self.pos - 1];
+		if digits.is_empty() {
+			return Err("Bencoded integer without digits");
+		}
+		if digits[0] == b'0' && (digits.len() > 1 || negative) {
+			return Err("Bencoded integer with leading zeros");
+		}
+
+		if negative {
+			Ok(value)
+		} else if value == i64::MIN {
+			Err("Bencoded integer out of range")
+		} else {
+			Ok(-value)
+		}
+	}
+
+	fn bytes(&mut self) -> BencodeResult<&'a [u8]> {
+		let len = try!(self.integer(b':'));
+		if len < 0 {
+			return Err("Negative string length");
+		}
+
+		if len as u64 > (self.data.len() - self.pos) as u64 {
+			return Err("Unexpected end of bencoded data");
+		}
+		let end = self.pos + len as usize;
+		let bytes = &self.data[self.pos..end];
+		self.pos = end;
+		Ok(bytes)
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{Bencode, BencodeResult, ToBencode, FromBencode, encode, decode};
+	use super::Bencode::{Integer, ByteString, List, Dict};
+	use std::collections::BTreeMap;
+
+	fn bytes(s: &str) -> Bencode {
+		ByteString(s.as_bytes().to_vec())
+	}
+
+	#[test]
+	fn test_decode() {
+		assert_eq!(Bencode::decode(b"i42e"), Ok(Integer(42)));
+		assert_eq!(Bencode::decode(b"i-42e"), Ok(Integer(-42)));
+		assert_eq!(Bencode::decode(b"i0e"), Ok(Integer(0)));
+		assert_eq!(Bencode::decode(b"i9223372036854775807e"), Ok(Integer(9223372036854775807)));
+		assert_eq!(Bencode::decode(b"i-9223372036854775808e"), Ok(Integer(-9223372036854775807 - 1)));
+		assert_eq!(Bencode::decode(b"4:spam"), Ok(bytes("spam")));
+		assert_eq!(Bencode::decode(b"0:"), Ok(bytes("")));
+		assert_eq!(Bencode::decode(b"l4:spami1ee"), Ok(List(vec![bytes("spam"), Integer(1)])));
+
+		let value = Bencode::decode(b"d1:ai1e1:bl0:ee").unwrap();
+		assert_eq!(value.get("a"), Some(&Integer(1)));
+		assert_eq!(value.get("b"), Some(&List(vec![bytes("")])));
+		assert_eq!(value.get("c"), None);
+	}
+
+	#[test]
+	fn test_decode_malformed() {
+		let malformed: [&[u8]; 22] = [
+			b"", b"i", b"ie", b"i-e", b"i01e", b"i-0e", b"i1", b"i1.5e", b"i9223372036854775808e",
+			b"5:spam", b"-1:a", b"01:a", b"4spam",
+			b"l", b"li1e", b"d", b"d1:a", b"di1ei1ee", b"d1:bi1e1:ai2ee", b"d1:ai1e1:ai2ee",
+			b"i1ei2e", b"x"
+		];
+		for data in malformed.iter() {
+			assert!(Bencode::decode(*data).is_err(), "{:?}", data);
+		}
+
+		let mut deep = vec![];
+		for _ in range(0, 100) { deep.push(b'l'); }
+		for _ in range(0, 100) { deep.push(b'e'); }
+		assert!(Bencode::decode(deep.as_slice()).is_err());
+	}
+
+	#[test]
+	fn test_encode() {
+		let mut dict = BTreeMap::new();
+		dict.insert(b"zz".to_vec(), Integer(-3));
+		dict.insert(b"a".to_vec(), List(vec![bytes("x"), Integer(0)]));
+		assert_eq!(Dict(dict).encode(), b"d1:al1:xi0ee2:zzi-3ee".to_vec());
+	}
+
+	/// Values built from a simple pseudo random sequence
+	fn random_value(seed: &mut u64, depth: usize) -> Bencode {
+		*seed = (*seed * 1103515245 + 12345) % (1 << 31);
+		let r = *seed;
+
+		// Only scalars further down, so the values stay small
+		let kind = if depth > 3 { r % 2 } else { r % 4 };
+		match kind {
+			0 => Integer((r as i64 - (1 << 30)) * (r as i64 % 1000)),
+			1 => ByteString(range(0, r % 20).map(|i| (i * r) as u8).collect()),
+			2 => List(range(0, r % 5).map(|_| random_value(seed, depth + 1)).collect()),
+			_ => Dict(range(0, r % 5).map(|i| {
+				(vec![b'k', i as u8], random_value(seed, depth + 1))
+			}).collect())
+		}
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let mut seed = 1;
+		for _ in range(0, 500) {
+			let value = random_value(&mut seed, 0);
+			let data = value.encode();
+			assert_eq!(Bencode::decode(data.as_slice()), Ok(value));
+
+			// Every truncation is malformed
+			for len in range(0, data.len()) {
+				assert!(Bencode::decode(&data[..len]).is_err());
+			}
+		}
+	}
+
+	#[derive(Debug, Eq, PartialEq)]
+	struct Query {
+		query: String,
+		target: Vec<u8>,
+		version: u32
+	}
+
+	impl ToBencode for Query {
+		fn to_bencode(&self) -> Bencode {
+			let mut dict = BTreeMap::new();
+			dict.insert(b"q".to_vec(), self.query.to_bencode());
+			dict.insert(b"tar".to_vec(), ByteString(self.target.clone()));
+			dict.insert(b"p".to_vec(), self.version.to_bencode());
+			Dict(dict)
+		}
+	}
+
+	impl FromBencode for Query {
+		fn from_bencode(value: &Bencode) -> BencodeResult<Query> {
+			let field = |key: &str| value.get(key).ok_or("Missing field");
+			Ok(Query {
+				query: try!(FromBencode::from_bencode(try!(field("q")))),
+				target: try!(try!(field("tar")).as_bytes().ok_or("Expected bytes")).to_vec(),
+				version: try!(FromBencode::from_bencode(try!(field("p"))))
+			})
+		}
+	}
+
+	#[test]
+	fn test_typed() {
+		let query = Query { query: "fn".to_string(), target: vec![0xfc, 0], version: 16 };
+		let data = encode(&query);
+		assert_eq!(data, b"d1:pi16e1:q2:fn3:tar2:\xfc\x00e".to_vec());
+		assert_eq!(decode::<Query>(data.as_slice()), Ok(query));
+
+		assert!(decode::<Query>(b"d1:pi-1e1:q2:fn3:tar0:e").is_err());
+		assert!(decode::<Query>(b"d1:pi16e3:tar0:e").is_err());
+		assert_eq!(decode::<Vec<u16>>(b"li1ei65535ee"), Ok(vec![1, 65535]));
+		assert!(decode::<Vec<u16>>(b"li65536ee").is_err());
+		assert!(decode::<i64>(b"0:").is_err());
+	}
+}
@@ -5,6 +5,11 @@ use std::num::Int;
 pub struct BigEndian<T: Int + fmt::Debug>(T);
 
 impl<T: Int> BigEndian<T> {
+	#[inline]
+	pub fn new(val: T) -> BigEndian<T> {
+		BigEndian(val.to_be())
+	}
+
 	#[inline]
 	pub fn val(&self) -> T {
 		Int::from_be(self.val_be())
@@ -14,6 +19,11 @@ impl<T: Int> BigEndian<T> {
 	pub fn val_be(&self) -> T {
 		self.0
 	}
+
+	#[inline]
+	pub fn set(&mut self, val: T) {
+		self.0 = val.to_be();
+	}
 }
 
 impl<T: Int + fmt::Debug> fmt::Debug for BigEndian<T> {
@@ -1,6 +1,65 @@
 pub use self::big_endian::BigEndian;
 
 pub mod base32;
+pub mod bencode;
 pub mod debug;
 
 mod big_endian;
+
+
+/// Seconds on a monotonic clock. Only differences between timestamps mean
+/// anything, but they aren't thrown off by changes to the wall clock.
+pub fn timestamp() -> u64 {
+	::time::precise_time_ns() / 1_000_000_000
+}
+
+
+/// Internet checksum (RFC 1071) of a buffer
+pub fn checksum(data: &[u8]) -> u16 {
+	let mut sum = 0u32;
+	for word in data.chunks(2) {
+		sum += (word[0] as u32) << 8;
+		if word.len() == 2 {
+			sum += word[1] as u32;
+		}
+	}
+
+	while sum >> 16 != 0 {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	!sum as u16
+}
+
+/// Mirrors the bits of a label, turning a forward route into a return path
+pub fn reverse_bits(mut bits: u64) -> u64 {
+	let mut reversed = 0;
+	for _ in range(0, 64) {
+		reversed = (reversed << 1) | (bits & 1);
+		bits >>= 1;
+	}
+	reversed
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::{checksum, reverse_bits};
+
+	#[test]
+	fn test_checksum() {
+		// Example from RFC 1071
+		assert_eq!(checksum(&[0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7]), 0x220D);
+		assert_eq!(checksum(&[0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7, 0x22, 0x0D]), 0);
+		assert_eq!(checksum(&[0x01]), 0xFEFF);
+		assert_eq!(checksum(&[]), 0xFFFF);
+	}
+
+	#[test]
+	fn test_reverse_bits() {
+		assert_eq!(reverse_bits(0), 0);
+		assert_eq!(reverse_bits(1), 0x8000_0000_0000_0000);
+		assert_eq!(reverse_bits(0b0011), 0xC000_0000_0000_0000);
+		assert_eq!(reverse_bits(0x0123_4567_89AB_CDEF), 0xF7B3_D591_E6A2_C480);
+	}
+}
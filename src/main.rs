@@ -8,14 +8,30 @@ extern crate "rustc-serialize" as rustc_serialize;
 extern crate docopt;
 
 use std::{os, old_io};
+use std::collections::HashMap;
+use std::old_io::net::ip::IpAddr::Ipv6Addr;
+use std::slice::bytes::copy_memory;
 use docopt::Docopt;
+use mio::net::SockAddr;
 use cjdrs::CjdrsError;
 use cjdrs::CjdrsResult;
 use cjdrs::Config;
+use cjdrs::crypto::{PasswordHash, random_password};
+use cjdrs::encoding_scheme::{self, DEFAULT_SCHEME};
 use cjdrs::EventHandler;
-use cjdrs::device::{self, NetDevice};
+use cjdrs::InterfaceController;
+use cjdrs::device::{
+	self,
+	NetDevice,
+	BeaconMode,
+	DEFAULT_DUAL_STACK,
+	DEFAULT_BEACON_MODE,
+	DEFAULT_BEACON_PORT};
+use cjdrs::packet::BEACON_PASSWORD_LENGTH;
+use cjdrs::PasswordStore;
 use cjdrs::Router;
-use cjdrs::{PrivateKey, PrivateIdentity};
+use cjdrs::SessionTimeouts;
+use cjdrs::{PrivateKey, PrivateIdentity, PublicKey};
 
 
 static USAGE: &'static str = "
@@ -99,24 +115,104 @@ fn run_cjdrs(config: &Config) -> CjdrsResult<()> {
 		&my_identity.address);
 	println!("Opened tun device '{}'", tun_device.get_name());
 
-	let udp_device = try!(device::Udp::create(config.udpBind.as_slice()));
-
-	let devices: Vec<Box<NetDevice>> = vec![
+	let mut devices: Vec<Box<NetDevice>> = vec![
 		Box::new(tun_device) as Box<NetDevice>,
-		Box::new(udp_device) as Box<NetDevice>,
 	];
 
+	let mut password_store = try!(PasswordStore::from_config(config));
+
+	// Peers that heard our beacon log in with a password of its own
+	let beacon_mode = match config.beaconMode {
+		Some(mode) => try!(BeaconMode::from_u8(mode).ok_or(CjdrsError::InvalidBeaconMode(mode))),
+		None => DEFAULT_BEACON_MODE
+	};
+	let beacon_port = config.beaconPort.unwrap_or(DEFAULT_BEACON_PORT);
+	let mut beacon_password = [0u8; BEACON_PASSWORD_LENGTH];
+	if beacon_mode == BeaconMode::SendAndAccept {
+		let password = random_password();
+		let password = &password[..BEACON_PASSWORD_LENGTH];
+		try!(password_store.add("beacon", password, None));
+		copy_memory(&mut beacon_password, password.as_bytes());
+	}
+
+	// Index and address families of every UDP device
+	let mut udp_devices = vec![];
+	let mut beacons_enabled = false;
+	let dual_stack = config.udpDualStack.unwrap_or(DEFAULT_DUAL_STACK);
+	for bind in config.udpBind.iter() {
+		let mut udp_device = try!(device::Udp::create(bind.as_slice(), dual_stack));
+		println!("Listening on udp {}", bind);
+
+		// The first device that can do IPv4 broadcasts handles the beacons
+		if beacon_mode != BeaconMode::Off && !beacons_enabled && udp_device.can_broadcast() {
+			beacons_enabled = true;
+			try!(udp_device.enable_beacons(
+				beacon_mode,
+				beacon_port,
+				&my_identity.public_key,
+				&beacon_password));
+			println!("Beacons on udp port {}", beacon_port);
+		}
+
+		let families = (udp_device.supports_ipv4(), udp_device.supports_ipv6());
+		udp_devices.push((devices.len(), families));
+		devices.push(Box::new(udp_device) as Box<NetDevice>);
+	}
+
 
 	let router = Router::new(&my_identity.address);
+	let scheme_name = config.encodingScheme.as_ref().map(|s| s.as_slice()).unwrap_or(DEFAULT_SCHEME);
+	let scheme = try!(encoding_scheme::from_name(scheme_name).ok_or(
+		CjdrsError::UnknownEncodingScheme(scheme_name.to_string())));
+	let interface_controller = InterfaceController::with_scheme(scheme);
+	let session_timeouts = SessionTimeouts::from_config(config);
 
 
 	// Start up the event loop
 	let mut mio_loop: mio::EventLoop<usize, ()> = try!(mio::EventLoop::new());
 	
-	let event_handler = EventHandler::new(
+	let mut event_handler = EventHandler::new(
 		my_identity,
 		devices,
-		router);
+		router,
+		interface_controller,
+		password_store,
+		session_timeouts);
+
+
+	// Connect to peers
+	let no_peers = HashMap::new();
+	for (address, peer) in config.connectTo.as_ref().unwrap_or(&no_peers).iter() {
+		let sock_addr = try!(SockAddr::parse(address.as_slice()).ok_or(
+			CjdrsError::InvalidPeerAddress(address.clone())));
+		let public_key = try!(PublicKey::from_string(peer.publicKey.as_slice()));
+
+		let is_ipv6 = match sock_addr {
+			SockAddr::InetAddr(Ipv6Addr(..), _) => true,
+			_ => false
+		};
+		let udp_device_idx = match udp_devices.iter().find(|&&(_, (v4, v6))| if is_ipv6 { v6 } else { v4 }) {
+			Some(&(idx, _)) => idx,
+			None => {
+				println!("No udp device can reach {}", address);
+				continue;
+			}
+		};
+
+		println!("Connecting to {}", address);
+		let connected = event_handler.connect(
+			udp_device_idx,
+			sock_addr,
+			&public_key,
+			Some(PasswordHash::from_password(peer.password.as_slice())),
+			peer.login.as_ref().map(|l| l.as_slice()),
+			peer.label.as_ref().map(|l| l.as_slice()));
+
+		// Failed attempts are retried from the event loop
+		if let Err(e) = connected {
+			println!("Connecting to {} failed: {}", address, e);
+		}
+	}
 
 	try!(event_handler.register_handlers(&mut mio_loop));
 	try!(mio_loop.run(event_handler));